@@ -0,0 +1,1017 @@
+//! Generates the protocol types in `src/messages.rs` from the Apache Kafka
+//! JSON message and metadata record schemas vendored under `resources/`.
+//!
+//! Every schema becomes a module named after the message (for example
+//! `fetch_request`) holding one struct per (nested) structure, each with a
+//! `Default` impl honouring the schema defaults and version-aware
+//! `VersionedDeserialize`/`VersionedSerialize` impls covering flexible
+//! versions, nullable fields and tagged fields. Under `cfg(test)` each
+//! struct can also be made at random, and one test round-trips every
+//! message through all of its versions.
+
+use std::{env, fmt::Write as _, fs, path::PathBuf};
+
+const SCHEMA_DIRS: &[&str] = &["resources/message", "resources/metadata"];
+
+fn main() {
+    let mut out = String::new();
+    let mut messages = Vec::new();
+    for dir in SCHEMA_DIRS {
+        println!("cargo:rerun-if-changed={dir}");
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap_or_else(|err| panic!("{dir}: {err}"))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            let text = fs::read_to_string(&path).unwrap();
+            let json = Parser::new(&text)
+                .parse_document()
+                .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+            let message = Message::from_json(&json);
+            message.generate(&mut out);
+            messages.push(message.name);
+        }
+    }
+    generate_round_trips(&messages, &mut out);
+    let dest = PathBuf::from(env::var("OUT_DIR").unwrap()).join("messages.rs");
+    fs::write(dest, out).unwrap();
+}
+
+// ---------------------------------------------------------------------------
+// JSON
+// ---------------------------------------------------------------------------
+
+/// The subset of JSON used by the schemas, which also allow `//` comments.
+#[derive(Debug)]
+enum Json {
+    Null,
+    /// A number or boolean, kept as written.
+    Scalar(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Json::String(value) | Json::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn array(&self, key: &str) -> &[Json] {
+        match self.get(key) {
+            Some(Json::Array(items)) => items,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input: input.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<Json, String> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos != self.input.len() {
+            return Err(format!("trailing characters at {}", self.pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.input.get(self.pos..self.pos + 2) {
+                Some(b"//") => {
+                    while self.pos < self.input.len() && self.input[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                Some(b"/*") => {
+                    self.pos += 2;
+                    while self.pos < self.input.len() && !self.input[self.pos..].starts_with(b"*/") {
+                        self.pos += 1;
+                    }
+                    self.pos += 2;
+                }
+                _ => match self.input.get(self.pos) {
+                    Some(byte) if byte.is_ascii_whitespace() => self.pos += 1,
+                    _ => return,
+                },
+            }
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.input.get(self.pos) != Some(&byte) {
+            return Err(format!("expected '{}' at {}", byte as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.input.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                self.skip_whitespace();
+                if self.input.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(entries));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    entries.push((key, self.parse_value()?));
+                    self.skip_whitespace();
+                    match self.input.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(entries));
+                        }
+                        _ => return Err(format!("expected ',' or '}}' at {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.input.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.input.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(format!("expected ',' or ']' at {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::String(self.parse_string()?)),
+            Some(b't') => self.parse_literal("true", Json::Scalar("true".into())),
+            Some(b'f') => self.parse_literal("false", Json::Scalar("false".into())),
+            Some(b'n') => self.parse_literal("null", Json::Null),
+            Some(byte) if *byte == b'-' || byte.is_ascii_digit() => {
+                let start = self.pos;
+                self.pos += 1;
+                while self
+                    .input
+                    .get(self.pos)
+                    .is_some_and(|byte| byte.is_ascii_alphanumeric() || b".+-".contains(byte))
+                {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+                Ok(Json::Scalar(number.to_string()))
+            }
+            _ => Err(format!("unexpected input at {}", self.pos)),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if !self.input[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(format!("expected {literal} at {}", self.pos));
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut value = Vec::new();
+        loop {
+            let byte = *self.input.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.input.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    match escape {
+                        b'n' => value.push(b'\n'),
+                        b't' => value.push(b'\t'),
+                        b'r' => value.push(b'\r'),
+                        b'b' => value.push(0x08),
+                        b'f' => value.push(0x0c),
+                        b'u' => {
+                            let hex = std::str::from_utf8(&self.input[self.pos..self.pos + 4])
+                                .map_err(|err| err.to_string())?;
+                            let code = u32::from_str_radix(hex, 16).map_err(|err| err.to_string())?;
+                            let ch = char::from_u32(code).unwrap_or('\u{fffd}');
+                            value.extend_from_slice(ch.to_string().as_bytes());
+                            self.pos += 4;
+                        }
+                        other => value.push(other),
+                    }
+                }
+                other => value.push(other),
+            }
+        }
+        String::from_utf8(value).map_err(|err| err.to_string())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Schema model
+// ---------------------------------------------------------------------------
+
+/// An inclusive version range; empty when `min > max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Versions {
+    min: i16,
+    max: i16,
+}
+
+impl Versions {
+    const NONE: Versions = Versions { min: 0, max: -1 };
+
+    fn parse(spec: Option<&str>) -> Self {
+        let Some(spec) = spec.map(str::trim) else {
+            return Self::NONE;
+        };
+        if spec == "none" {
+            Self::NONE
+        } else if let Some(min) = spec.strip_suffix('+') {
+            Versions {
+                min: min.parse().unwrap(),
+                max: i16::MAX,
+            }
+        } else if let Some((min, max)) = spec.split_once('-') {
+            Versions {
+                min: min.parse().unwrap(),
+                max: max.parse().unwrap(),
+            }
+        } else {
+            let version = spec.parse().unwrap();
+            Versions {
+                min: version,
+                max: version,
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    fn intersect(&self, other: Versions) -> Versions {
+        Versions {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    /// The versions in `self` that come before `other` starts, assuming
+    /// `other` is a suffix of `self` (as tagged versions always are).
+    fn before(&self, other: Versions) -> Versions {
+        if other.is_empty() {
+            *self
+        } else {
+            self.intersect(Versions {
+                min: i16::MIN,
+                max: other.min.saturating_sub(1),
+            })
+        }
+    }
+
+    /// How to test for membership at runtime, given that `version` is
+    /// known to lie within `valid`.
+    fn condition(&self, valid: Versions) -> Cond {
+        let range = self.intersect(valid);
+        if range.is_empty() {
+            return Cond::Never;
+        }
+        if range == valid {
+            return Cond::Always;
+        }
+        let expr = match (range.min > valid.min, range.max < valid.max) {
+            (true, false) => format!("version >= {}", range.min),
+            (false, true) => format!("version <= {}", range.max),
+            _ if range.min == range.max => format!("version == {}", range.min),
+            _ => format!("({}..={}).contains(&version)", range.min, range.max),
+        };
+        Cond::Expr(expr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cond {
+    Always,
+    Never,
+    Expr(String),
+}
+
+impl Cond {
+    /// Picks `yes` or `no`, deferring to runtime only when necessary.
+    fn select(&self, yes: String, no: String) -> String {
+        match self {
+            Cond::Always => yes,
+            Cond::Never => no,
+            Cond::Expr(expr) => format!("if {expr} {{ {yes} }} else {{ {no} }}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Bool,
+    Int8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    Float64,
+    Uuid,
+    String,
+    Bytes,
+    Records,
+    Array(Box<Type>),
+    Struct(String),
+}
+
+impl Type {
+    fn parse(spec: &str) -> Self {
+        match spec {
+            "bool" => Type::Bool,
+            "int8" => Type::Int8,
+            "int16" => Type::Int16,
+            "uint16" => Type::UInt16,
+            "int32" => Type::Int32,
+            "uint32" => Type::UInt32,
+            "int64" => Type::Int64,
+            "float64" => Type::Float64,
+            "uuid" => Type::Uuid,
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            "records" => Type::Records,
+            _ => match spec.strip_prefix("[]") {
+                Some(element) => Type::Array(Box::new(Type::parse(element))),
+                None => Type::Struct(spec.to_string()),
+            },
+        }
+    }
+
+    fn rust(&self) -> String {
+        match self {
+            Type::Bool => "bool".into(),
+            Type::Int8 => "i8".into(),
+            Type::Int16 => "i16".into(),
+            Type::UInt16 => "u16".into(),
+            Type::Int32 => "i32".into(),
+            Type::UInt32 => "u32".into(),
+            Type::Int64 => "i64".into(),
+            Type::Float64 => "f64".into(),
+            Type::Uuid => "u128".into(),
+            Type::String => "String".into(),
+            Type::Bytes | Type::Records => "Bytes".into(),
+            Type::Array(element) => format!("Vec<{}>", element.rust()),
+            Type::Struct(name) => name.clone(),
+        }
+    }
+
+    /// Fixed-width types, written with the plain `Buf`/`BufMut` accessors.
+    fn fixed(&self) -> Option<(&'static str, usize)> {
+        match self {
+            Type::Bool => Some(("bool", 1)),
+            Type::Int8 => Some(("i8", 1)),
+            Type::Int16 => Some(("i16", 2)),
+            Type::UInt16 => Some(("u16", 2)),
+            Type::Int32 => Some(("i32", 4)),
+            Type::UInt32 => Some(("u32", 4)),
+            Type::Int64 => Some(("i64", 8)),
+            Type::Float64 => Some(("f64", 8)),
+            Type::Uuid => Some(("u128", 16)),
+            _ => None,
+        }
+    }
+
+    fn may_be_null(&self) -> bool {
+        matches!(
+            self,
+            Type::String | Type::Bytes | Type::Records | Type::Array(_) | Type::Struct(_)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    ty: Type,
+    versions: Versions,
+    nullable_versions: Versions,
+    tagged_versions: Versions,
+    tag: Option<u32>,
+    default: Option<String>,
+    about: Option<String>,
+}
+
+impl Field {
+    fn from_json(json: &Json) -> Self {
+        let ty = Type::parse(json.str("type").expect("field without type"));
+        let mut nullable_versions = Versions::parse(json.str("nullableVersions"));
+        if matches!(ty, Type::Records) && nullable_versions.is_empty() {
+            nullable_versions = Versions::parse(json.str("versions"));
+        }
+        Field {
+            name: snake_case(json.str("name").expect("field without name")),
+            ty,
+            versions: Versions::parse(json.str("versions")),
+            nullable_versions,
+            tagged_versions: Versions::parse(json.str("taggedVersions")),
+            tag: json.str("tag").map(|tag| tag.parse().unwrap()),
+            default: json.str("default").map(str::to_string),
+            about: json.str("about").map(str::to_string),
+        }
+    }
+
+    /// Records are always optional; other nullable types only when the
+    /// schema allows null in some version.
+    fn is_option(&self) -> bool {
+        matches!(self.ty, Type::Records)
+            || (self.ty.may_be_null() && !self.nullable_versions.is_empty())
+    }
+
+    fn rust_type(&self) -> String {
+        match self.is_option() {
+            true => format!("Option<{}>", self.ty.rust()),
+            false => self.ty.rust(),
+        }
+    }
+
+    fn default_expr(&self) -> String {
+        let default = self.default.as_deref().map(str::trim);
+        let null = default == Some("null");
+        let wrap = |value: String| match self.is_option() {
+            true if null => "None".to_string(),
+            true => format!("Some({value})"),
+            false => value,
+        };
+        match &self.ty {
+            Type::Bool => (default == Some("true")).to_string(),
+            Type::Float64 => match default {
+                Some(value) if value.contains('.') => value.to_string(),
+                Some(value) => format!("{value}.0"),
+                None => "0.0".to_string(),
+            },
+            ty if ty.fixed().is_some() => match default {
+                Some(value) => match value.strip_prefix("0x") {
+                    Some(hex) => format!("0x{hex}"),
+                    None => value.to_string(),
+                },
+                None => "0".to_string(),
+            },
+            Type::String => wrap(match default {
+                Some(value) if !value.is_empty() && !null => format!("{value:?}.to_string()"),
+                _ => "String::new()".to_string(),
+            }),
+            Type::Bytes => wrap("Bytes::new()".to_string()),
+            Type::Records => "None".to_string(),
+            Type::Array(_) => wrap("Vec::new()".to_string()),
+            Type::Struct(name) => wrap(format!("{name}::default()")),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Struct {
+    name: String,
+    about: Option<String>,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Message {
+    name: String,
+    api_key: Option<i16>,
+    valid_versions: Versions,
+    flexible_versions: Versions,
+    structs: Vec<Struct>,
+}
+
+impl Message {
+    fn from_json(json: &Json) -> Self {
+        let name = json.str("name").expect("message without name").to_string();
+        let mut structs = Vec::new();
+        collect_struct(&name, None, json.array("fields"), &mut structs);
+        for common in json.array("commonStructs") {
+            let common_name = common.str("name").expect("common struct without name");
+            collect_struct(common_name, None, common.array("fields"), &mut structs);
+        }
+        Message {
+            api_key: json.str("apiKey").map(|key| key.parse().unwrap()),
+            valid_versions: Versions::parse(json.str("validVersions")),
+            flexible_versions: Versions::parse(json.str("flexibleVersions")),
+            name,
+            structs,
+        }
+    }
+
+    fn generate(&self, out: &mut String) {
+        let module = snake_case(&self.name);
+        let valid = self.valid_versions;
+        writeln!(out, "pub mod {module} {{").unwrap();
+        writeln!(out, "    use super::*;\n").unwrap();
+        if let Some(api_key) = self.api_key {
+            writeln!(out, "    pub const API_KEY: i16 = {api_key};").unwrap();
+        }
+        writeln!(out, "    pub const MIN_VERSION: i16 = {};", valid.min).unwrap();
+        writeln!(out, "    pub const MAX_VERSION: i16 = {};\n", valid.max).unwrap();
+        for def in &self.structs {
+            self.generate_struct(def, out);
+        }
+        writeln!(out, "}}").unwrap();
+        writeln!(out, "pub use self::{module}::{};\n", self.name).unwrap();
+    }
+
+    fn flexible(&self) -> Cond {
+        self.flexible_versions.condition(self.valid_versions)
+    }
+
+    /// Whether the encoding is flexible, given that `version` lies within
+    /// `within`. Falls back to the `flexible` local for runtime checks.
+    fn flexible_within(&self, within: Versions) -> Cond {
+        match self.flexible_versions.condition(within) {
+            Cond::Expr(_) => Cond::Expr("flexible".to_string()),
+            cond => cond,
+        }
+    }
+
+    fn generate_struct(&self, def: &Struct, out: &mut String) {
+        let valid = self.valid_versions;
+        let name = &def.name;
+        let tagged: Vec<&Field> = def
+            .fields
+            .iter()
+            .filter(|field| field.tag.is_some() && !field.tagged_versions.intersect(valid).is_empty())
+            .collect();
+
+        // Definition.
+        if let Some(about) = &def.about {
+            writeln!(out, "    /// {about}").unwrap();
+        }
+        writeln!(out, "    #[derive(Debug, Clone, PartialEq)]").unwrap();
+        writeln!(out, "    pub struct {name} {{").unwrap();
+        for field in &def.fields {
+            if let Some(about) = &field.about {
+                writeln!(out, "        /// {about}").unwrap();
+            }
+            writeln!(out, "        pub {}: {},", ident(&field.name), field.rust_type()).unwrap();
+        }
+        writeln!(out, "        /// Tagged fields this schema does not know about.").unwrap();
+        writeln!(out, "        pub unknown_tagged_fields: TaggedFields,").unwrap();
+        writeln!(out, "    }}\n").unwrap();
+
+        writeln!(out, "    impl Default for {name} {{").unwrap();
+        writeln!(out, "        fn default() -> Self {{").unwrap();
+        writeln!(out, "            Self {{").unwrap();
+        for field in &def.fields {
+            writeln!(out, "                {}: {},", ident(&field.name), field.default_expr()).unwrap();
+        }
+        writeln!(out, "                unknown_tagged_fields: TaggedFields::default(),").unwrap();
+        writeln!(out, "            }}").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}\n").unwrap();
+
+        // Known tagged fields are merged with the unknown ones on the way out.
+        if !tagged.is_empty() {
+            writeln!(out, "    impl {name} {{").unwrap();
+            writeln!(out, "        fn tagged_fields(&self, version: i16) -> TaggedFields {{").unwrap();
+            writeln!(out, "            let mut tagged_fields = self.unknown_tagged_fields.clone();").unwrap();
+            for field in &tagged {
+                let place = format!("self.{}", ident(&field.name));
+                let mut check = format!("{place} != {}", field.default_expr());
+                if let Cond::Expr(expr) = field.tagged_versions.condition(valid) {
+                    check = format!("{expr} && {check}");
+                }
+                writeln!(out, "            if {check} {{").unwrap();
+                writeln!(out, "                let mut data = Vec::new();").unwrap();
+                writeln!(out, "                let buffer = &mut data;").unwrap();
+                writeln!(out, "                {};", self.write(field, &place, field.tagged_versions.intersect(valid))).unwrap();
+                writeln!(out, "                tagged_fields.insert({}, data);", field.tag.unwrap()).unwrap();
+                writeln!(out, "            }}").unwrap();
+            }
+            writeln!(out, "            tagged_fields").unwrap();
+            writeln!(out, "        }}").unwrap();
+            writeln!(out, "    }}\n").unwrap();
+        }
+        let all_tagged_fields = match tagged.is_empty() {
+            true => "self.unknown_tagged_fields",
+            false => "self.tagged_fields(version)",
+        };
+
+        // Decoding.
+        let flexible = self.flexible();
+        writeln!(out, "    impl<T: Buf> VersionedDeserialize<T> for {name} {{").unwrap();
+        writeln!(out, "        fn from_bytes(buffer: &mut T, version: i16) -> Result<Self, DecodeError> {{").unwrap();
+        writeln!(out, "            let flexible = {};", flexible_expr(&flexible)).unwrap();
+        writeln!(out, "            let mut this = Self::default();").unwrap();
+        for field in &def.fields {
+            let regular = field.versions.before(field.tagged_versions).intersect(valid);
+            let statement = format!("this.{} = {};", ident(&field.name), self.read(field, regular));
+            emit_conditional(out, &regular.condition(valid), &statement, 3);
+        }
+        if flexible != Cond::Never {
+            writeln!(out, "            if flexible {{").unwrap();
+            writeln!(out, "                let mut tagged_fields = TaggedFields::from_bytes(buffer)?;").unwrap();
+            for field in &tagged {
+                let statement = format!(
+                    "if let Some(mut data) = tagged_fields.take({}) {{ let buffer = &mut data; this.{} = {}; }}",
+                    field.tag.unwrap(),
+                    ident(&field.name),
+                    self.read(field, field.tagged_versions.intersect(valid))
+                );
+                let within = self.flexible_versions.intersect(valid);
+                emit_conditional(out, &field.tagged_versions.condition(within), &statement, 4);
+            }
+            writeln!(out, "                this.unknown_tagged_fields = tagged_fields;").unwrap();
+            writeln!(out, "            }}").unwrap();
+        }
+        writeln!(out, "            Ok(this)").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}\n").unwrap();
+
+        // Encoding.
+        writeln!(out, "    impl VersionedSerialize for {name} {{").unwrap();
+        writeln!(out, "        fn encoded_len(&self, version: i16) -> usize {{").unwrap();
+        writeln!(out, "            let flexible = {};", flexible_expr(&flexible)).unwrap();
+        writeln!(out, "            let mut len = 0;").unwrap();
+        for field in &def.fields {
+            let regular = field.versions.before(field.tagged_versions).intersect(valid);
+            let place = format!("self.{}", ident(&field.name));
+            let statement = format!("len += {};", self.len(field, &place, regular));
+            emit_conditional(out, &regular.condition(valid), &statement, 3);
+        }
+        if flexible != Cond::Never {
+            writeln!(out, "            if flexible {{").unwrap();
+            writeln!(out, "                len += {all_tagged_fields}.encoded_len();").unwrap();
+            writeln!(out, "            }}").unwrap();
+        }
+        writeln!(out, "            len").unwrap();
+        writeln!(out, "        }}\n").unwrap();
+        writeln!(out, "        fn serialize<B: BufMut>(&self, buffer: &mut B, version: i16) {{").unwrap();
+        writeln!(out, "            let flexible = {};", flexible_expr(&flexible)).unwrap();
+        for field in &def.fields {
+            let regular = field.versions.before(field.tagged_versions).intersect(valid);
+            let place = format!("self.{}", ident(&field.name));
+            let statement = format!("{};", self.write(field, &place, regular));
+            emit_conditional(out, &regular.condition(valid), &statement, 3);
+        }
+        if flexible != Cond::Never {
+            writeln!(out, "            if flexible {{").unwrap();
+            writeln!(out, "                {all_tagged_fields}.serialize(buffer);").unwrap();
+            writeln!(out, "            }}").unwrap();
+        }
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}\n").unwrap();
+
+        // Random values, leaving out unknown tagged fields so that they
+        // cannot clash with known ones.
+        writeln!(out, "    #[cfg(test)]").unwrap();
+        writeln!(out, "    impl Random for {name} {{").unwrap();
+        writeln!(out, "        fn random(rng: &mut Rng) -> Self {{").unwrap();
+        writeln!(out, "            Self {{").unwrap();
+        for field in &def.fields {
+            let value = random_value(&field.ty);
+            let value = match field.is_option() {
+                true => format!("rng.option(|rng| {value})"),
+                false => value,
+            };
+            writeln!(out, "                {}: {value},", ident(&field.name)).unwrap();
+        }
+        writeln!(out, "                unknown_tagged_fields: TaggedFields::default(),").unwrap();
+        writeln!(out, "            }}").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}\n").unwrap();
+    }
+
+    /// An expression decoding `field` from `buffer`, propagating errors
+    /// with `?`.
+    fn read(&self, field: &Field, within: Versions) -> String {
+        let flexible = &self.flexible_within(within);
+        let nullable = field.nullable_versions.condition(within);
+        if !field.is_option() {
+            return read_value(&field.ty, flexible);
+        }
+        let not_null = format!("Some({})", read_value(&field.ty, flexible));
+        nullable.select(read_nullable(&field.ty, flexible), not_null)
+    }
+
+    /// A statement encoding the value at `place` into `buffer`.
+    fn write(&self, field: &Field, place: &str, within: Versions) -> String {
+        let flexible = &self.flexible_within(within);
+        if !field.is_option() {
+            return write_value(&field.ty, &format!("&{place}"), flexible);
+        }
+        let nullable = field.nullable_versions.condition(within);
+        let not_null = match &field.ty {
+            Type::Struct(name) => format!(
+                "match &{place} {{ Some(value) => value.serialize(buffer, version), None => {name}::default().serialize(buffer, version) }}"
+            ),
+            ty => write_value(ty, &format!("{place}.as_deref().unwrap_or_default()"), flexible),
+        };
+        nullable.select(write_nullable(&field.ty, place, flexible), not_null)
+    }
+
+    /// An expression for the encoded size of the value at `place`.
+    fn len(&self, field: &Field, place: &str, within: Versions) -> String {
+        let flexible = &self.flexible_within(within);
+        if !field.is_option() {
+            return len_value(&field.ty, &format!("&{place}"), flexible);
+        }
+        let nullable = field.nullable_versions.condition(within);
+        let not_null = match &field.ty {
+            Type::Struct(name) => format!(
+                "{place}.as_ref().map_or_else(|| {name}::default().encoded_len(version), |value| value.encoded_len(version))"
+            ),
+            ty => len_value(ty, &format!("{place}.as_deref().unwrap_or_default()"), flexible),
+        };
+        nullable.select(len_nullable(&field.ty, place, flexible), not_null)
+    }
+}
+
+fn collect_struct(name: &str, about: Option<&str>, fields: &[Json], structs: &mut Vec<Struct>) {
+    for field in fields {
+        if field.get("fields").is_some() {
+            let ty = field.str("type").unwrap();
+            let nested = ty.strip_prefix("[]").unwrap_or(ty);
+            if !structs.iter().any(|def| def.name == nested) {
+                collect_struct(nested, field.str("about"), field.array("fields"), structs);
+            }
+        }
+    }
+    let def = Struct {
+        name: name.to_string(),
+        about: about.map(str::to_string),
+        fields: fields.iter().map(Field::from_json).collect(),
+    };
+    structs.insert(0, def);
+}
+
+/// A test round-tripping random values of each of `messages` through
+/// every version it has.
+fn generate_round_trips(messages: &[String], out: &mut String) {
+    writeln!(out, "#[cfg(test)]").unwrap();
+    writeln!(out, "mod tests {{").unwrap();
+    writeln!(out, "    use super::*;").unwrap();
+    writeln!(out, "    use crate::testing::round_trip_random;\n").unwrap();
+    writeln!(out, "    #[test]").unwrap();
+    writeln!(out, "    fn round_trips() {{").unwrap();
+    writeln!(out, "        let mut rng = Rng::new(0);").unwrap();
+    for name in messages {
+        let module = snake_case(name);
+        writeln!(out, "        round_trip_random::<{name}>(&mut rng, {module}::MIN_VERSION..={module}::MAX_VERSION);").unwrap();
+    }
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn flexible_expr(flexible: &Cond) -> String {
+    match flexible {
+        Cond::Always => "true".to_string(),
+        Cond::Never => "false".to_string(),
+        Cond::Expr(expr) => expr.clone(),
+    }
+}
+
+fn emit_conditional(out: &mut String, cond: &Cond, statement: &str, indent: usize) {
+    let pad = "    ".repeat(indent);
+    match cond {
+        Cond::Always => writeln!(out, "{pad}{statement}").unwrap(),
+        Cond::Never => {}
+        Cond::Expr(expr) => writeln!(out, "{pad}if {expr} {{ {statement} }}").unwrap(),
+    }
+}
+
+fn read_value(ty: &Type, flexible: &Cond) -> String {
+    match ty {
+        Type::Bool => "buffer.get_checked_bool()?".to_string(),
+        ty if ty.fixed().is_some() => {
+            let (name, size) = ty.fixed().unwrap();
+            format!("{{ buffer.ensure({size})?; buffer.get_{name}() }}")
+        }
+        Type::String => flexible.select(
+            "buffer.get_checked_compact_string()?".into(),
+            "buffer.get_checked_string16()?".into(),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            "buffer.get_checked_compact_nullable_bytes()?.ok_or(DecodeError::InvalidLength(-1))?"
+                .into(),
+            "buffer.get_checked_nullable_bytes()?.ok_or(DecodeError::InvalidLength(-1))?".into(),
+        ),
+        Type::Array(element) => {
+            let element = read_value(element, flexible);
+            flexible.select(
+                format!("buffer.get_checked_compact_array(|buffer| Ok({element}))?"),
+                format!("buffer.get_checked_array(|buffer| Ok({element}))?"),
+            )
+        }
+        Type::Struct(name) => format!("{name}::from_bytes(buffer, version)?"),
+        _ => unreachable!(),
+    }
+}
+
+fn read_nullable(ty: &Type, flexible: &Cond) -> String {
+    match ty {
+        Type::String => flexible.select(
+            "buffer.get_checked_compact_nullable_string()?".into(),
+            "buffer.get_checked_nullable_string()?".into(),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            "buffer.get_checked_compact_nullable_bytes()?".into(),
+            "buffer.get_checked_nullable_bytes()?".into(),
+        ),
+        Type::Array(element) => {
+            let element = read_value(element, flexible);
+            flexible.select(
+                format!("buffer.get_checked_compact_nullable_array(|buffer| Ok({element}))?"),
+                format!("buffer.get_checked_nullable_array(|buffer| Ok({element}))?"),
+            )
+        }
+        // Nullable structs are preceded by a marker byte: -1 for null.
+        Type::Struct(name) => format!(
+            "match buffer.get_checked_i8()? {{ -1 => None, _ => Some({name}::from_bytes(buffer, version)?) }}"
+        ),
+        _ => unreachable!(),
+    }
+}
+
+/// An expression for a random value of `ty`, drawn from `rng`.
+fn random_value(ty: &Type) -> String {
+    match ty {
+        Type::Bool => "rng.bool()".to_string(),
+        Type::Int8 => "rng.i8()".to_string(),
+        Type::Int16 => "rng.i16()".to_string(),
+        Type::UInt16 => "rng.next_u64() as u16".to_string(),
+        Type::Int32 => "rng.i32()".to_string(),
+        Type::UInt32 => "rng.next_u64() as u32".to_string(),
+        Type::Int64 => "rng.i64()".to_string(),
+        // Whole numbers, so that NaN never makes a value unequal to itself.
+        Type::Float64 => "rng.i32() as f64".to_string(),
+        Type::Uuid => "rng.u128()".to_string(),
+        Type::String => "rng.string()".to_string(),
+        Type::Bytes | Type::Records => "rng.bytes()".to_string(),
+        Type::Array(element) => format!("rng.vec(|rng| {})", random_value(element)),
+        Type::Struct(name) => format!("{name}::random(rng)"),
+    }
+}
+
+/// `value` is an expression of type `&T`.
+fn write_value(ty: &Type, value: &str, flexible: &Cond) -> String {
+    match ty {
+        ty if ty.fixed().is_some() => format!("buffer.put_{}(*{value})", ty.fixed().unwrap().0),
+        Type::String => flexible.select(
+            format!("buffer.put_compact_string({value})"),
+            format!("buffer.put_string16({value})"),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            format!("buffer.put_compact_bytes({value})"),
+            format!("buffer.put_bytes32({value})"),
+        ),
+        Type::Array(element) => {
+            let element = write_value(element, "item", flexible);
+            flexible.select(
+                format!("buffer.put_compact_array({value}, |buffer, item| {element})"),
+                format!("buffer.put_array({value}, |buffer, item| {element})"),
+            )
+        }
+        Type::Struct(_) => format!("{}.serialize(buffer, version)", receiver(value)),
+        _ => unreachable!(),
+    }
+}
+
+/// `place` is an expression of type `Option<T>`.
+fn write_nullable(ty: &Type, place: &str, flexible: &Cond) -> String {
+    match ty {
+        Type::String => flexible.select(
+            format!("buffer.put_compact_nullable_string({place}.as_deref())"),
+            format!("buffer.put_nullable_string({place}.as_deref())"),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            format!("buffer.put_compact_nullable_bytes({place}.as_deref())"),
+            format!("buffer.put_nullable_bytes({place}.as_deref())"),
+        ),
+        Type::Array(element) => {
+            let element = write_value(element, "item", flexible);
+            flexible.select(
+                format!("buffer.put_compact_nullable_array({place}.as_deref(), |buffer, item| {element})"),
+                format!("buffer.put_nullable_array({place}.as_deref(), |buffer, item| {element})"),
+            )
+        }
+        Type::Struct(_) => format!(
+            "match &{place} {{ Some(value) => {{ buffer.put_i8(1); value.serialize(buffer, version); }} None => buffer.put_i8(-1) }}"
+        ),
+        _ => unreachable!(),
+    }
+}
+
+/// `value` is an expression of type `&T`.
+fn len_value(ty: &Type, value: &str, flexible: &Cond) -> String {
+    match ty {
+        ty if ty.fixed().is_some() => ty.fixed().unwrap().1.to_string(),
+        Type::String => flexible.select(
+            format!("compact_string_len({value})"),
+            format!("string16_len({value})"),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            format!("compact_bytes_len({value})"),
+            format!("bytes32_len({value})"),
+        ),
+        Type::Array(element) => {
+            let element = len_value(element, "item", flexible);
+            flexible.select(
+                format!("compact_array_len({value}, |item| {element})"),
+                format!("array_len({value}, |item| {element})"),
+            )
+        }
+        Type::Struct(_) => format!("{}.encoded_len(version)", receiver(value)),
+        _ => unreachable!(),
+    }
+}
+
+/// `place` is an expression of type `Option<T>`.
+fn len_nullable(ty: &Type, place: &str, flexible: &Cond) -> String {
+    match ty {
+        Type::String => flexible.select(
+            format!("compact_nullable_string_len({place}.as_deref())"),
+            format!("nullable_string_len({place}.as_deref())"),
+        ),
+        Type::Bytes | Type::Records => flexible.select(
+            format!("compact_nullable_bytes_len({place}.as_deref())"),
+            format!("nullable_bytes_len({place}.as_deref())"),
+        ),
+        Type::Array(element) => {
+            let element = len_value(element, "item", flexible);
+            flexible.select(
+                format!("compact_nullable_array_len({place}.as_deref(), |item| {element})"),
+                format!("nullable_array_len({place}.as_deref(), |item| {element})"),
+            )
+        }
+        Type::Struct(_) => {
+            format!("1 + {place}.as_ref().map_or(0, |value| value.encoded_len(version))")
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Turns a `&T` expression into a method receiver, dropping a leading `&`
+/// so that `&self.field` becomes `self.field`.
+fn receiver(value: &str) -> &str {
+    value.strip_prefix('&').unwrap_or(value)
+}
+
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, ch) in chars.iter().enumerate() {
+        if ch.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_ascii_lowercase());
+            if prev.is_ascii_lowercase() || prev.is_ascii_digit() || (prev.is_ascii_uppercase() && next_is_lower) {
+                out.push('_');
+            }
+        }
+        out.push(ch.to_ascii_lowercase());
+    }
+    out
+}
+
+fn ident(name: &str) -> String {
+    match name {
+        "type" | "match" | "ref" | "move" | "loop" | "mod" | "use" | "where" | "async" => format!("r#{name}"),
+        _ => name.to_string(),
+    }
+}
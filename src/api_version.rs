@@ -1,75 +1,142 @@
-use bytes::BufMut;
+pub use crate::messages::api_versions_request::{self, ApiVersionsRequest};
+pub use crate::messages::api_versions_response::{
+    self, ApiVersion, ApiVersionsResponse, FinalizedFeatureKey, SupportedFeatureKey,
+};
+use crate::{
+    handler::HANDLERS,
+    metadata::{MetadataImage, METADATA_VERSIONS},
+};
 
-#[derive(Debug)]
-pub struct ApiVersion {
-    pub(crate) error_code: i16,
-    pub(crate) api_keys: (u8, Vec<ApiKey>),
-    pub(crate) throttle_time_ms: i32,
-}
-
-impl ApiVersion {
-    pub fn new(error_code: i16) -> Self {
+impl ApiVersionsResponse {
+    /// Advertises every registered handler, and from v3 the features this
+    /// broker supports and the levels `image` has finalized.
+    pub fn new(error_code: i16, image: &MetadataImage) -> Self {
+        let api_keys = HANDLERS
+            .iter()
+            .map(|handler| ApiVersion {
+                api_key: handler.api_key,
+                min_version: handler.min_version,
+                max_version: handler.max_version,
+                ..Default::default()
+            })
+            .collect();
         Self {
             error_code,
-            api_keys: (
-                4,
-                vec![
-                    ApiKey {
-                        api_key: 75,
-                        min_version: 0,
-                        max_version: 0,
-                    },
-                    ApiKey {
-                        api_key: 18,
-                        min_version: 0,
-                        max_version: 4,
-                    },
-                    ApiKey {
-                        api_key: 1,
-                        min_version: 0,
-                        max_version: 16,
-                    },
-                ],
-            ),
-            throttle_time_ms: 0,
+            api_keys,
+            supported_features: vec![SupportedFeatureKey {
+                name: "metadata.version".to_string(),
+                min_version: *METADATA_VERSIONS.start(),
+                max_version: *METADATA_VERSIONS.end(),
+                ..Default::default()
+            }],
+            finalized_features_epoch: image.features_epoch(),
+            finalized_features: image
+                .features()
+                .map(|(name, level)| FinalizedFeatureKey {
+                    name: name.to_string(),
+                    max_version_level: level,
+                    min_version_level: level,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
         }
     }
 }
 
-impl Into<Vec<u8>> for &ApiVersion {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.error_code.to_be_bytes());
-        buffer.extend_from_slice(&self.api_keys.0.to_be_bytes());
-        buffer.extend_from_slice(
-            &self
-                .api_keys
-                .1
-                .iter()
-                .map(|api_key| Into::<Vec<u8>>::into(api_key))
-                .collect::<Vec<Vec<u8>>>()
-                .concat(),
-        );
-        buffer.extend_from_slice(&self.throttle_time_ms.to_be_bytes());
-        buffer.put_u8(0);
-        buffer
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        record::{Record, RecordBatch},
+        testing::{captured, decode, encode, hex, serialize_batches},
+    };
+
+    /// Body of an ApiVersions v3 request sent by the Java client.
+    const JAVA_CLIENT_REQUEST_V3: &str = "
+        12 6170616368652d6b61666b612d6a617661
+        06 332e372e30
+        00";
+
+    /// Body of an ApiVersions v3 request sent by librdkafka.
+    const LIBRDKAFKA_REQUEST_V3: &str = "
+        0b 6c696272646b61666b61
+        07 322e31322e31
+        00";
+
+    #[test]
+    fn decodes_client_request() {
+        let request: ApiVersionsRequest = decode(&hex(JAVA_CLIENT_REQUEST_V3), 3);
+        assert_eq!(request.client_software_name, "apache-kafka-java");
+        assert_eq!(request.client_software_version, "3.7.0");
+        let request: ApiVersionsRequest = captured(LIBRDKAFKA_REQUEST_V3, 3);
+        assert_eq!(request.client_software_name, "librdkafka");
+        assert_eq!(request.client_software_version, "2.12.1");
+        for version in 0..=2 {
+            assert_eq!(decode::<ApiVersionsRequest>(&[], version), Default::default());
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct ApiKey {
-    pub api_key: i16,
-    pub min_version: i16,
-    pub max_version: i16,
-}
+    #[test]
+    fn encodes_response_for_each_version() {
+        let response = ApiVersionsResponse::new(0, &MetadataImage::default());
+        let api_keys = "
+            0000 0003 000b  0001 0004 0010  0002 0000 0009  0003 0000 000c  0012 0000 0004
+            0013 0000 0007  0014 0000 0006  0025 0000 0003  004b 0000 0000";
+        let v0 = format!("0000 00000009 {api_keys}");
+        let v1 = format!("{v0} 00000000");
+        let compact_api_keys = "0a 00000003000b00 00010004001000 00020000000900 00030000000c00
+            00120000000400 00130000000700 00140000000600 00250000000300 004b0000000000";
+        let supported_features = "02 11 6d657461646174612e76657273696f6e 0001 0014 00";
+        let v3 = format!("0000 {compact_api_keys} 00000000 01 00 17 {supported_features}");
+        assert_eq!(encode(&response, 0), hex(&v0));
+        assert_eq!(encode(&response, 1), hex(&v1));
+        assert_eq!(encode(&response, 2), hex(&v1));
+        assert_eq!(encode(&response, 3), hex(&v3));
+        assert_eq!(encode(&response, 4), hex(&v3));
+    }
+
+    #[test]
+    fn reports_finalized_features() {
+        // metadata.version 20 finalized at offset 5.
+        let batch = RecordBatch {
+            base_offset: 5,
+            records: vec![Record {
+                value: Some(hex("01 0c 00  11 6d657461646174612e76657273696f6e 0014 00").into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut image = MetadataImage::default();
+        image.replay_segment(&serialize_batches(&[batch])).unwrap();
+        let response = ApiVersionsResponse::new(0, &image);
+        assert_eq!(response.finalized_features_epoch, 5);
+        assert_eq!(response.finalized_features.len(), 1);
+        assert_eq!(response.finalized_features[0].name, "metadata.version");
+        assert_eq!(response.finalized_features[0].min_version_level, 20);
+        assert_eq!(response.finalized_features[0].max_version_level, 20);
+
+        let bytes = encode(&response, 3);
+        let features = "
+            02 11 6d657461646174612e76657273696f6e 0001 0014 00
+            01 08 0000000000000005
+            02 17 02 11 6d657461646174612e76657273696f6e 0014 0014 00";
+        assert!(bytes.ends_with(&hex(&format!("03 00 17 {features}"))));
+        // Older versions have nowhere to put them.
+        assert_eq!(
+            encode(&response, 2),
+            encode(&ApiVersionsResponse::new(0, &MetadataImage::default()), 2)
+        );
+    }
 
-impl Into<Vec<u8>> for &ApiKey {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.api_key.to_be_bytes());
-        buffer.extend_from_slice(&self.min_version.to_be_bytes());
-        buffer.extend_from_slice(&self.max_version.to_be_bytes());
-        buffer.put_u8(0);
-        buffer
+    #[test]
+    fn keeps_unknown_tagged_fields() {
+        // The v3 golden response above with an extra tag 5 carrying two bytes.
+        let bytes = hex("0000 01 00000000 02 00 17 02 11 6d657461646174612e76657273696f6e 0001 0014 00 05 02 abcd");
+        let response: ApiVersionsResponse = decode(&bytes, 3);
+        assert_eq!(response.supported_features.len(), 1);
+        assert_eq!(response.unknown_tagged_fields.0.len(), 1);
+        assert_eq!(response.unknown_tagged_fields.0[0].tag, 5);
+        assert_eq!(encode(&response, 3), bytes);
     }
 }
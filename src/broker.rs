@@ -0,0 +1,259 @@
+use std::sync::{atomic::AtomicUsize, Mutex, RwLock};
+
+use bytes::Bytes;
+
+use crate::{
+    config::Config,
+    error_code,
+    fetch_session::FetchSessionCache,
+    log::LogManager,
+    messages::{
+        config_record, partition_record, remove_topic_record, topic_record, ConfigRecord,
+        PartitionRecord, RemoveTopicRecord, TopicRecord,
+    },
+    metadata::{self, MetadataImage, MetadataLog},
+    purgatory::Purgatory,
+};
+
+/// State shared by every connection.
+#[derive(Debug, Default)]
+pub struct Broker {
+    pub config: Config,
+    /// The cluster metadata. Changes are written to the metadata log
+    /// before they are replayed here, so that the two never disagree.
+    pub image: RwLock<MetadataImage>,
+    pub metadata_log: MetadataLog,
+    /// Held by whoever is changing the metadata, from checking the image
+    /// until the records are replayed. The image itself is only locked for
+    /// the replay, so readers are not held up while the log is flushed.
+    metadata_writer: Mutex<()>,
+    /// The cluster id from `meta.properties`, if the log directory has been
+    /// formatted.
+    pub cluster_id: Option<String>,
+    /// Bumped by every Fetch to rotate which partition is served first.
+    pub fetch_rotation: AtomicUsize,
+    /// Fetches waiting for `min_bytes` of records to be appended.
+    pub fetch_purgatory: Purgatory,
+    pub fetch_sessions: FetchSessionCache,
+    pub logs: LogManager,
+}
+
+impl Broker {
+    /// Loads the cluster metadata from the configured log directory and
+    /// recovers the partition logs there. A broker that cannot read the
+    /// metadata starts out knowing no topics.
+    pub fn start(config: Config) -> Self {
+        let image = match MetadataImage::load(&config.metadata_log_dir()) {
+            Ok(image) => image,
+            Err(err) => {
+                println!("starting without cluster metadata: {:#}", err);
+                MetadataImage::default()
+            }
+        };
+        let meta_properties = config.log_dir().join("meta.properties");
+        let cluster_id = match Config::load(&meta_properties) {
+            Ok(properties) => properties.get("cluster.id").map(str::to_string),
+            Err(err) => {
+                println!("starting without a cluster id: {:#}", err);
+                None
+            }
+        };
+        let mut broker = Self::new(config, image);
+        broker.cluster_id = cluster_id;
+        if let Err(err) = broker.logs.recover() {
+            println!("failed to recover logs: {:#}", err);
+        }
+        broker
+    }
+
+    pub fn new(config: Config, image: MetadataImage) -> Self {
+        Self {
+            fetch_sessions: FetchSessionCache::new(config.fetch_session_slots()),
+            logs: LogManager::new(&config),
+            metadata_log: MetadataLog::new(&config),
+            config,
+            image: RwLock::new(image),
+            metadata_writer: Mutex::default(),
+            cluster_id: None,
+            fetch_rotation: AtomicUsize::new(0),
+            fetch_purgatory: Purgatory::default(),
+        }
+    }
+
+    /// Places `partitions` partitions of `replication_factor` replicas each
+    /// on the brokers of the cluster, which is only this one.
+    pub fn assign_replicas(
+        &self,
+        partitions: i32,
+        replication_factor: i16,
+    ) -> Result<Vec<Vec<i32>>, i16> {
+        if partitions <= 0 {
+            return Err(error_code::INVALID_PARTITIONS);
+        }
+        if replication_factor != 1 {
+            return Err(error_code::INVALID_REPLICATION_FACTOR);
+        }
+        Ok(vec![vec![self.config.node_id()]; partitions as usize])
+    }
+
+    /// Whether a partition can be placed on `replicas`, which with a single
+    /// broker means on this one alone.
+    pub fn valid_replicas(&self, replicas: &[i32]) -> bool {
+        replicas == [self.config.node_id()]
+    }
+
+    /// Creates topic `name` with `configs` and a partition for each replica
+    /// list in `assignments`, led by its first replica. The records are
+    /// written to the metadata log before the topic becomes visible, then
+    /// the partition logs are created. Returns the new topic's id.
+    pub fn create_topic(
+        &self,
+        name: &str,
+        assignments: &[Vec<i32>],
+        configs: &[(String, String)],
+    ) -> Result<u128, i16> {
+        if !metadata::valid_topic_name(name) {
+            return Err(error_code::INVALID_TOPIC_EXCEPTION);
+        }
+        let _writer = self.metadata_writer.lock().unwrap();
+        let storage_error = |err| {
+            println!("failed to create topic {name}: {:#}", err);
+            error_code::KAFKA_STORAGE_ERROR
+        };
+        let topic_id = {
+            let image = self.image.read().unwrap();
+            if image.topic(name).is_some() {
+                return Err(error_code::TOPIC_ALREADY_EXISTS);
+            }
+            image.new_topic_id().map_err(storage_error)?
+        };
+        let topic = TopicRecord {
+            name: name.to_string(),
+            topic_id,
+            ..Default::default()
+        };
+        let mut values = vec![metadata::record_value(topic_record::API_KEY, 0, &topic)];
+        for (key, value) in configs {
+            let config = ConfigRecord {
+                resource_type: metadata::TOPIC_RESOURCE,
+                resource_name: name.to_string(),
+                name: key.clone(),
+                value: Some(value.clone()),
+                ..Default::default()
+            };
+            values.push(metadata::record_value(config_record::API_KEY, 0, &config));
+        }
+        for (partition_id, replicas) in assignments.iter().enumerate() {
+            values.push(new_partition(topic_id, partition_id as i32, replicas));
+        }
+        self.metadata_log.append(&values).map_err(storage_error)?;
+        let mut image = self.image.write().unwrap();
+        for value in &values {
+            image.replay(value).expect("metadata records encoded above");
+        }
+        drop(image);
+        // The topic exists once its records are written; a partition whose
+        // directory cannot be made yet gets another try on its first append.
+        for partition in 0..assignments.len() as i32 {
+            if let Err(err) = self.logs.create(name, partition) {
+                println!("failed to create log {name}-{partition}: {:#}", err);
+            }
+        }
+        Ok(topic_id)
+    }
+
+    /// Grows topic `name` to `count` partitions, the new ones placed on the
+    /// replicas in `assignments`, which has to cover exactly the partitions
+    /// added. As with [`Self::create_topic`], the records are written to
+    /// the metadata log before the partitions become visible, then their
+    /// logs are created.
+    pub fn create_partitions(
+        &self,
+        name: &str,
+        count: i32,
+        assignments: &[Vec<i32>],
+    ) -> Result<(), i16> {
+        let _writer = self.metadata_writer.lock().unwrap();
+        let (topic_id, current) = match self.image.read().unwrap().topic(name) {
+            Some(topic) => (topic.topic_id, topic.partitions.len() as i32),
+            None => return Err(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+        };
+        if count <= current {
+            return Err(error_code::INVALID_PARTITIONS);
+        }
+        if assignments.len() as i32 != count - current {
+            return Err(error_code::INVALID_REPLICA_ASSIGNMENT);
+        }
+        let values: Vec<_> = (current..count)
+            .zip(assignments)
+            .map(|(partition_id, replicas)| new_partition(topic_id, partition_id, replicas))
+            .collect();
+        if let Err(err) = self.metadata_log.append(&values) {
+            println!("failed to create partitions of {name}: {:#}", err);
+            return Err(error_code::KAFKA_STORAGE_ERROR);
+        }
+        let mut image = self.image.write().unwrap();
+        for value in &values {
+            image.replay(value).expect("metadata records encoded above");
+        }
+        drop(image);
+        for partition in current..count {
+            if let Err(err) = self.logs.create(name, partition) {
+                println!("failed to create log {name}-{partition}: {:#}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the topic with `topic_id`. Once the removal is in the
+    /// metadata log the topic is gone from the image, fetches waiting on
+    /// its partitions are woken to find out, and the partition logs are
+    /// set aside for deletion in the background. Returns the topic's name.
+    pub fn delete_topic(&self, topic_id: u128) -> Result<String, i16> {
+        let _writer = self.metadata_writer.lock().unwrap();
+        let (name, partitions) = match self.image.read().unwrap().topic_by_id(topic_id) {
+            Some(topic) => (
+                topic.name.clone(),
+                topic.partitions.keys().copied().collect::<Vec<i32>>(),
+            ),
+            None => return Err(error_code::UNKNOWN_TOPIC_ID),
+        };
+        let record = RemoveTopicRecord {
+            topic_id,
+            ..Default::default()
+        };
+        let value = metadata::record_value(remove_topic_record::API_KEY, 0, &record);
+        if let Err(err) = self.metadata_log.append(std::slice::from_ref(&value)) {
+            println!("failed to delete topic {name}: {:#}", err);
+            return Err(error_code::KAFKA_STORAGE_ERROR);
+        }
+        self.image
+            .write()
+            .unwrap()
+            .replay(&value)
+            .expect("metadata record encoded above");
+        for partition in partitions {
+            if let Err(err) = self.logs.delete(&name, partition, topic_id) {
+                println!("failed to delete log {name}-{partition}: {:#}", err);
+            }
+            self.fetch_purgatory.wake(&name, partition);
+        }
+        Ok(name)
+    }
+}
+
+/// The record of a new partition of `topic_id` on `replicas`, all in sync
+/// and led by the first.
+fn new_partition(topic_id: u128, partition_id: i32, replicas: &[i32]) -> Bytes {
+    let partition = PartitionRecord {
+        partition_id,
+        topic_id,
+        replicas: replicas.to_vec(),
+        isr: replicas.to_vec(),
+        leader: replicas.first().copied().unwrap_or(-1),
+        leader_epoch: 0,
+        partition_epoch: 0,
+        ..Default::default()
+    };
+    metadata::record_value(partition_record::API_KEY, 0, &partition)
+}
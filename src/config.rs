@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+
+const DEFAULT_LOG_DIR: &str = "/tmp/kraft-combined-logs";
+const DEFAULT_FETCH_SESSION_SLOTS: usize = 1000;
+const DEFAULT_SEGMENT_BYTES: u64 = 1 << 30;
+const DEFAULT_ROLL_HOURS: i64 = 24 * 7;
+const DEFAULT_INDEX_INTERVAL_BYTES: u64 = 4096;
+const DEFAULT_CHECKPOINT_INTERVAL_MS: u64 = 60_000;
+const DEFAULT_NODE_ID: i32 = 1;
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: i32 = 9092;
+const DEFAULT_NUM_PARTITIONS: i32 = 1;
+const DEFAULT_REPLICATION_FACTOR: i16 = 1;
+const DEFAULT_FILE_DELETE_DELAY_MS: u64 = 60_000;
+const DEFAULT_SOCKET_REQUEST_MAX_BYTES: usize = 100 * 1024 * 1024;
+
+/// Broker settings from a Java-style `server.properties` file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    properties: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Self::parse(&text))
+    }
+
+    /// Parses `key=value` lines, skipping blanks and `#`/`!` comments.
+    pub fn parse(text: &str) -> Self {
+        let properties = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+            .filter_map(|line| line.split_once(['=', ':']))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Self { properties }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+
+    /// The directory partition logs live in: the first of `log.dirs`,
+    /// falling back to `log.dir`.
+    pub fn log_dir(&self) -> PathBuf {
+        self.get("log.dirs")
+            .and_then(|dirs| dirs.split(',').map(str::trim).find(|dir| !dir.is_empty()))
+            .or_else(|| self.get("log.dir"))
+            .unwrap_or(DEFAULT_LOG_DIR)
+            .into()
+    }
+
+    /// Where the `__cluster_metadata-0` log lives, which is the log
+    /// directory unless `metadata.log.dir` says otherwise.
+    pub fn metadata_log_dir(&self) -> PathBuf {
+        let dir = self.get("metadata.log.dir").map_or_else(|| self.log_dir(), PathBuf::from);
+        dir.join("__cluster_metadata-0")
+    }
+
+    /// The size at which a log segment is closed and a new one started,
+    /// which Kafka keeps to an int.
+    pub fn segment_bytes(&self) -> u64 {
+        self.parsed("log.segment.bytes")
+            .unwrap_or(DEFAULT_SEGMENT_BYTES)
+            .min(i32::MAX as u64)
+    }
+
+    /// The age at which a log segment is rolled even if it is not full,
+    /// from `log.roll.ms` or else `log.roll.hours`.
+    pub fn roll_ms(&self) -> i64 {
+        self.parsed("log.roll.ms")
+            .or_else(|| self.parsed("log.roll.hours").map(|hours: i64| hours * 60 * 60 * 1000))
+            .unwrap_or(DEFAULT_ROLL_HOURS * 60 * 60 * 1000)
+    }
+
+    /// How many bytes of batches go between offset index entries.
+    pub fn index_interval_bytes(&self) -> u64 {
+        self.parsed("log.index.interval.bytes").unwrap_or(DEFAULT_INDEX_INTERVAL_BYTES)
+    }
+
+    /// How often the logs are flushed and their recovery points
+    /// checkpointed.
+    pub fn checkpoint_interval_ms(&self) -> u64 {
+        self.parsed("log.flush.offset.checkpoint.interval.ms")
+            .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL_MS)
+    }
+
+    /// How many incremental fetch sessions are kept before the least
+    /// recently used one is evicted.
+    pub fn fetch_session_slots(&self) -> usize {
+        self.parsed("max.incremental.fetch.session.cache.slots")
+            .unwrap_or(DEFAULT_FETCH_SESSION_SLOTS)
+    }
+
+    /// This broker's id, from `node.id` or else the older `broker.id`.
+    pub fn node_id(&self) -> i32 {
+        self.parsed("node.id")
+            .or_else(|| self.parsed("broker.id"))
+            .unwrap_or(DEFAULT_NODE_ID)
+    }
+
+    /// The address to accept clients on, from the first entry of
+    /// `listeners`, where an empty host means every interface. Without
+    /// `listeners` the broker only listens on the loopback interface.
+    pub fn listener(&self) -> String {
+        match self.get("listeners").and_then(first_listener) {
+            Some(("", port)) => format!("0.0.0.0:{port}"),
+            Some((host, port)) => format!("{host}:{port}"),
+            None => format!("127.0.0.1:{DEFAULT_PORT}"),
+        }
+    }
+
+    /// The host and port clients are told to connect to: the first entry
+    /// of `advertised.listeners`, falling back to `listeners`.
+    pub fn advertised_listener(&self) -> (String, i32) {
+        let listener = self
+            .get("advertised.listeners")
+            .or_else(|| self.get("listeners"))
+            .and_then(first_listener);
+        let (host, port) = listener.unwrap_or(("", DEFAULT_PORT));
+        let host = match host {
+            "" => DEFAULT_HOST,
+            host => host,
+        };
+        (host.to_string(), port)
+    }
+
+    /// The largest request a client may send; larger ones get the
+    /// connection closed.
+    pub fn socket_request_max_bytes(&self) -> usize {
+        self.parsed("socket.request.max.bytes")
+            .unwrap_or(DEFAULT_SOCKET_REQUEST_MAX_BYTES)
+    }
+
+    /// Whether topics that clients ask for are created when missing.
+    pub fn auto_create_topics(&self) -> bool {
+        self.parsed("auto.create.topics.enable").unwrap_or(true)
+    }
+
+    /// How many partitions topics get when the creator does not say.
+    pub fn num_partitions(&self) -> i32 {
+        self.parsed("num.partitions").unwrap_or(DEFAULT_NUM_PARTITIONS)
+    }
+
+    /// How many replicas topics get when the creator does not say.
+    pub fn default_replication_factor(&self) -> i16 {
+        self.parsed("default.replication.factor")
+            .unwrap_or(DEFAULT_REPLICATION_FACTOR)
+    }
+
+    /// The `message.timestamp.type` of topics that do not set their own:
+    /// CreateTime or LogAppendTime.
+    pub fn message_timestamp_type(&self) -> &str {
+        self.get("log.message.timestamp.type")
+            .unwrap_or("CreateTime")
+    }
+
+    /// Whether topics may be deleted.
+    pub fn delete_topic_enable(&self) -> bool {
+        self.parsed("delete.topic.enable").unwrap_or(true)
+    }
+
+    /// How long the logs of a deleted topic are kept, renamed, before they
+    /// are removed from disk.
+    pub fn file_delete_delay_ms(&self) -> u64 {
+        self.parsed("log.segment.delete.delay.ms")
+            .unwrap_or(DEFAULT_FILE_DELETE_DELAY_MS)
+    }
+
+    /// The value of `key` parsed as a `T`, if it is set and valid.
+    fn parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|value| value.parse().ok())
+    }
+}
+
+/// The host and port of the first of `listeners`, which are written
+/// `NAME://host:port`. The host is empty for the default interface.
+fn first_listener(listeners: &str) -> Option<(&str, i32)> {
+    let listener = listeners.split(',').map(str::trim).find(|l| !l.is_empty())?;
+    let address = match listener.split_once("://") {
+        Some((_, address)) => address,
+        None => listener,
+    };
+    Some(match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().unwrap_or(DEFAULT_PORT)),
+        None => ("", DEFAULT_PORT),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_log_dirs() {
+        let config = Config::parse(
+            "# comment\nprocess.roles=broker,controller\nlog.dirs = /data/a,/data/b\n",
+        );
+        assert_eq!(config.get("process.roles"), Some("broker,controller"));
+        assert_eq!(config.log_dir(), PathBuf::from("/data/a"));
+        assert_eq!(
+            config.metadata_log_dir(),
+            PathBuf::from("/data/a/__cluster_metadata-0")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_defaults() {
+        let config = Config::parse("log.dir=/data\nmetadata.log.dir=/meta");
+        assert_eq!(config.log_dir(), PathBuf::from("/data"));
+        assert_eq!(config.metadata_log_dir(), PathBuf::from("/meta/__cluster_metadata-0"));
+        assert_eq!(Config::default().log_dir(), PathBuf::from(DEFAULT_LOG_DIR));
+        assert_eq!(Config::default().fetch_session_slots(), DEFAULT_FETCH_SESSION_SLOTS);
+        let config = Config::parse("max.incremental.fetch.session.cache.slots=2");
+        assert_eq!(config.fetch_session_slots(), 2);
+        assert_eq!(Config::default().socket_request_max_bytes(), 104857600);
+        let config = Config::parse("socket.request.max.bytes=1024");
+        assert_eq!(config.socket_request_max_bytes(), 1024);
+    }
+
+    #[test]
+    fn reads_segment_settings() {
+        let config = Config::default();
+        assert_eq!(config.segment_bytes(), 1 << 30);
+        assert_eq!(config.roll_ms(), 7 * 24 * 60 * 60 * 1000);
+        assert_eq!(config.index_interval_bytes(), 4096);
+
+        let config =
+            Config::parse("log.segment.bytes=1024\nlog.roll.hours=1\nlog.index.interval.bytes=0");
+        assert_eq!(config.segment_bytes(), 1024);
+        assert_eq!(config.roll_ms(), 60 * 60 * 1000);
+        assert_eq!(config.index_interval_bytes(), 0);
+        assert_eq!(Config::parse("log.roll.ms=5\nlog.roll.hours=1").roll_ms(), 5);
+        assert_eq!(config.checkpoint_interval_ms(), 60_000);
+        let config = Config::parse("log.segment.bytes=5000000000");
+        assert_eq!(config.segment_bytes(), i32::MAX as u64);
+    }
+
+    #[test]
+    fn reads_broker_identity() {
+        let config = Config::default();
+        assert_eq!(config.node_id(), 1);
+        assert_eq!(config.listener(), "127.0.0.1:9092");
+        assert_eq!(config.advertised_listener(), ("localhost".to_string(), 9092));
+        assert!(config.auto_create_topics());
+
+        let config = Config::parse(
+            "node.id=3\nlisteners=PLAINTEXT://:9093,CONTROLLER://:9094\nauto.create.topics.enable=false",
+        );
+        assert_eq!(config.node_id(), 3);
+        assert_eq!(config.listener(), "0.0.0.0:9093");
+        assert_eq!(config.advertised_listener(), ("localhost".to_string(), 9093));
+        assert!(!config.auto_create_topics());
+        assert!(config.delete_topic_enable());
+        assert!(!Config::parse("delete.topic.enable=false").delete_topic_enable());
+        assert_eq!(config.message_timestamp_type(), "CreateTime");
+        let config = Config::parse("log.message.timestamp.type=LogAppendTime");
+        assert_eq!(config.message_timestamp_type(), "LogAppendTime");
+        let config = Config::parse(
+            "broker.id=2\nlisteners=PLAINTEXT://:9093\nadvertised.listeners=PLAINTEXT://kafka:19092",
+        );
+        assert_eq!(config.node_id(), 2);
+        assert_eq!(config.listener(), "0.0.0.0:9093");
+        assert_eq!(config.advertised_listener(), ("kafka".to_string(), 19092));
+        let config = Config::parse("listeners=PLAINTEXT://127.0.0.1:9094");
+        assert_eq!(config.listener(), "127.0.0.1:9094");
+    }
+}
@@ -0,0 +1,313 @@
+pub use crate::messages::create_partitions_request::{
+    self, CreatePartitionsAssignment, CreatePartitionsRequest, CreatePartitionsTopic,
+};
+pub use crate::messages::create_partitions_response::{
+    self, CreatePartitionsResponse, CreatePartitionsTopicResult,
+};
+use crate::{broker::Broker, error_code, handler};
+
+impl CreatePartitionsResponse {
+    /// Grows every topic in `request` to the partition count it asks for,
+    /// or with `validate_only` just reports whether it could.
+    pub fn new(request: &CreatePartitionsRequest, broker: &Broker) -> Self {
+        let results = request
+            .topics
+            .iter()
+            .map(|topic| {
+                let names = request.topics.iter().map(|other| &other.name);
+                let created = match handler::repeated(names, &topic.name) {
+                    false => create(broker, topic, request.validate_only),
+                    true => Err(error_code::INVALID_REQUEST),
+                };
+                CreatePartitionsTopicResult {
+                    name: topic.name.clone(),
+                    error_code: created.err().unwrap_or(error_code::NONE),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Self {
+            results,
+            ..Default::default()
+        }
+    }
+}
+
+fn create(
+    broker: &Broker,
+    request: &CreatePartitionsTopic,
+    validate_only: bool,
+) -> Result<(), i16> {
+    let assignments = assign(broker, request)?;
+    match validate_only {
+        true => Ok(()),
+        false => broker.create_partitions(&request.name, request.count, &assignments),
+    }
+}
+
+/// The replicas of each partition to be added, either as `request` lists
+/// them or placed by the broker with the replication factor the topic
+/// already has.
+fn assign(broker: &Broker, request: &CreatePartitionsTopic) -> Result<Vec<Vec<i32>>, i16> {
+    let (current, replication_factor) = {
+        let image = broker.image.read().unwrap();
+        let topic = image
+            .topic(&request.name)
+            .ok_or(error_code::UNKNOWN_TOPIC_OR_PARTITION)?;
+        let replication_factor = topic
+            .partitions
+            .values()
+            .next()
+            .map_or(1, |partition| partition.replicas.len() as i16);
+        (topic.partitions.len() as i32, replication_factor)
+    };
+    if request.count <= current {
+        return Err(error_code::INVALID_PARTITIONS);
+    }
+    let Some(assignments) = &request.assignments else {
+        return broker.assign_replicas(request.count - current, replication_factor);
+    };
+    // One list per new partition.
+    let valid = assignments.len() as i32 == request.count - current
+        && assignments
+            .iter()
+            .all(|assignment| broker.valid_replicas(&assignment.broker_ids));
+    if !valid {
+        return Err(error_code::INVALID_REPLICA_ASSIGNMENT);
+    }
+    Ok(assignments
+        .iter()
+        .map(|assignment| assignment.broker_ids.clone())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        describe::{DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse, TopicRequest},
+        metadata::MetadataImage,
+        testing::{broker, captured, decode, encode, golden, hex},
+    };
+
+    fn topic(name: &str, count: i32, assignments: Option<&[&[i32]]>) -> CreatePartitionsTopic {
+        CreatePartitionsTopic {
+            name: name.to_string(),
+            count,
+            assignments: assignments.map(|assignments| {
+                assignments
+                    .iter()
+                    .map(|broker_ids| CreatePartitionsAssignment {
+                        broker_ids: broker_ids.to_vec(),
+                        ..Default::default()
+                    })
+                    .collect()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn create(
+        broker: &Broker,
+        topics: Vec<CreatePartitionsTopic>,
+        validate_only: bool,
+    ) -> Vec<i16> {
+        let request = CreatePartitionsRequest {
+            topics,
+            validate_only,
+            ..Default::default()
+        };
+        CreatePartitionsResponse::new(&request, broker)
+            .results
+            .iter()
+            .map(|topic| topic.error_code)
+            .collect()
+    }
+
+    fn partitions(broker: &Broker) -> usize {
+        broker
+            .image
+            .read()
+            .unwrap()
+            .topic("foo")
+            .unwrap()
+            .partitions
+            .len()
+    }
+
+    #[test]
+    fn decodes_request_v3() {
+        let bytes = "02 04 666f6f 00000003 02 02 00000001 00 00  00007530 00 00";
+        let request: CreatePartitionsRequest = decode(&hex(bytes), 3);
+        let topic = &request.topics[0];
+        assert_eq!(topic.name, "foo");
+        assert_eq!(topic.count, 3);
+        assert_eq!(topic.assignments.as_ref().unwrap()[0].broker_ids, [1]);
+        assert_eq!(request.timeout_ms, 30000);
+        assert_eq!(encode(&request, 3), hex(bytes));
+
+        let v0 = "00000001 0003 666f6f 00000002 ffffffff 00007530 01";
+        let request: CreatePartitionsRequest = decode(&hex(v0), 0);
+        assert_eq!(request.topics[0].assignments, None);
+        assert!(request.validate_only);
+    }
+
+    #[test]
+    fn creates_partitions() {
+        let broker = broker("create-partitions", "", &[("foo", 1)]);
+        assert_eq!(
+            create(&broker, vec![topic("foo", 3, None)], false),
+            [error_code::NONE]
+        );
+        assert_eq!(partitions(&broker), 3);
+        assert_eq!(
+            create(&broker, vec![topic("foo", 4, Some(&[&[1]]))], false),
+            [error_code::NONE]
+        );
+        let image = MetadataImage::load(&broker.config.metadata_log_dir()).unwrap();
+        let foo = image.topic("foo").unwrap();
+        assert_eq!(foo.partitions.len(), 4);
+        assert_eq!(foo.partitions[&3].leader, 1);
+        assert!(broker.config.log_dir().join("foo-3").is_dir());
+
+        // The new partitions can be described straight away.
+        let describe = DescribeTopicPartitionsRequest {
+            topics: vec![TopicRequest {
+                name: "foo".to_string(),
+                ..Default::default()
+            }],
+            response_partition_limit: 100,
+            ..Default::default()
+        };
+        let response =
+            DescribeTopicPartitionsResponse::new(&describe, &broker.image.read().unwrap());
+        assert_eq!(response.topics[0].partitions.len(), 4);
+    }
+
+    #[test]
+    fn only_validates_when_asked() {
+        let broker = broker("create-partitions-validate-only", "", &[("foo", 1)]);
+        assert_eq!(
+            create(&broker, vec![topic("foo", 2, None)], true),
+            [error_code::NONE]
+        );
+        assert_eq!(partitions(&broker), 1);
+        assert!(!broker.config.log_dir().join("foo-1").exists());
+    }
+
+    #[test]
+    fn rejects_invalid_counts_and_assignments() {
+        let broker = broker("create-partitions-invalid", "", &[("foo", 1)]);
+        let cases = [
+            (
+                topic("bar", 2, None),
+                error_code::UNKNOWN_TOPIC_OR_PARTITION,
+            ),
+            (topic("foo", 1, None), error_code::INVALID_PARTITIONS),
+            (topic("foo", 0, None), error_code::INVALID_PARTITIONS),
+            (
+                topic("foo", 3, Some(&[&[1]])),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+            (
+                topic("foo", 2, Some(&[&[2]])),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+            (
+                topic("foo", 2, Some(&[&[1, 1]])),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+        ];
+        for (topic, expected) in cases {
+            assert_eq!(
+                create(&broker, vec![topic.clone()], false),
+                [expected],
+                "{topic:?}"
+            );
+        }
+        assert_eq!(
+            create(
+                &broker,
+                vec![topic("foo", 2, None), topic("foo", 3, None)],
+                false
+            ),
+            [error_code::INVALID_REQUEST, error_code::INVALID_REQUEST]
+        );
+        assert_eq!(partitions(&broker), 1);
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let response = CreatePartitionsResponse {
+            results: vec![CreatePartitionsTopicResult {
+                name: "foo".to_string(),
+                error_code: error_code::INVALID_PARTITIONS,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            encode(&response, 0),
+            hex("00000000 00000001 0003 666f6f 0025 ffff")
+        );
+        assert_eq!(
+            encode(&response, 3),
+            hex("00000000 02 04 666f6f 0025 00 00 00")
+        );
+    }
+
+    /// Body of a v0 request librdkafka 2.12.1 sent to grow "foo" to two
+    /// partitions.
+    const LIBRDKAFKA_REQUEST_V0: &str = "00000001 0003 666f6f 00000002 ffffffff 0000ea60 00";
+
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: CreatePartitionsRequest = captured(LIBRDKAFKA_REQUEST_V0, 0);
+        assert_eq!(request.topics[0].name, "foo");
+        assert_eq!(request.topics[0].count, 2);
+        assert_eq!(request.topics[0].assignments, None);
+        assert_eq!(request.timeout_ms, 60000);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = CreatePartitionsRequest {
+            topics: vec![topic("foo", 3, Some(&[&[1]]))],
+            timeout_ms: 30000,
+            validate_only: true,
+            ..Default::default()
+        };
+        golden(
+            &request,
+            0..=3,
+            &[
+                (
+                    0,
+                    "00000001 0003 666f6f 00000003 00000001 00000001 00000001  00007530 01",
+                ),
+                (
+                    2,
+                    "02 04 666f6f 00000003 02 02 00000001 00 00  00007530 01 00",
+                ),
+            ],
+        );
+        let response = CreatePartitionsResponse {
+            throttle_time_ms: 100,
+            results: vec![CreatePartitionsTopicResult {
+                name: "foo".to_string(),
+                error_code: error_code::INVALID_PARTITIONS,
+                error_message: Some("too few".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        golden(
+            &response,
+            0..=3,
+            &[
+                (0, "00000064 00000001 0003 666f6f 0025 0007 746f6f20666577"),
+                (2, "00000064 02 04 666f6f 0025 08 746f6f20666577 00 00"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,561 @@
+pub use crate::messages::create_topics_request::{
+    self, CreatableReplicaAssignment, CreatableTopic, CreatableTopicConfig, CreateTopicsRequest,
+};
+pub use crate::messages::create_topics_response::{
+    self, CreatableTopicConfigs, CreatableTopicResult, CreateTopicsResponse,
+};
+use crate::{broker::Broker, error_code, handler, metadata};
+
+/// The `ConfigSource` of a config set on the topic itself.
+const DYNAMIC_TOPIC_CONFIG: i8 = 1;
+
+/// What a topic config's value has to look like.
+#[derive(Debug, Clone, Copy)]
+enum ConfigType {
+    Boolean,
+    /// An int no smaller than the bound.
+    Int(i32),
+    /// A long no smaller than the bound.
+    Long(i64),
+    /// A fraction between 0 and 1.
+    Ratio,
+    /// One of the values.
+    Choice(&'static [&'static str]),
+    /// A comma-separated list drawn from the values.
+    List(&'static [&'static str]),
+    String,
+}
+
+/// The topic configs Kafka knows, and how each is checked.
+const TOPIC_CONFIGS: &[(&str, ConfigType)] = &[
+    ("cleanup.policy", ConfigType::List(&["delete", "compact"])),
+    (
+        "compression.type",
+        ConfigType::Choice(&["uncompressed", "zstd", "lz4", "snappy", "gzip", "producer"]),
+    ),
+    ("delete.retention.ms", ConfigType::Long(0)),
+    ("file.delete.delay.ms", ConfigType::Long(0)),
+    ("flush.messages", ConfigType::Long(1)),
+    ("flush.ms", ConfigType::Long(0)),
+    (
+        "follower.replication.throttled.replicas",
+        ConfigType::String,
+    ),
+    ("index.interval.bytes", ConfigType::Int(0)),
+    ("leader.replication.throttled.replicas", ConfigType::String),
+    ("local.retention.bytes", ConfigType::Long(-2)),
+    ("local.retention.ms", ConfigType::Long(-2)),
+    ("max.compaction.lag.ms", ConfigType::Long(1)),
+    ("max.message.bytes", ConfigType::Int(0)),
+    ("message.downconversion.enable", ConfigType::Boolean),
+    ("message.format.version", ConfigType::String),
+    ("message.timestamp.after.max.ms", ConfigType::Long(0)),
+    ("message.timestamp.before.max.ms", ConfigType::Long(0)),
+    ("message.timestamp.difference.max.ms", ConfigType::Long(0)),
+    (
+        "message.timestamp.type",
+        ConfigType::Choice(&["CreateTime", "LogAppendTime"]),
+    ),
+    ("min.cleanable.dirty.ratio", ConfigType::Ratio),
+    ("min.compaction.lag.ms", ConfigType::Long(0)),
+    ("min.insync.replicas", ConfigType::Int(1)),
+    ("preallocate", ConfigType::Boolean),
+    ("remote.storage.enable", ConfigType::Boolean),
+    ("retention.bytes", ConfigType::Long(-1)),
+    ("retention.ms", ConfigType::Long(-1)),
+    ("segment.bytes", ConfigType::Int(14)),
+    ("segment.index.bytes", ConfigType::Int(4)),
+    ("segment.jitter.ms", ConfigType::Long(0)),
+    ("segment.ms", ConfigType::Long(1)),
+    ("unclean.leader.election.enable", ConfigType::Boolean),
+];
+
+impl ConfigType {
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            ConfigType::Boolean => {
+                value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false")
+            }
+            ConfigType::Int(min) => value.parse::<i32>().is_ok_and(|value| value >= min),
+            ConfigType::Long(min) => value.parse::<i64>().is_ok_and(|value| value >= min),
+            ConfigType::Ratio => value
+                .parse::<f64>()
+                .is_ok_and(|value| (0.0..=1.0).contains(&value)),
+            ConfigType::Choice(choices) => choices.contains(&value),
+            ConfigType::List(choices) => {
+                value.split(',').all(|item| choices.contains(&item.trim()))
+            }
+            ConfigType::String => true,
+        }
+    }
+}
+
+impl CreateTopicsResponse {
+    /// Creates every topic in `request` that passes validation, or with
+    /// `validate_only` just reports whether it would.
+    pub fn new(request: &CreateTopicsRequest, version: i16, broker: &Broker) -> Self {
+        let topics = request
+            .topics
+            .iter()
+            .map(|topic| {
+                let names = request.topics.iter().map(|other| &other.name);
+                match handler::repeated(names, &topic.name) {
+                    false => {
+                        CreatableTopicResult::create(broker, topic, version, request.validate_only)
+                    }
+                    true => CreatableTopicResult::error(&topic.name, error_code::INVALID_REQUEST),
+                }
+            })
+            .collect();
+        Self {
+            topics,
+            ..Default::default()
+        }
+    }
+}
+
+impl CreatableTopicResult {
+    fn error(name: &str, error_code: i16) -> Self {
+        Self {
+            name: name.to_string(),
+            error_code,
+            ..Default::default()
+        }
+    }
+
+    fn create(
+        broker: &Broker,
+        request: &CreatableTopic,
+        version: i16,
+        validate_only: bool,
+    ) -> Self {
+        let name = request.name.as_str();
+        if !metadata::valid_topic_name(name) {
+            return Self::error(name, error_code::INVALID_TOPIC_EXCEPTION);
+        }
+        if broker.image.read().unwrap().topic(name).is_some() {
+            return Self::error(name, error_code::TOPIC_ALREADY_EXISTS);
+        }
+        let assignments = match assign(broker, request, version) {
+            Ok(assignments) => assignments,
+            Err(error_code) => return Self::error(name, error_code),
+        };
+        let configs = match validate_configs(&request.configs) {
+            Ok(configs) => configs,
+            Err(error_code) => return Self::error(name, error_code),
+        };
+        let topic_id = match validate_only {
+            true => 0,
+            false => match broker.create_topic(name, &assignments, &configs) {
+                Ok(topic_id) => topic_id,
+                Err(error_code) => return Self::error(name, error_code),
+            },
+        };
+        Self {
+            name: name.to_string(),
+            topic_id,
+            error_code: error_code::NONE,
+            num_partitions: assignments.len() as i32,
+            replication_factor: assignments[0].len() as i16,
+            configs: Some(
+                configs
+                    .into_iter()
+                    .map(|(name, value)| CreatableTopicConfigs {
+                        name,
+                        value: Some(value),
+                        read_only: false,
+                        config_source: DYNAMIC_TOPIC_CONFIG,
+                        is_sensitive: false,
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+/// The replicas of each partition of the topic `request` asks for, either
+/// as it lists them or placed by the broker. From v4 a count of -1 takes
+/// the broker's default; before that it is as invalid as any other.
+fn assign(broker: &Broker, request: &CreatableTopic, version: i16) -> Result<Vec<Vec<i32>>, i16> {
+    if request.assignments.is_empty() {
+        let partitions = match (request.num_partitions, version) {
+            (-1, 4..) => broker.config.num_partitions(),
+            (partitions, _) => partitions,
+        };
+        let replication_factor = match (request.replication_factor, version) {
+            (-1, 4..) => broker.config.default_replication_factor(),
+            (replication_factor, _) => replication_factor,
+        };
+        return broker.assign_replicas(partitions, replication_factor);
+    }
+    if request.num_partitions != -1 || request.replication_factor != -1 {
+        return Err(error_code::INVALID_REQUEST);
+    }
+    let mut assignments: Vec<&CreatableReplicaAssignment> = request.assignments.iter().collect();
+    assignments.sort_by_key(|assignment| assignment.partition_index);
+    for (index, assignment) in assignments.iter().enumerate() {
+        // Partitions have to be numbered from 0 without gaps.
+        let valid = assignment.partition_index == index as i32
+            && broker.valid_replicas(&assignment.broker_ids);
+        if !valid {
+            return Err(error_code::INVALID_REPLICA_ASSIGNMENT);
+        }
+    }
+    Ok(assignments
+        .into_iter()
+        .map(|assignment| assignment.broker_ids.clone())
+        .collect())
+}
+
+/// Checks that every config is a known topic config, set once, to a valid
+/// value.
+fn validate_configs(configs: &[CreatableTopicConfig]) -> Result<Vec<(String, String)>, i16> {
+    let mut validated: Vec<(String, String)> = Vec::with_capacity(configs.len());
+    for config in configs {
+        if validated.iter().any(|(name, _)| *name == config.name) {
+            return Err(error_code::INVALID_REQUEST);
+        }
+        let config_type = TOPIC_CONFIGS
+            .iter()
+            .find(|(name, _)| *name == config.name)
+            .map(|&(_, config_type)| config_type);
+        let value = config.value.as_deref();
+        match (config_type, value) {
+            (Some(config_type), Some(value)) if config_type.accepts(value) => {
+                validated.push((config.name.clone(), value.to_string()))
+            }
+            _ => return Err(error_code::INVALID_CONFIG),
+        }
+    }
+    Ok(validated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata::MetadataImage,
+        testing::{broker, captured, decode, encode, golden, hex},
+    };
+
+    fn topic(name: &str, num_partitions: i32, replication_factor: i16) -> CreatableTopic {
+        CreatableTopic {
+            name: name.to_string(),
+            num_partitions,
+            replication_factor,
+            ..Default::default()
+        }
+    }
+
+    fn create(broker: &Broker, topics: Vec<CreatableTopic>) -> Vec<i16> {
+        let request = CreateTopicsRequest {
+            topics,
+            ..Default::default()
+        };
+        CreateTopicsResponse::new(&request, 7, broker)
+            .topics
+            .iter()
+            .map(|topic| topic.error_code)
+            .collect()
+    }
+
+    #[test]
+    fn decodes_request_v7() {
+        let bytes = "
+            02 04 666f6f 00000003 0001 01
+                02 0f 636c65616e75702e706f6c696379 08 636f6d70616374 00
+                00
+            00007530 01 00";
+        let request: CreateTopicsRequest = decode(&hex(bytes), 7);
+        let topic = &request.topics[0];
+        assert_eq!(topic.name, "foo");
+        assert_eq!(topic.num_partitions, 3);
+        assert_eq!(topic.configs[0].name, "cleanup.policy");
+        assert_eq!(topic.configs[0].value.as_deref(), Some("compact"));
+        assert_eq!(request.timeout_ms, 30000);
+        assert!(request.validate_only);
+        assert_eq!(encode(&request, 7), hex(bytes));
+    }
+
+    #[test]
+    fn creates_topics() {
+        let broker = broker("create-topics", "", &[]);
+        let mut foo = topic("foo", 2, 1);
+        foo.configs.push(CreatableTopicConfig {
+            name: "retention.ms".to_string(),
+            value: Some("1000".to_string()),
+            ..Default::default()
+        });
+        let request = CreateTopicsRequest {
+            topics: vec![foo],
+            ..Default::default()
+        };
+        let response = CreateTopicsResponse::new(&request, 7, &broker);
+        let created = &response.topics[0];
+        assert_eq!(created.error_code, error_code::NONE);
+        assert_eq!(created.num_partitions, 2);
+        assert_eq!(created.replication_factor, 1);
+        assert_eq!(created.configs.as_ref().unwrap()[0].name, "retention.ms");
+
+        let image = broker.image.read().unwrap();
+        let foo = image.topic("foo").unwrap();
+        assert_eq!(foo.topic_id, created.topic_id);
+        assert_eq!(foo.partitions.len(), 2);
+        drop(image);
+        let log_dir = broker.config.log_dir();
+        assert!(log_dir.join("foo-0").is_dir());
+        assert!(log_dir.join("foo-1").is_dir());
+        let image = MetadataImage::load(&broker.config.metadata_log_dir()).unwrap();
+        assert_eq!(image.topic("foo").unwrap().partitions.len(), 2);
+        assert_eq!(image.topic("foo").unwrap().configs["retention.ms"], "1000");
+
+        assert_eq!(
+            create(&broker, vec![topic("foo", 1, 1)]),
+            [error_code::TOPIC_ALREADY_EXISTS]
+        );
+    }
+
+    #[test]
+    fn only_validates_when_asked() {
+        let broker = broker("create-topics-validate-only", "", &[]);
+        let request = CreateTopicsRequest {
+            topics: vec![topic("foo", -1, -1)],
+            validate_only: true,
+            ..Default::default()
+        };
+        let response = CreateTopicsResponse::new(&request, 7, &broker);
+        assert_eq!(response.topics[0].error_code, error_code::NONE);
+        assert_eq!(response.topics[0].num_partitions, 1);
+        assert_eq!(response.topics[0].topic_id, 0);
+        assert!(broker.image.read().unwrap().topic("foo").is_none());
+        assert!(!broker.config.log_dir().join("foo-0").exists());
+    }
+
+    #[test]
+    fn takes_defaults_from_v4() {
+        let broker = broker("create-topics-defaults", "", &[]);
+        let request = |num_partitions, replication_factor| CreateTopicsRequest {
+            topics: vec![topic("foo", num_partitions, replication_factor)],
+            validate_only: true,
+            ..Default::default()
+        };
+        let created = |request, version| {
+            CreateTopicsResponse::new(&request, version, &broker).topics[0].error_code
+        };
+        assert_eq!(created(request(-1, -1), 4), error_code::NONE);
+        assert_eq!(created(request(-1, 1), 3), error_code::INVALID_PARTITIONS);
+        assert_eq!(
+            created(request(1, -1), 3),
+            error_code::INVALID_REPLICATION_FACTOR
+        );
+        assert_eq!(created(request(1, 1), 0), error_code::NONE);
+    }
+
+    #[test]
+    fn rejects_invalid_topics() {
+        let broker = broker("create-topics-invalid", "", &[]);
+        let config = |name: &str, value: Option<&str>| {
+            let mut topic = topic("foo", 1, 1);
+            topic.configs.push(CreatableTopicConfig {
+                name: name.to_string(),
+                value: value.map(str::to_string),
+                ..Default::default()
+            });
+            topic
+        };
+        let assigned = |assignments: &[(i32, &[i32])]| CreatableTopic {
+            assignments: assignments
+                .iter()
+                .map(
+                    |&(partition_index, broker_ids)| CreatableReplicaAssignment {
+                        partition_index,
+                        broker_ids: broker_ids.to_vec(),
+                        ..Default::default()
+                    },
+                )
+                .collect(),
+            ..topic("foo", -1, -1)
+        };
+        let cases = [
+            (topic("foo/bar", 1, 1), error_code::INVALID_TOPIC_EXCEPTION),
+            (topic("foo", 0, 1), error_code::INVALID_PARTITIONS),
+            (topic("foo", 1, 0), error_code::INVALID_REPLICATION_FACTOR),
+            (topic("foo", 1, 3), error_code::INVALID_REPLICATION_FACTOR),
+            (
+                config("retention.ms", Some("soon")),
+                error_code::INVALID_CONFIG,
+            ),
+            (
+                config("no.such.config", Some("1")),
+                error_code::INVALID_CONFIG,
+            ),
+            (config("cleanup.policy", None), error_code::INVALID_CONFIG),
+            (
+                assigned(&[(0, &[1]), (2, &[1])]),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+            (
+                assigned(&[(0, &[2])]),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+            (
+                assigned(&[(0, &[1, 1])]),
+                error_code::INVALID_REPLICA_ASSIGNMENT,
+            ),
+            (
+                CreatableTopic {
+                    num_partitions: 1,
+                    ..assigned(&[(0, &[1])])
+                },
+                error_code::INVALID_REQUEST,
+            ),
+        ];
+        for (topic, expected) in cases {
+            assert_eq!(
+                create(&broker, vec![topic.clone()]),
+                [expected],
+                "{topic:?}"
+            );
+        }
+        assert_eq!(
+            create(&broker, vec![topic("foo", 1, 1), topic("foo", 2, 1)]),
+            [error_code::INVALID_REQUEST, error_code::INVALID_REQUEST]
+        );
+        assert_eq!(broker.image.read().unwrap().topics().count(), 0);
+
+        assert_eq!(
+            create(&broker, vec![assigned(&[(1, &[1]), (0, &[1])])]),
+            [error_code::NONE]
+        );
+        let image = broker.image.read().unwrap();
+        assert_eq!(image.topic("foo").unwrap().partitions.len(), 2);
+    }
+
+    #[test]
+    fn encodes_response_v7() {
+        let response = CreateTopicsResponse {
+            topics: vec![CreatableTopicResult {
+                name: "foo".to_string(),
+                topic_id: 2,
+                num_partitions: 1,
+                replication_factor: 1,
+                configs: Some(Vec::new()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let v7 = "
+            00000000
+            02 04 666f6f 00000000000000000000000000000002 0000 00 00000001 0001 01 00
+            00";
+        assert_eq!(encode(&response, 7), hex(v7));
+        let v0 = "00000001 0003 666f6f 0000";
+        assert_eq!(encode(&response, 0), hex(v0));
+    }
+
+    /// Body of a v4 request librdkafka 2.12.1 sent for "foo" with one
+    /// partition and one replica.
+    const LIBRDKAFKA_REQUEST_V4: &str = "
+        00000001 0003 666f6f 00000001 0001 00000000 00000000
+        0000ea60 00";
+
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: CreateTopicsRequest = captured(LIBRDKAFKA_REQUEST_V4, 4);
+        assert_eq!(request.topics[0].name, "foo");
+        assert_eq!(request.topics[0].num_partitions, 1);
+        assert_eq!(request.topics[0].replication_factor, 1);
+        assert!(request.topics[0].assignments.is_empty());
+        assert_eq!(request.timeout_ms, 60000);
+        assert!(!request.validate_only);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = CreateTopicsRequest {
+            topics: vec![CreatableTopic {
+                name: "foo".to_string(),
+                num_partitions: 3,
+                replication_factor: 1,
+                assignments: vec![CreatableReplicaAssignment {
+                    partition_index: 0,
+                    broker_ids: vec![1],
+                    ..Default::default()
+                }],
+                configs: vec![CreatableTopicConfig {
+                    name: "a".to_string(),
+                    value: Some("b".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            timeout_ms: 30000,
+            validate_only: true,
+            ..Default::default()
+        };
+        let v0 = "00000001 0003 666f6f 00000003 0001
+            00000001 00000000 00000001 00000001
+            00000001 0001 61 0001 62
+            00007530";
+        golden(
+            &request,
+            0..=7,
+            &[
+                (0, v0),
+                (1, &format!("{v0} 01")),
+                (
+                    5,
+                    "02 04 666f6f 00000003 0001
+                        02 00000000 02 00000001 00
+                        02 02 61 02 62 00
+                        00
+                    00007530 01 00",
+                ),
+            ],
+        );
+
+        let response = CreateTopicsResponse {
+            throttle_time_ms: 100,
+            topics: vec![CreatableTopicResult {
+                name: "foo".to_string(),
+                topic_id: 2,
+                error_code: error_code::TOPIC_ALREADY_EXISTS,
+                error_message: Some("taken".to_string()),
+                topic_config_error_code: 1,
+                num_partitions: 3,
+                replication_factor: 1,
+                configs: Some(vec![CreatableTopicConfigs {
+                    name: "a".to_string(),
+                    value: Some("b".to_string()),
+                    config_source: 5,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let v1 = "00000001 0003 666f6f 0024 0005 74616b656e";
+        // The config error code is tag 0, two bytes long.
+        let v5_topic = "0024 06 74616b656e 00000003 0001
+            02 02 61 02 62 00 05 00 00
+            01 00 02 0001";
+        golden(
+            &response,
+            0..=7,
+            &[
+                (0, "00000001 0003 666f6f 0024"),
+                (1, v1),
+                (2, &format!("00000064 {v1}")),
+                (5, &format!("00000064 02 04 666f6f {v5_topic} 00")),
+                (
+                    7,
+                    &format!(
+                        "00000064 02 04 666f6f 00000000000000000000000000000002 {v5_topic} 00"
+                    ),
+                ),
+            ],
+        );
+    }
+}
@@ -0,0 +1,317 @@
+pub use crate::messages::delete_topics_request::{self, DeleteTopicState, DeleteTopicsRequest};
+pub use crate::messages::delete_topics_response::{
+    self, DeletableTopicResult, DeleteTopicsResponse,
+};
+use crate::{broker::Broker, error_code, handler};
+
+impl DeleteTopicsResponse {
+    /// Deletes the topics in `request`, which up to v5 are named and from
+    /// v6 are given by name or by id.
+    pub fn new(request: &DeleteTopicsRequest, broker: &Broker) -> Self {
+        let requested: Vec<(Option<&str>, u128)> = request
+            .topic_names
+            .iter()
+            .map(|name| (Some(name.as_str()), 0))
+            .chain(
+                request
+                    .topics
+                    .iter()
+                    .map(|topic| (topic.name.as_deref(), topic.topic_id)),
+            )
+            .collect();
+        let responses = requested
+            .iter()
+            .map(|&(name, topic_id)| {
+                match handler::repeated(requested.iter().copied(), (name, topic_id)) {
+                    false => DeletableTopicResult::delete(broker, name, topic_id),
+                    true => {
+                        DeletableTopicResult::error(name, topic_id, error_code::INVALID_REQUEST)
+                    }
+                }
+            })
+            .collect();
+        Self {
+            responses,
+            ..Default::default()
+        }
+    }
+}
+
+impl DeletableTopicResult {
+    fn error(name: Option<&str>, topic_id: u128, error_code: i16) -> Self {
+        Self {
+            name: name.map(str::to_string),
+            topic_id,
+            error_code,
+            ..Default::default()
+        }
+    }
+
+    fn delete(broker: &Broker, name: Option<&str>, topic_id: u128) -> Self {
+        if !broker.config.delete_topic_enable() {
+            return Self::error(name, topic_id, error_code::TOPIC_DELETION_DISABLED);
+        }
+        let deleted = match (name, topic_id) {
+            (Some(_), 1..) => Err(error_code::INVALID_REQUEST),
+            (Some(name), 0) => {
+                let topic_id = broker.image.read().unwrap().topic(name).map(|topic| topic.topic_id);
+                match topic_id {
+                    Some(topic_id) => broker
+                        .delete_topic(topic_id)
+                        .map(|name| (name, topic_id))
+                        // Someone else deleted it first, which by name is
+                        // an unknown topic rather than an unknown id.
+                        .map_err(|error_code| match error_code {
+                            error_code::UNKNOWN_TOPIC_ID => error_code::UNKNOWN_TOPIC_OR_PARTITION,
+                            error_code => error_code,
+                        }),
+                    None => Err(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+                }
+            }
+            (None, topic_id) => broker.delete_topic(topic_id).map(|name| (name, topic_id)),
+        };
+        match deleted {
+            Ok((name, topic_id)) => Self {
+                name: Some(name),
+                topic_id,
+                error_code: error_code::NONE,
+                ..Default::default()
+            },
+            Err(error_code) => Self::error(name, topic_id, error_code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        fetch::{FetchPartition, FetchRequest, FetchResponse, FetchTopic},
+        messages::TopicRecord,
+        metadata::MetadataImage,
+        testing::{broker, captured, decode, encode, golden, hex, temp_dir, topic_id},
+    };
+
+    /// A broker with topics "foo" and "bar" of two partitions each.
+    fn two_topics(name: &str, config: &str) -> Broker {
+        broker(name, config, &[("foo", 2), ("bar", 2)])
+    }
+
+    fn by_name(names: &[&str]) -> DeleteTopicsRequest {
+        DeleteTopicsRequest {
+            topic_names: names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn error_codes(response: &DeleteTopicsResponse) -> Vec<i16> {
+        response
+            .responses
+            .iter()
+            .map(|topic| topic.error_code)
+            .collect()
+    }
+
+    #[test]
+    fn decodes_requests() {
+        let v1 = "00000001 0003 666f6f 00007530";
+        let request: DeleteTopicsRequest = decode(&hex(v1), 1);
+        assert_eq!(request.topic_names, ["foo"]);
+        assert_eq!(request.timeout_ms, 30000);
+        assert_eq!(encode(&request, 1), hex(v1));
+
+        let v6 = "02 00 00000000000000000000000000000002 00 00007530 00";
+        let request: DeleteTopicsRequest = decode(&hex(v6), 6);
+        assert_eq!(request.topics[0].name, None);
+        assert_eq!(request.topics[0].topic_id, 2);
+        assert_eq!(encode(&request, 6), hex(v6));
+    }
+
+    #[test]
+    fn deletes_topics_by_name() {
+        let broker = two_topics("delete-topics-by-name", "log.segment.delete.delay.ms=0");
+        let foo = topic_id(&broker, "foo");
+        let response = DeleteTopicsResponse::new(&by_name(&["foo", "baz"]), &broker);
+        assert_eq!(
+            error_codes(&response),
+            [error_code::NONE, error_code::UNKNOWN_TOPIC_OR_PARTITION]
+        );
+        assert_eq!(response.responses[0].topic_id, foo);
+        assert!(broker.image.read().unwrap().topic("foo").is_none());
+        let image = MetadataImage::load(&broker.config.metadata_log_dir()).unwrap();
+        assert!(image.topic("foo").is_none());
+        assert!(image.topic("bar").is_some());
+
+        let log_dir = broker.config.log_dir();
+        assert!(!log_dir.join("foo-0").exists());
+        assert!(log_dir.join(format!("foo-1.{foo:032x}-delete")).is_dir());
+        broker.logs.delete_expired().unwrap();
+        assert!(!log_dir.join(format!("foo-1.{foo:032x}-delete")).exists());
+        assert!(log_dir.join("bar-1").is_dir());
+
+        // Fetches for the topic now fail.
+        let fetch = FetchRequest {
+            topics: vec![FetchTopic {
+                topic_id: foo,
+                partitions: vec![FetchPartition::default()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let response = FetchResponse::new(&fetch, 16, &broker);
+        assert_eq!(
+            response.responses[0].partitions[0].error_code,
+            error_code::UNKNOWN_TOPIC_ID
+        );
+        // And it can be created again, with a new id.
+        let assignments = broker.assign_replicas(1, 1).unwrap();
+        let recreated = broker.create_topic("foo", &assignments, &[]).unwrap();
+        assert_ne!(recreated, foo);
+    }
+
+    #[test]
+    fn deletes_topics_by_id() {
+        let broker = two_topics("delete-topics-by-id", "");
+        let bar = topic_id(&broker, "bar");
+        let state = |name: Option<&str>, topic_id| DeleteTopicState {
+            name: name.map(str::to_string),
+            topic_id,
+            ..Default::default()
+        };
+        let request = DeleteTopicsRequest {
+            topics: vec![
+                state(None, bar),
+                state(None, 7),
+                state(Some("foo"), 9),
+                state(Some("foo"), 0),
+            ],
+            ..Default::default()
+        };
+        let response = DeleteTopicsResponse::new(&request, &broker);
+        assert_eq!(
+            error_codes(&response),
+            [
+                error_code::NONE,
+                error_code::UNKNOWN_TOPIC_ID,
+                error_code::INVALID_REQUEST,
+                error_code::NONE,
+            ]
+        );
+        assert_eq!(response.responses[0].name.as_deref(), Some("bar"));
+        assert_eq!(broker.image.read().unwrap().topics().count(), 0);
+    }
+
+    #[test]
+    fn rejects_duplicates_and_disabled_deletion() {
+        let broker = two_topics("delete-topics-duplicates", "");
+        let response = DeleteTopicsResponse::new(&by_name(&["foo", "foo"]), &broker);
+        assert_eq!(
+            error_codes(&response),
+            [error_code::INVALID_REQUEST, error_code::INVALID_REQUEST]
+        );
+
+        let broker = two_topics("delete-topics-disabled", "delete.topic.enable=false");
+        let response = DeleteTopicsResponse::new(&by_name(&["foo"]), &broker);
+        assert_eq!(error_codes(&response), [error_code::TOPIC_DELETION_DISABLED]);
+        assert!(broker.image.read().unwrap().topic("foo").is_some());
+    }
+
+    #[test]
+    fn reports_storage_errors_by_name() {
+        // The metadata log cannot be created under a plain file.
+        let dir = temp_dir("delete-topics-storage-error");
+        fs::write(dir.join("metadata"), "").unwrap();
+        let config = Config::parse(&format!(
+            "log.dirs={}\nmetadata.log.dir={}",
+            dir.display(),
+            dir.join("metadata").display()
+        ));
+        let mut image = MetadataImage::default();
+        image.add_topic(TopicRecord {
+            name: "foo".to_string(),
+            topic_id: 2,
+            ..Default::default()
+        });
+        let broker = Broker::new(config, image);
+        let response = DeleteTopicsResponse::new(&by_name(&["foo"]), &broker);
+        assert_eq!(error_codes(&response), [error_code::KAFKA_STORAGE_ERROR]);
+        assert!(broker.image.read().unwrap().topic("foo").is_some());
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let response = DeleteTopicsResponse {
+            responses: vec![DeletableTopicResult {
+                name: Some("foo".to_string()),
+                topic_id: 2,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(encode(&response, 0), hex("00000001 0003 666f6f 0000"));
+        let v6 = "00000000 02 04 666f6f 00000000000000000000000000000002 0000 00 00 00";
+        assert_eq!(encode(&response, 6), hex(v6));
+    }
+
+    /// Body of a v1 request librdkafka 2.12.1 sent for "foo".
+    const LIBRDKAFKA_REQUEST_V1: &str = "00000001 0003 666f6f 0000ea60";
+
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: DeleteTopicsRequest = captured(LIBRDKAFKA_REQUEST_V1, 1);
+        assert_eq!(request.topic_names, ["foo"]);
+        assert_eq!(request.timeout_ms, 60000);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = DeleteTopicsRequest {
+            topics: vec![DeleteTopicState {
+                name: None,
+                topic_id: 2,
+                ..Default::default()
+            }],
+            topic_names: vec!["foo".to_string()],
+            timeout_ms: 30000,
+            ..Default::default()
+        };
+        golden(
+            &request,
+            0..=6,
+            &[
+                (0, "00000001 0003 666f6f 00007530"),
+                (4, "02 04 666f6f 00007530 00"),
+                (6, "02 00 00000000000000000000000000000002 00 00007530 00"),
+            ],
+        );
+
+        let response = DeleteTopicsResponse {
+            throttle_time_ms: 100,
+            responses: vec![DeletableTopicResult {
+                name: Some("foo".to_string()),
+                topic_id: 2,
+                error_code: error_code::TOPIC_DELETION_DISABLED,
+                error_message: Some("off".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        golden(
+            &response,
+            0..=6,
+            &[
+                (0, "00000001 0003 666f6f 0049"),
+                (1, "00000064 00000001 0003 666f6f 0049"),
+                (4, "00000064 02 04 666f6f 0049 00 00"),
+                (5, "00000064 02 04 666f6f 0049 04 6f6666 00 00"),
+                (
+                    6,
+                    "00000064 02 04 666f6f 00000000000000000000000000000002 0049 04 6f6666 00 00",
+                ),
+            ],
+        );
+    }
+}
@@ -1,199 +1,223 @@
-use bytes::{Buf, BufMut};
+pub use crate::messages::describe_topic_partitions_request::{
+    self, DescribeTopicPartitionsRequest, TopicRequest,
+};
+pub use crate::messages::describe_topic_partitions_response::{
+    self, Cursor, DescribeTopicPartitionsResponse, DescribeTopicPartitionsResponsePartition,
+    DescribeTopicPartitionsResponseTopic,
+};
+use crate::{error_code, metadata::MetadataImage};
+
+/// The most partitions returned in one response, whatever the client asks for.
+const MAX_PARTITION_LIMIT: i32 = 2000;
+
+/// The `topic_authorized_operations` of a topic whose operations were not
+/// asked for. DescribeTopicPartitions has no way to ask, and there are no
+/// ACLs to report them from.
+const AUTHORIZED_OPERATIONS_OMITTED: i32 = i32::MIN;
 
-use crate::deserialize::Deserialize;
-
-#[derive(Debug)]
-pub struct DescribeTopicPartitionsRequest {
-    topics: (u8, Vec<(u8, String)>),
-    response_partition_limit: i32,
-    cursor: Cursor,
-}
-
-impl<T: Buf> Deserialize<T> for DescribeTopicPartitionsRequest {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let mut topics = (buffer.get_u8(), Vec::new());
-        println!("topics: {}", topics.0);
-        for _ in 0..topics.0 - 1 {
-            let len = buffer.get_u8();
-            println!("topic_name: {}", len);
-            let topic_name = (
-                len,
-                String::from_utf8_lossy(&buffer.copy_to_bytes(len as usize - 1)).to_string(),
-            );
-            println!("{:?}", topic_name);
-            topics.1.push(topic_name);
+impl DescribeTopicPartitionsResponse {
+    /// Describes the requested topics from `image`, sorted by name and
+    /// starting at the request's cursor. Once the partition limit is hit the
+    /// response carries a cursor pointing at the first partition left out.
+    pub fn new(request: &DescribeTopicPartitionsRequest, image: &MetadataImage) -> Self {
+        let mut names: Vec<&str> = request.topics.iter().map(|topic| topic.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let cursor = request.cursor.as_ref();
+        let mut remaining = request.response_partition_limit.clamp(0, MAX_PARTITION_LIMIT);
+        let mut topics = Vec::new();
+        let mut next_cursor = None;
+        for name in names {
+            if cursor.is_some_and(|cursor| name < cursor.topic_name.as_str()) {
+                continue;
+            }
+            let Some(topic) = image.topic(name) else {
+                topics.push(DescribeTopicPartitionsResponseTopic {
+                    error_code: error_code::UNKNOWN_TOPIC_OR_PARTITION,
+                    name: Some(name.to_string()),
+                    topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+                    ..Default::default()
+                });
+                continue;
+            };
+            let first_partition = match cursor {
+                Some(cursor) if cursor.topic_name == name => cursor.partition_index,
+                _ => 0,
+            };
+            let mut partitions = Vec::new();
+            for (&partition_index, partition) in topic.partitions.range(first_partition..) {
+                if remaining == 0 {
+                    next_cursor = Some(Cursor {
+                        topic_name: name.to_string(),
+                        partition_index,
+                        ..Default::default()
+                    });
+                    break;
+                }
+                remaining -= 1;
+                partitions.push(DescribeTopicPartitionsResponsePartition {
+                    error_code: error_code::NONE,
+                    partition_index,
+                    leader_id: partition.leader,
+                    leader_epoch: partition.leader_epoch,
+                    replica_nodes: partition.replicas.clone(),
+                    isr_nodes: partition.isr.clone(),
+                    eligible_leader_replicas: Some(
+                        partition.eligible_leader_replicas.clone().unwrap_or_default(),
+                    ),
+                    last_known_elr: Some(partition.last_known_elr.clone().unwrap_or_default()),
+                    offline_replicas: Vec::new(),
+                    ..Default::default()
+                });
+            }
+            if next_cursor.is_some() && partitions.is_empty() {
+                break;
+            }
+            topics.push(DescribeTopicPartitionsResponseTopic {
+                error_code: error_code::NONE,
+                name: Some(name.to_string()),
+                topic_id: topic.topic_id,
+                is_internal: false,
+                partitions,
+                topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+                ..Default::default()
+            });
+            if next_cursor.is_some() {
+                break;
+            }
         }
-        // buffer.get_u8();
-        let response_partition_limit = buffer.get_i32();
-        let cursor = Cursor::from_bytes(buffer);
-        buffer.get_u8();
-
         Self {
             topics,
-            response_partition_limit,
-            cursor,
+            next_cursor,
+            ..Default::default()
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct Cursor {
-    pub topic_name: (u8, String),
-    pub partition_index: i32,
-}
-
-impl<T: Buf> Deserialize<T> for Cursor {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let len = buffer.get_u8();
-        println!("cursor topic_name: {}", len);
-        let topic_name = (
-            len,
-            String::from_utf8_lossy(&buffer.copy_to_bytes(len as usize - 1)).to_string(),
-        );
-        // let partition_index = buffer.get_i32();
-        buffer.get_u8();
-
-        Self {
-            topic_name,
-            partition_index: 0,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messages::{PartitionRecord, TopicRecord},
+        testing::{decode, encode, hex},
+    };
+
+    /// A request for "foo" with no cursor.
+    const REQUEST: &str = "02 04 666f6f 00  00000064  ff  00";
+
+    #[test]
+    fn decodes_request() {
+        let request: DescribeTopicPartitionsRequest = decode(&hex(REQUEST), 0);
+        assert_eq!(request.topics.len(), 1);
+        assert_eq!(request.topics[0].name, "foo");
+        assert_eq!(request.response_partition_limit, 100);
+        assert_eq!(request.cursor, None);
+        assert_eq!(encode(&request, 0), hex(REQUEST));
     }
-}
 
-impl Into<Vec<u8>> for &Cursor {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.topic_name.0.to_be_bytes());
-        buffer.extend_from_slice(&self.topic_name.1.as_bytes());
-        buffer.extend_from_slice(&self.partition_index.to_be_bytes());
-        buffer
+    #[test]
+    fn decodes_request_with_cursor() {
+        let bytes = hex("02 04 666f6f 00  00000064  01 04 666f6f 00000002 00  00");
+        let request: DescribeTopicPartitionsRequest = decode(&bytes, 0);
+        let cursor = request.cursor.as_ref().unwrap();
+        assert_eq!(cursor.topic_name, "foo");
+        assert_eq!(cursor.partition_index, 2);
+        assert_eq!(encode(&request, 0), bytes);
     }
-}
-
-#[derive(Debug)]
-pub struct DescribeTopicPartitionsResponse {
-    throttle_time_ms: i32,
-    topics: (u8, Vec<Topic>),
-    next_cursor: Cursor,
-}
 
-impl DescribeTopicPartitionsResponse {
-    pub fn new(error_code: i16, request: &DescribeTopicPartitionsRequest) -> Self {
-        let topics = (
-            request.topics.0,
-            request
-                .topics
-                .1
-                .iter()
-                .map(|topic| Topic::new(error_code, topic.clone()))
-                .collect(),
-        );
-        Self {
-            throttle_time_ms: 0,
-            topics,
-            next_cursor: request.cursor.clone(),
+    #[test]
+    fn decodes_empty_and_null_topic_arrays() {
+        for topics in ["01", "00"] {
+            let bytes = hex(&format!("{topics} 00000064 ff 00"));
+            let request: DescribeTopicPartitionsRequest = decode(&bytes, 0);
+            assert!(request.topics.is_empty());
         }
     }
-}
 
-impl Into<Vec<u8>> for &DescribeTopicPartitionsResponse {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.throttle_time_ms.to_be_bytes());
-        buffer.extend_from_slice(&self.topics.0.to_be_bytes());
-        self.topics
-            .1
-            .iter()
-            .for_each(|topic| buffer.extend_from_slice(&Into::<Vec<u8>>::into(topic)));
-        buffer.extend_from_slice(&Into::<Vec<u8>>::into(&self.next_cursor));
-        buffer.put_u8(0);
-        buffer
+    #[test]
+    fn encodes_unknown_topic_response() {
+        let request: DescribeTopicPartitionsRequest = decode(&hex(REQUEST), 0);
+        let response = DescribeTopicPartitionsResponse::new(&request, &MetadataImage::default());
+        let expected = "
+            00000000
+            02 0003 04 666f6f 00000000000000000000000000000000 00 01 80000000 00
+            ff
+            00";
+        assert_eq!(encode(&response, 0), hex(expected));
     }
-}
-
-#[derive(Debug)]
-struct Topic {
-    error_code: i16,
-    name: (u8, String),
-    topic_id: u128,
-    is_internal: bool,
-    partitions: (u8, Vec<Partition>),
-    topic_authorized_operations: i32,
-}
 
-impl Topic {
-    fn new(error_code: i16, name: (u8, String)) -> Self {
-        Self {
-            error_code,
-            name,
-            topic_id: 0,
-            is_internal: false,
-            partitions: (0, Vec::new()),
-            topic_authorized_operations: 0,
+    fn image() -> MetadataImage {
+        let mut image = MetadataImage::default();
+        for (name, topic_id, partitions) in [("bar", 1, 2), ("foo", 2, 1)] {
+            image.add_topic(TopicRecord {
+                name: name.to_string(),
+                topic_id,
+                ..Default::default()
+            });
+            for partition_id in 0..partitions {
+                image.add_partition(PartitionRecord {
+                    partition_id,
+                    topic_id,
+                    replicas: vec![1],
+                    isr: vec![1],
+                    leader: 1,
+                    leader_epoch: 0,
+                    ..Default::default()
+                });
+            }
         }
+        image
     }
-}
 
-impl Into<Vec<u8>> for &Topic {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.error_code.to_be_bytes());
-        buffer.extend_from_slice(&self.name.0.to_be_bytes());
-        buffer.extend_from_slice(&self.name.1.as_bytes());
-        buffer.extend_from_slice(&self.topic_id.to_be_bytes());
-        buffer.put_u8(self.is_internal as u8);
-        buffer.extend_from_slice(&self.partitions.0.to_be_bytes());
-        self.partitions
-            .1
-            .iter()
-            .for_each(|partition| buffer.extend_from_slice(&Into::<Vec<u8>>::into(partition)));
-        buffer.extend_from_slice(&self.topic_authorized_operations.to_be_bytes());
-        buffer.put_u8(0);
-        buffer
+    fn request(names: &[&str], limit: i32) -> DescribeTopicPartitionsRequest {
+        DescribeTopicPartitionsRequest {
+            topics: names
+                .iter()
+                .map(|name| TopicRequest {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            response_partition_limit: limit,
+            ..Default::default()
+        }
     }
-}
 
-#[derive(Debug)]
-struct Partition {
-    error_code: i16,
-    partition_index: i32,
-    leader_id: i32,
-    leader_epoch: i32,
-    replica_nodes: (u8, Vec<i32>),
-    isr_nodes: (u8, Vec<i32>),
-    eligible_leader_replicas: (u8, Vec<i32>),
-    last_known_elr: (u8, Vec<i32>),
-    offline_replicas: (u8, Vec<i32>),
-}
+    #[test]
+    fn describes_known_topics() {
+        let response = DescribeTopicPartitionsResponse::new(&request(&["foo"], 100), &image());
+        let topic = &response.topics[0];
+        assert_eq!(topic.error_code, 0);
+        assert_eq!(topic.topic_id, 2);
+        assert_eq!(topic.partitions.len(), 1);
+        assert_eq!(topic.partitions[0].leader_id, 1);
+        assert_eq!(topic.partitions[0].replica_nodes, vec![1]);
+        assert_eq!(topic.partitions[0].isr_nodes, vec![1]);
+        assert_eq!(topic.topic_authorized_operations, i32::MIN);
+        assert_eq!(response.next_cursor, None);
+    }
 
-impl Into<Vec<u8>> for &Partition {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.error_code.to_be_bytes());
-        buffer.extend_from_slice(&self.partition_index.to_be_bytes());
-        buffer.extend_from_slice(&self.leader_id.to_be_bytes());
-        buffer.extend_from_slice(&self.leader_epoch.to_be_bytes());
-        buffer.extend_from_slice(&self.replica_nodes.0.to_be_bytes());
-        self.replica_nodes.1.iter().for_each(|node| {
-            buffer.extend_from_slice(&node.to_be_bytes());
-        });
-        buffer.extend_from_slice(&self.isr_nodes.0.to_be_bytes());
-        self.isr_nodes.1.iter().for_each(|node| {
-            buffer.extend_from_slice(&node.to_be_bytes());
-        });
-        buffer.extend_from_slice(&self.eligible_leader_replicas.0.to_be_bytes());
-        self.eligible_leader_replicas.1.iter().for_each(|node| {
-            buffer.extend_from_slice(&node.to_be_bytes());
-        });
-        buffer.extend_from_slice(&self.last_known_elr.0.to_be_bytes());
-        self.last_known_elr.1.iter().for_each(|node| {
-            buffer.extend_from_slice(&node.to_be_bytes());
-        });
-        buffer.extend_from_slice(&self.offline_replicas.0.to_be_bytes());
-        self.offline_replicas.1.iter().for_each(|node| {
-            buffer.extend_from_slice(&node.to_be_bytes());
+    #[test]
+    fn pages_through_partitions() {
+        let image = image();
+        let mut request = request(&["foo", "baz", "bar"], 2);
+        let response = DescribeTopicPartitionsResponse::new(&request, &image);
+        let names: Vec<_> = response.topics.iter().map(|topic| topic.name.as_deref()).collect();
+        assert_eq!(names, [Some("bar"), Some("baz")]);
+        assert_eq!(response.topics[0].partitions.len(), 2);
+        assert_eq!(response.topics[1].error_code, 3);
+        let cursor = response.next_cursor.unwrap();
+        assert_eq!((cursor.topic_name.as_str(), cursor.partition_index), ("foo", 0));
+
+        request.cursor = Some(describe_topic_partitions_request::Cursor {
+            topic_name: cursor.topic_name,
+            partition_index: cursor.partition_index,
+            ..Default::default()
         });
-        buffer.put_u8(0);
-        buffer
+        let response = DescribeTopicPartitionsResponse::new(&request, &image);
+        assert_eq!(response.topics.len(), 1);
+        assert_eq!(response.topics[0].topic_id, 2);
+        assert_eq!(response.next_cursor, None);
     }
 }
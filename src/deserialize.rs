@@ -1,5 +1,251 @@
 use bytes::{Buf, Bytes};
+use thiserror::Error;
 
-pub trait Deserialize<T: Buf> {
-    fn from_bytes(buffer: &mut T) -> Self;
-}
\ No newline at end of file
+pub trait Deserialize<T: Buf>: Sized {
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError>;
+}
+
+/// Decoding for types whose wire layout depends on the API version.
+pub trait VersionedDeserialize<T: Buf>: Sized {
+    fn from_bytes(buffer: &mut T, version: i16) -> Result<Self, DecodeError>;
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("short buffer: needed {needed} bytes but only {remaining} remaining")]
+    ShortBuffer { needed: usize, remaining: usize },
+    #[error("invalid length: {0}")]
+    InvalidLength(i64),
+    #[error("malformed varint")]
+    InvalidVarint,
+    #[error("tagged field {0} out of order or repeated")]
+    InvalidTag(u32),
+    #[error("record batch crc mismatch: expected {expected:#010x}, computed {computed:#010x}")]
+    InvalidCrc { expected: u32, computed: u32 },
+    #[error("unsupported record batch magic: {0}")]
+    UnsupportedMagic(i8),
+    #[error("unsupported record batch compression: {0}")]
+    UnsupportedCompression(i16),
+    #[error("record batch last offset delta {last_offset_delta} does not fit {count} records")]
+    InvalidLastOffsetDelta { last_offset_delta: i32, count: i32 },
+    #[error("invalid utf-8 in string")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+macro_rules! get_checked {
+    ($($name:ident => $get:ident: $ty:ty),* $(,)?) => {
+        $(
+            fn $name(&mut self) -> Result<$ty, DecodeError> {
+                self.ensure(std::mem::size_of::<$ty>())?;
+                Ok(self.$get())
+            }
+        )*
+    };
+}
+
+/// Bounds-checked counterparts of the `Buf::get_*` accessors. Named apart
+/// from the `Buf::try_get_*` methods of `bytes` 1.10 and later, which would
+/// otherwise make every call ambiguous.
+pub trait BufExt: Buf {
+    fn ensure(&self, needed: usize) -> Result<(), DecodeError> {
+        let remaining = self.remaining();
+        if remaining < needed {
+            return Err(DecodeError::ShortBuffer { needed, remaining });
+        }
+        Ok(())
+    }
+
+    get_checked! {
+        get_checked_u8 => get_u8: u8,
+        get_checked_i8 => get_i8: i8,
+        get_checked_i16 => get_i16: i16,
+        get_checked_i32 => get_i32: i32,
+        get_checked_u32 => get_u32: u32,
+        get_checked_i64 => get_i64: i64,
+    }
+
+    fn get_checked_bool(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.get_checked_u8()? != 0)
+    }
+
+    fn copy_to_bytes_checked(&mut self, len: usize) -> Result<Bytes, DecodeError> {
+        self.ensure(len)?;
+        Ok(self.copy_to_bytes(len))
+    }
+
+    fn get_checked_string(&mut self, len: usize) -> Result<String, DecodeError> {
+        let bytes = self.copy_to_bytes_checked(len)?;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    /// Reads an INT16 length where -1 means null.
+    fn get_checked_length16(&mut self) -> Result<Option<usize>, DecodeError> {
+        match self.get_checked_i16()? {
+            -1 => Ok(None),
+            len => usize::try_from(len)
+                .map(Some)
+                .map_err(|_| DecodeError::InvalidLength(len as i64)),
+        }
+    }
+
+    /// Reads an INT32 length where -1 means null.
+    fn get_checked_length32(&mut self) -> Result<Option<usize>, DecodeError> {
+        match self.get_checked_i32()? {
+            -1 => Ok(None),
+            len => usize::try_from(len)
+                .map(Some)
+                .map_err(|_| DecodeError::InvalidLength(len as i64)),
+        }
+    }
+
+    /// Reads an INT16-length-prefixed string.
+    fn get_checked_string16(&mut self) -> Result<String, DecodeError> {
+        self.get_checked_nullable_string()?
+            .ok_or(DecodeError::InvalidLength(-1))
+    }
+
+    /// Reads an INT16-length-prefixed string, where a length of -1 means null.
+    fn get_checked_nullable_string(&mut self) -> Result<Option<String>, DecodeError> {
+        match self.get_checked_length16()? {
+            Some(len) => Ok(Some(self.get_checked_string(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_checked_nullable_bytes(&mut self) -> Result<Option<Bytes>, DecodeError> {
+        match self.get_checked_length32()? {
+            Some(len) => Ok(Some(self.copy_to_bytes_checked(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads an INT32-length-prefixed array. A null array is returned as
+    /// an empty one.
+    fn get_checked_array<E, F>(&mut self, f: F) -> Result<Vec<E>, DecodeError>
+    where
+        Self: Sized,
+        F: FnMut(&mut Self) -> Result<E, DecodeError>,
+    {
+        Ok(self.get_checked_nullable_array(f)?.unwrap_or_default())
+    }
+
+    fn get_checked_nullable_array<E, F>(&mut self, f: F) -> Result<Option<Vec<E>>, DecodeError>
+    where
+        Self: Sized,
+        F: FnMut(&mut Self) -> Result<E, DecodeError>,
+    {
+        match self.get_checked_length32()? {
+            Some(len) => Ok(Some(self.get_checked_elements(len, f)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads an unsigned LEB128 varint of at most five bytes.
+    fn get_checked_unsigned_varint(&mut self) -> Result<u32, DecodeError> {
+        let mut value = 0u32;
+        for i in 0..5 {
+            let byte = self.get_checked_u8()?;
+            if i == 4 && byte > 0x0f {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= ((byte & 0x7f) as u32) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    /// Reads an unsigned LEB128 varlong of at most ten bytes.
+    fn get_checked_unsigned_varlong(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let byte = self.get_checked_u8()?;
+            if i == 9 && byte > 0x01 {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= ((byte & 0x7f) as u64) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    /// Reads a zigzag-encoded signed varint, as used inside record batches.
+    fn get_checked_varint(&mut self) -> Result<i32, DecodeError> {
+        let value = self.get_checked_unsigned_varint()?;
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+
+    /// Reads a zigzag-encoded signed varlong.
+    fn get_checked_varlong(&mut self) -> Result<i64, DecodeError> {
+        let value = self.get_checked_unsigned_varlong()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Reads a compact length (stored as `N + 1`), where `None` means null.
+    fn get_checked_compact_length(&mut self) -> Result<Option<usize>, DecodeError> {
+        let len = self.get_checked_unsigned_varint()?;
+        Ok(len.checked_sub(1).map(|len| len as usize))
+    }
+
+    fn get_checked_compact_string(&mut self) -> Result<String, DecodeError> {
+        self.get_checked_compact_nullable_string()?
+            .ok_or(DecodeError::InvalidLength(-1))
+    }
+
+    fn get_checked_compact_nullable_string(&mut self) -> Result<Option<String>, DecodeError> {
+        match self.get_checked_compact_length()? {
+            Some(len) => Ok(Some(self.get_checked_string(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_checked_compact_nullable_bytes(&mut self) -> Result<Option<Bytes>, DecodeError> {
+        match self.get_checked_compact_length()? {
+            Some(len) => Ok(Some(self.copy_to_bytes_checked(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a compact array, decoding each element with `f`. A null array
+    /// is returned as an empty one.
+    fn get_checked_compact_array<E, F>(&mut self, f: F) -> Result<Vec<E>, DecodeError>
+    where
+        Self: Sized,
+        F: FnMut(&mut Self) -> Result<E, DecodeError>,
+    {
+        Ok(self.get_checked_compact_nullable_array(f)?.unwrap_or_default())
+    }
+
+    fn get_checked_compact_nullable_array<E, F>(
+        &mut self,
+        f: F,
+    ) -> Result<Option<Vec<E>>, DecodeError>
+    where
+        Self: Sized,
+        F: FnMut(&mut Self) -> Result<E, DecodeError>,
+    {
+        match self.get_checked_compact_length()? {
+            Some(len) => Ok(Some(self.get_checked_elements(len, f)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_checked_elements<E, F>(&mut self, len: usize, mut f: F) -> Result<Vec<E>, DecodeError>
+    where
+        Self: Sized,
+        F: FnMut(&mut Self) -> Result<E, DecodeError>,
+    {
+        // Cap the up-front allocation by what the buffer could possibly
+        // hold, so a bogus length cannot trigger a huge reservation.
+        let mut items = Vec::with_capacity(len.min(self.remaining()));
+        for _ in 0..len {
+            items.push(f(self)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Buf + ?Sized> BufExt for T {}
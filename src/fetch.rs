@@ -1,334 +1,767 @@
-use std::borrow::BorrowMut;
-
-use bytes::{Buf, BufMut, Bytes};
-
-use crate::deserialize::Deserialize;
-
-/*
-Fetch Request (Version: 16) => max_wait_ms min_bytes max_bytes isolation_level session_id session_epoch [topics] [forgotten_topics_data] rack_id TAG_BUFFER
-  max_wait_ms => INT32
-  min_bytes => INT32
-  max_bytes => INT32
-  isolation_level => INT8
-  session_id => INT32
-  session_epoch => INT32
-  topics => topic_id [partitions] TAG_BUFFER
-    topic_id => UUID
-    partitions => partition current_leader_epoch fetch_offset last_fetched_epoch log_start_offset partition_max_bytes TAG_BUFFER
-      partition => INT32
-      current_leader_epoch => INT32
-      fetch_offset => INT64
-      last_fetched_epoch => INT32
-      log_start_offset => INT64
-      partition_max_bytes => INT32
-  forgotten_topics_data => topic_id [partitions] TAG_BUFFER
-    topic_id => UUID
-    partitions => INT32
-  rack_id => COMPACT_STRING
-*/
-#[derive(Debug)]
-pub struct FetchRequest {
-    pub max_wait_ms: i32,
-    pub min_bytes: i32,
-    pub max_bytes: i32,
-    pub isolation_level: i8,
-    pub session_id: i32,
-    pub session_epoch: i32,
-    pub topics: (u8, Vec<Topic>),
-    pub forgotten_topics_data: (u8, Vec<ForgottenTopicsData>),
-    pub rack_id: (u8, String),
-}
+use std::{sync::atomic::Ordering, time::Duration};
+
+use bytes::Bytes;
+use tokio::time::{timeout_at, Instant};
 
-impl<T: Buf> Deserialize<T> for FetchRequest {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let max_wait_ms = buffer.get_i32();
-        let min_bytes = buffer.get_i32();
-        let max_bytes = buffer.get_i32();
-        let isolation_level = buffer.get_i8();
-        let session_id = buffer.get_i32();
-        let session_epoch = buffer.get_i32();
-        let mut topics = (buffer.get_u8(), Vec::new());
-        for _ in 0..topics.0 - 1 {
-            let topic = Topic::from_bytes(buffer);
-            println!("{:?}", topic);
-            topics.1.push(topic);
+pub use crate::messages::fetch_request::{self, FetchPartition, FetchRequest, FetchTopic};
+pub use crate::messages::fetch_response::{
+    self, FetchResponse, FetchableTopicResponse, PartitionData,
+};
+use crate::{
+    broker::Broker,
+    error_code,
+    metadata::{self, MetadataImage, TopicImage},
+};
+
+impl FetchResponse {
+    /// Answers `request` within the fetch session it belongs to, if any.
+    pub async fn handle(request: &FetchRequest, version: i16, broker: &Broker) -> Self {
+        let context = match broker.fetch_sessions.begin(request) {
+            Ok(context) => context,
+            Err(error_code) => {
+                return Self {
+                    error_code,
+                    ..Default::default()
+                }
+            }
+        };
+        let mut response = Self::delayed(&context.request, version, broker).await;
+        broker.fetch_sessions.complete(&context, &mut response);
+        response
+    }
+
+    /// Answers `request` once `min_bytes` of records are available, a
+    /// partition reports an error, or `max_wait_ms` has passed, whichever
+    /// comes first. In the meantime the request sits in the broker's fetch
+    /// purgatory and is re-evaluated whenever one of its partitions is
+    /// appended to.
+    pub async fn delayed(request: &FetchRequest, version: i16, broker: &Broker) -> Self {
+        let max_wait = Duration::from_millis(request.max_wait_ms.max(0) as u64);
+        let deadline = Instant::now() + max_wait;
+        let watched = Self::watched(request, version, broker);
+        loop {
+            let appended = broker
+                .fetch_purgatory
+                .watch(watched.iter().map(|(name, partition)| (name.as_str(), *partition)));
+            let response = Self::new(request, version, broker);
+            if response.satisfies(request.min_bytes) {
+                return response;
+            }
+            if timeout_at(deadline, appended.notified()).await.is_err() {
+                return response;
+            }
         }
+    }
 
-        let mut forgotten_topics_data = (buffer.get_u8(), Vec::new());
-        println!("forgotten_topics_data: {}", forgotten_topics_data.0);
-        for _ in 0..forgotten_topics_data.0 - 1 {
-            let data = ForgottenTopicsData::from_bytes(buffer);
-            forgotten_topics_data.1.push(data);
+    /// The partitions whose appends may complete `request`, by topic name.
+    /// Unknown topics fail the fetch straight away, so are not watched.
+    fn watched(request: &FetchRequest, version: i16, broker: &Broker) -> Vec<(String, i32)> {
+        let image = broker.image.read().unwrap();
+        let mut watched = Vec::new();
+        for topic in &request.topics {
+            let Ok(topic_image) = lookup(&image, topic, version) else {
+                continue;
+            };
+            watched.extend(
+                topic
+                    .partitions
+                    .iter()
+                    .map(|partition| (topic_image.name.clone(), partition.partition)),
+            );
         }
+        watched
+    }
 
-        let mut rack_id = (buffer.get_u8(), String::new());
-        println!("rack_id: {}", rack_id.0);
+    /// Whether the response can be sent without waiting for more data.
+    fn satisfies(&self, min_bytes: i32) -> bool {
+        let partitions = self.responses.iter().flat_map(|topic| &topic.partitions);
+        let mut bytes = 0;
+        for partition in partitions {
+            if partition.error_code != error_code::NONE {
+                return true;
+            }
+            bytes += partition.records.as_ref().map_or(0, Bytes::len);
+        }
+        bytes >= usize::try_from(min_bytes).unwrap_or(0)
+    }
 
-        rack_id.1 =
-            String::from_utf8_lossy(&buffer.copy_to_bytes(rack_id.0 as usize - 1)).to_string();
+    /// Answers every requested partition, in request order. The response is
+    /// bounded by `max_bytes` and each partition by its
+    /// `partition_max_bytes`, except that the first batch found is always
+    /// returned whole (KIP-74). Which partition gets first pick of the
+    /// budget rotates from one request to the next, so that a busy
+    /// partition cannot starve the others.
+    pub fn new(request: &FetchRequest, version: i16, broker: &Broker) -> Self {
+        let requested: Vec<(&FetchTopic, &FetchPartition)> = request
+            .topics
+            .iter()
+            .flat_map(|topic| {
+                topic
+                    .partitions
+                    .iter()
+                    .map(move |partition| (topic, partition))
+            })
+            .collect();
 
-        buffer.get_u8();
+        let mut partitions = vec![PartitionData::default(); requested.len()];
+        let first = match requested.len() {
+            0 => 0,
+            len => broker.fetch_rotation.fetch_add(1, Ordering::Relaxed) % len,
+        };
+        // Everything is looked up before any log is read, so that the image
+        // is not locked for the reads.
+        let resolved: Vec<Result<(String, i32), i16>> = {
+            let image = broker.image.read().unwrap();
+            requested
+                .iter()
+                .map(|&(topic, partition)| resolve(&image, topic, partition, version))
+                .collect()
+        };
+        let mut remaining = usize::try_from(request.max_bytes).unwrap_or(0);
+        let mut min_one = true;
+        for index in (first..requested.len()).chain(0..first) {
+            let (_, partition) = requested[index];
+            let max_bytes = usize::try_from(partition.partition_max_bytes)
+                .unwrap_or(0)
+                .min(remaining);
+            let data = match &resolved[index] {
+                Ok((topic, leader_epoch)) => {
+                    PartitionData::read(broker, topic, *leader_epoch, partition, max_bytes, min_one)
+                }
+                Err(error_code) => PartitionData::error(partition.partition, *error_code),
+            };
+            let size = data.records.as_ref().map_or(0, Bytes::len);
+            if size > 0 {
+                remaining = remaining.saturating_sub(size);
+                min_one = false;
+            }
+            partitions[index] = data;
+        }
 
+        let mut partitions = partitions.into_iter();
+        let responses = request
+            .topics
+            .iter()
+            .map(|topic| FetchableTopicResponse {
+                topic: topic.topic.clone(),
+                topic_id: topic.topic_id,
+                partitions: partitions.by_ref().take(topic.partitions.len()).collect(),
+                ..Default::default()
+            })
+            .collect();
         Self {
-            max_wait_ms,
-            min_bytes,
-            max_bytes,
-            isolation_level,
-            session_id,
-            session_epoch,
-            topics,
-            forgotten_topics_data,
-            rack_id,
+            session_id: request.session_id,
+            responses,
+            ..Default::default()
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Topic {
-    topic_id: u128,
-    partitions: (u8, Vec<PartitionReq>),
+/// The topic `topic` asks for. Topics are named up to v12 and identified
+/// by id from v13, where a zero id is as unknown as any other.
+fn lookup<'a>(
+    image: &'a MetadataImage,
+    topic: &FetchTopic,
+    version: i16,
+) -> Result<&'a TopicImage, i16> {
+    match version {
+        ..=12 => image
+            .topic(&topic.topic)
+            .ok_or(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+        _ => image
+            .topic_by_id(topic.topic_id)
+            .ok_or(error_code::UNKNOWN_TOPIC_ID),
+    }
+}
+
+/// The name and leader epoch of the topic partition `partition` asks for.
+fn resolve(
+    image: &MetadataImage,
+    topic: &FetchTopic,
+    partition: &FetchPartition,
+    version: i16,
+) -> Result<(String, i32), i16> {
+    let topic_image = lookup(image, topic, version)?;
+    match topic_image.partitions.get(&partition.partition) {
+        Some(state) => Ok((topic_image.name.clone(), state.leader_epoch)),
+        None => Err(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+    }
 }
 
-impl<T: Buf> Deserialize<T> for Topic {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let topic_id = buffer.get_u128();
-        println!("topic_id: {}", topic_id);
-        let mut partitions = (buffer.get_u8(), Vec::new());
-        println!("partitions: {}", partitions.0);
-        for _ in 0..partitions.0 - 1 {
-            let partition = PartitionReq::from_bytes(buffer);
-            println!("{:?}", partition);
-            partitions.1.push(partition);
+impl PartitionData {
+    /// A partition that could not be served; offsets are unknown.
+    fn error(partition_index: i32, error_code: i16) -> Self {
+        Self {
+            partition_index,
+            error_code,
+            high_watermark: -1,
+            last_stable_offset: -1,
+            log_start_offset: -1,
+            records: Some(Bytes::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Serves `request` from the log of partition `request.partition` of
+    /// `topic`, whose leader is at `leader_epoch`, starting at the batch
+    /// holding the fetch offset and returning at most `max_bytes` unless
+    /// `min_one`.
+    fn read(
+        broker: &Broker,
+        topic: &str,
+        leader_epoch: i32,
+        request: &FetchPartition,
+        max_bytes: usize,
+        min_one: bool,
+    ) -> Self {
+        let index = request.partition;
+        let epoch = metadata::check_leader_epoch(request.current_leader_epoch, leader_epoch);
+        if let Err(error_code) = epoch {
+            return Self::error(index, error_code);
         }
-        buffer.get_u8();
 
+        let read = broker.logs.get(topic, index).and_then(|log| {
+            let log = log.lock().unwrap();
+            let range = log.log_start_offset()..=log.log_end_offset();
+            let records = match range.contains(&request.fetch_offset) {
+                true => Some(log.read(request.fetch_offset, max_bytes, min_one)?),
+                false => None,
+            };
+            Ok((range, records))
+        });
+        let (range, records) = match read {
+            Ok(read) => read,
+            Err(err) => {
+                println!("reading {}-{}: {}", topic, index, err);
+                return Self::error(index, error_code::KAFKA_STORAGE_ERROR);
+            }
+        };
+        let Some(records) = records else {
+            return Self::error(index, error_code::OFFSET_OUT_OF_RANGE);
+        };
         Self {
-            topic_id,
-            partitions,
+            partition_index: index,
+            error_code: error_code::NONE,
+            high_watermark: *range.end(),
+            last_stable_offset: *range.end(),
+            log_start_offset: *range.start(),
+            records: Some(records),
+            ..Default::default()
         }
     }
 }
 
-#[derive(Debug)]
-pub struct PartitionReq {
-    partition: i32,
-    current_leader_epoch: i32,
-    fetch_offset: i64,
-    last_fetched_epoch: i32,
-    log_start_offset: i64,
-    partition_max_bytes: i32,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messages::{
+            fetch_request::{ForgottenTopic, ReplicaState},
+            fetch_response::{AbortedTransaction, LeaderIdAndEpoch, NodeEndpoint},
+        },
+        record::{read_batches, Record, RecordBatch},
+        serialize::Serialize,
+        testing::{broker, captured, decode, encode, golden, hex, serialize_batches, topic_id},
+    };
 
-impl<T: Buf> Deserialize<T> for PartitionReq {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let partition = buffer.get_i32();
-        let current_leader_epoch = buffer.get_i32();
-        let fetch_offset = buffer.get_i64();
-        let last_fetched_epoch = buffer.get_i32();
-        let log_start_offset = buffer.get_i64();
-        let partition_max_bytes = buffer.get_i32();
+    /// A v4 consumer fetch of partition 0 of "foo".
+    const REQUEST_V4: &str = "
+        ffffffff 000001f4 00000001 03200000 00
+        00000001 0003 666f6f
+            00000001 00000000 0000000000000000 00100000";
 
-        buffer.get_u8();
+    /// A v16 fetch of partition 0 of topic id 1.
+    const REQUEST_V16: &str = "
+        000001f4 00000001 000003e8 00 00000000 00000000
+        02 00000000000000000000000000000001
+            02 00000000 ffffffff 0000000000000000 ffffffff 0000000000000000 00100000 00
+            00
+        01 01 00";
 
-        Self {
-            partition,
-            current_leader_epoch,
-            fetch_offset,
-            last_fetched_epoch,
-            log_start_offset,
-            partition_max_bytes,
+    #[test]
+    fn decodes_request_v4() {
+        let request: FetchRequest = decode(&hex(REQUEST_V4), 4);
+        assert_eq!(request.replica_id, -1);
+        assert_eq!(request.max_wait_ms, 500);
+        assert_eq!(request.max_bytes, 52428800);
+        assert_eq!(request.topics.len(), 1);
+        assert_eq!(request.topics[0].topic, "foo");
+        assert_eq!(request.topics[0].partitions[0].partition_max_bytes, 1048576);
+        assert_eq!(encode(&request, 4), hex(REQUEST_V4));
+    }
+
+    #[test]
+    fn decodes_request_v16() {
+        let request: FetchRequest = decode(&hex(REQUEST_V16), 16);
+        assert_eq!(request.max_bytes, 1000);
+        assert_eq!(request.topics[0].topic_id, 1);
+        let partition = &request.topics[0].partitions[0];
+        assert_eq!(partition.current_leader_epoch, -1);
+        assert_eq!(partition.last_fetched_epoch, -1);
+        assert_eq!(encode(&request, 16), hex(REQUEST_V16));
+    }
+
+    #[test]
+    fn decodes_empty_and_null_topic_arrays() {
+        let prefix = "000001f4 00000001 000003e8 00 00000000 00000000";
+        for topics in ["01", "00"] {
+            let bytes = hex(&format!("{prefix} {topics} 01 01 00"));
+            let request: FetchRequest = decode(&bytes, 16);
+            assert!(request.topics.is_empty());
         }
     }
-}
 
-#[derive(Debug)]
-pub struct ForgottenTopicsData {
-    topic_id: u128,
-    partitions: (u8, Vec<i32>),
-}
+    #[test]
+    fn encodes_unknown_topic_response() {
+        let request: FetchRequest = decode(&hex(REQUEST_V16), 16);
+        let response = FetchResponse::new(&request, 16, &Broker::default());
+        let expected = "
+            00000000 0000 00000000
+            02 00000000000000000000000000000001
+                02 00000000 0064 ffffffffffffffff ffffffffffffffff ffffffffffffffff
+                    01 ffffffff 01 00
+                00
+            00";
+        assert_eq!(encode(&response, 16), hex(expected));
 
-impl<T: Buf> Deserialize<T> for ForgottenTopicsData {
-    fn from_bytes(mut buffer: &mut T) -> Self {
-        let topic_id = buffer.get_u128();
-        let mut partitions = (buffer.get_u8(), Vec::new());
+        let request: FetchRequest = decode(&hex(REQUEST_V4), 4);
+        let response = FetchResponse::new(&request, 4, &Broker::default());
+        let expected = "
+            00000000
+            00000001 0003 666f6f
+                00000001 00000000 0003 ffffffffffffffff ffffffffffffffff
+                    00000000 00000000";
+        assert_eq!(encode(&response, 4), hex(expected));
+    }
 
-        for _ in 0..partitions.0 - 1 {
-            let partition = buffer.get_i32();
-            partitions.1.push(partition);
-        }
-        buffer.get_u8();
+    /// A broker whose "foo" partition 0 holds batches at offsets 0-1 and 2.
+    fn with_records(name: &str) -> Broker {
+        let broker = broker(name, "", &[("foo", 1)]);
+        append(&broker, 2);
+        append(&broker, 1);
+        broker
+    }
 
-        Self {
-            topic_id,
-            partitions,
-        }
+    /// Appends a batch of `count` records to the end of "foo" partition 0.
+    fn append(broker: &Broker, count: i32) {
+        let batch = RecordBatch {
+            last_offset_delta: count - 1,
+            records: (0..count)
+                .map(|offset_delta| Record {
+                    offset_delta,
+                    value: Some(Bytes::from_static(b"hello")),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let log = broker.logs.get("foo", 0).unwrap();
+        let mut log = log.lock().unwrap();
+        log.append(&mut serialize_batches(&[batch])).unwrap();
     }
-}
 
-/*
-Fetch Response (Version: 16) => throttle_time_ms error_code session_id [responses] TAG_BUFFER
-  throttle_time_ms => INT32
-  error_code => INT16
-  session_id => INT32
-  responses => topic_id [partitions] TAG_BUFFER
-    topic_id => UUID
-    partitions => partition_index error_code high_watermark last_stable_offset log_start_offset [aborted_transactions] preferred_read_replica records TAG_BUFFER
-      partition_index => INT32
-      error_code => INT16
-      high_watermark => INT64
-      last_stable_offset => INT64
-      log_start_offset => INT64
-      aborted_transactions => producer_id first_offset TAG_BUFFER
-        producer_id => INT64
-        first_offset => INT64
-      preferred_read_replica => INT32
-      records => COMPACT_RECORDS
-*/
-#[derive(Debug)]
-pub struct FetchResponse {
-    throttle_time_ms: i32,
-    error_code: i16,
-    session_id: i32,
-    responses: (u8, Vec<Response>),
-}
+    fn fetch(topic_id: u128, fetch_offset: i64) -> FetchRequest {
+        FetchRequest {
+            topics: vec![FetchTopic {
+                topic_id,
+                partitions: vec![FetchPartition {
+                    partition: 0,
+                    fetch_offset,
+                    partition_max_bytes: 1 << 20,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
 
-impl FetchResponse {
-    pub fn new(error_code: i16, request: &FetchRequest) -> Self {
-        let responses = request
-            .topics
-            .1
+    fn base_offsets(partition: &PartitionData) -> Vec<i64> {
+        let records = partition.records.as_deref().unwrap();
+        read_batches(records)
+            .unwrap()
             .iter()
-            .map(|topic| Response::new(topic.topic_id))
-            .collect();
-        Self {
-            throttle_time_ms: 0,
-            error_code,
-            session_id: request.session_id,
-            responses: (request.topics.0, responses),
-        }
+            .map(|batch| batch.base_offset)
+            .collect()
     }
-}
 
-impl Into<Vec<u8>> for &FetchResponse {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.throttle_time_ms.to_be_bytes());
-        buffer.extend_from_slice(&self.error_code.to_be_bytes());
-        buffer.extend_from_slice(&self.session_id.to_be_bytes());
-        buffer.put_u8(self.responses.0);
-        buffer.extend_from_slice(
-            &self
-                .responses
-                .1
-                .iter()
-                .map(|response| Into::<Vec<u8>>::into(response))
-                .collect::<Vec<Vec<u8>>>()
-                .concat(),
-        );
-        buffer.put_u8(0);
-        buffer
+    #[test]
+    fn serves_records_from_the_log() {
+        let broker = with_records("serves-records");
+        let foo = topic_id(&broker, "foo");
+        let response = FetchResponse::new(&fetch(foo, 0), 16, &broker);
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(partition.error_code, 0);
+        assert_eq!(partition.high_watermark, 3);
+        assert_eq!(partition.last_stable_offset, 3);
+        assert_eq!(partition.log_start_offset, 0);
+        assert_eq!(base_offsets(partition), [0, 2]);
+
+        let response = FetchResponse::new(&fetch(foo, 2), 16, &broker);
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [2]);
+
+        // Consumers before v13 name the topic instead, which later
+        // versions do not fall back to.
+        let mut request = fetch(0, 0);
+        request.topics[0].topic = "foo".to_string();
+        let response = FetchResponse::new(&request, 12, &broker);
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [0, 2]);
+        let response = FetchResponse::new(&request, 13, &broker);
+        assert_eq!(error_codes(&response), [error_code::UNKNOWN_TOPIC_ID]);
     }
-}
 
-#[derive(Debug)]
-struct Response {
-    topic_id: u128,
-    partitions: (u8, Vec<PartitionResp>),
-}
+    fn batch_sizes(partition: &PartitionData) -> Vec<usize> {
+        let records = partition.records.as_deref().unwrap();
+        read_batches(records)
+            .unwrap()
+            .iter()
+            .map(Serialize::encoded_len)
+            .collect()
+    }
 
-impl Response {
-    fn new(topic_id: u128) -> Self {
-        Self {
-            topic_id,
-            partitions: (2, vec![PartitionResp::new(0, 100)]),
-        }
+    #[test]
+    fn limits_partition_bytes() {
+        let broker = with_records("partition-max-bytes");
+        let foo = topic_id(&broker, "foo");
+        let response = FetchResponse::new(&fetch(foo, 0), 16, &broker);
+        let sizes = batch_sizes(&response.responses[0].partitions[0]);
+
+        let mut request = fetch(foo, 0);
+        request.topics[0].partitions[0].partition_max_bytes = (sizes[0] + sizes[1] - 1) as i32;
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [0]);
+
+        // A batch larger than the limit is still returned, so that the
+        // consumer can make progress.
+        request.topics[0].partitions[0].partition_max_bytes = 1;
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [0]);
     }
-}
 
-impl Into<Vec<u8>> for &Response {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.topic_id.to_be_bytes());
-        buffer.put_u8(self.partitions.0);
-        buffer.extend_from_slice(
-            &self
+    #[test]
+    fn limits_response_bytes_and_rotates_partitions() {
+        let broker = with_records("max-bytes");
+        let foo = topic_id(&broker, "foo");
+        let response = FetchResponse::new(&fetch(foo, 0), 16, &broker);
+        let sizes = batch_sizes(&response.responses[0].partitions[0]);
+
+        let mut request = fetch(foo, 0);
+        let partition = request.topics[0].partitions[0].clone();
+        request.topics[0].partitions.push(partition);
+        request.max_bytes = (sizes[0] + sizes[1] + 1) as i32;
+        let response = FetchResponse::new(&request, 16, &broker);
+        let served = |response: &FetchResponse| -> Vec<Vec<i64>> {
+            response.responses[0]
                 .partitions
-                .1
                 .iter()
-                .map(|partition| Into::<Vec<u8>>::into(partition))
-                .collect::<Vec<Vec<u8>>>()
-                .concat(),
-        );
-        buffer.put_u8(0);
-        buffer
+                .map(base_offsets)
+                .collect()
+        };
+        // The rotation has moved on by one since the first fetch above.
+        assert_eq!(served(&response), [vec![], vec![0, 2]]);
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(served(&response), [vec![0, 2], vec![]]);
+
+        // Only the first partition served may exceed the budget.
+        request.max_bytes = 1;
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(served(&response), [vec![], vec![0]]);
     }
-}
 
-#[derive(Debug)]
-struct PartitionResp {
-    partition_index: i32,
-    error_code: i16,
-    high_watermark: i64,
-    last_stable_offset: i64,
-    log_start_offset: i64,
-    aborted_transactions: (u8, Vec<AbortedTransaction>),
-    preferred_read_replica: i32,
-    records: (u8, Vec<u8>),
-}
+    #[tokio::test]
+    async fn waits_for_min_bytes() {
+        let broker = with_records("long-poll");
+        let foo = topic_id(&broker, "foo");
+        let mut request = fetch(foo, 3);
+        request.min_bytes = 1;
+        request.max_wait_ms = 50;
+        let started = Instant::now();
+        let response = FetchResponse::delayed(&request, 16, &broker).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), []);
 
-impl PartitionResp {
-    fn new(partition_index: i32, error_code: i16) -> Self {
-        Self {
-            partition_index,
-            error_code,
-            high_watermark: 0,
-            last_stable_offset: 0,
-            log_start_offset: 0,
-            aborted_transactions: (0, vec![]),
-            preferred_read_replica: 0,
-            records: (0, vec![]),
-        }
+        // An append to the partition completes the fetch early.
+        request.max_wait_ms = 10_000;
+        let appending = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            append(&broker, 1);
+            broker.fetch_purgatory.wake("foo", 0);
+        };
+        let started = Instant::now();
+        let (response, ()) = tokio::join!(FetchResponse::delayed(&request, 16, &broker), appending);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [3]);
+
+        // Data that is already there, or an error, needs no waiting.
+        let response = FetchResponse::delayed(&request, 16, &broker).await;
+        assert_eq!(base_offsets(&response.responses[0].partitions[0]), [3]);
+        request.topics[0].partitions[0].fetch_offset = 9;
+        let response = FetchResponse::delayed(&request, 16, &broker).await;
+        assert_eq!(error_codes(&response), [error_code::OFFSET_OUT_OF_RANGE]);
     }
-}
 
-impl Into<Vec<u8>> for &PartitionResp {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.partition_index.to_be_bytes());
-        buffer.extend_from_slice(&self.error_code.to_be_bytes());
-        buffer.extend_from_slice(&self.high_watermark.to_be_bytes());
-        buffer.extend_from_slice(&self.last_stable_offset.to_be_bytes());
-        buffer.extend_from_slice(&self.log_start_offset.to_be_bytes());
-        buffer.put_u8(self.aborted_transactions.0);
-        buffer.extend_from_slice(
-            &self
-                .aborted_transactions
-                .1
-                .iter()
-                .map(|aborted_transaction| Into::<Vec<u8>>::into(aborted_transaction))
-                .collect::<Vec<Vec<u8>>>()
-                .concat(),
+    fn error_codes(response: &FetchResponse) -> Vec<i16> {
+        response.responses[0]
+            .partitions
+            .iter()
+            .map(|partition| partition.error_code)
+            .collect()
+    }
+
+    #[test]
+    fn reports_errors_per_partition() {
+        let broker = with_records("partition-errors");
+        let foo = topic_id(&broker, "foo");
+        let mut request = fetch(foo, 0);
+        let partition = |partition, fetch_offset, current_leader_epoch| FetchPartition {
+            partition,
+            fetch_offset,
+            current_leader_epoch,
+            ..Default::default()
+        };
+        request.topics[0].partitions = vec![
+            partition(0, 3, -1),
+            partition(0, 4, -1),
+            partition(0, -1, -1),
+            partition(1, 0, -1),
+            partition(0, 0, 0),
+            partition(0, 0, -2),
+            partition(0, 0, 1),
+        ];
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(
+            error_codes(&response),
+            [
+                error_code::NONE,
+                error_code::OFFSET_OUT_OF_RANGE,
+                error_code::OFFSET_OUT_OF_RANGE,
+                error_code::UNKNOWN_TOPIC_OR_PARTITION,
+                error_code::NONE,
+                error_code::FENCED_LEADER_EPOCH,
+                error_code::UNKNOWN_LEADER_EPOCH,
+            ]
+        );
+        let out_of_range = &response.responses[0].partitions[1];
+        assert_eq!(out_of_range.high_watermark, -1);
+        assert_eq!(out_of_range.records.as_deref(), Some(&[][..]));
+
+        let mut request = fetch(7, 0);
+        request.topics[0].partitions.push(partition(3, 0, -1));
+        let response = FetchResponse::new(&request, 16, &broker);
+        assert_eq!(
+            error_codes(&response),
+            [error_code::UNKNOWN_TOPIC_ID, error_code::UNKNOWN_TOPIC_ID]
         );
-        buffer.extend_from_slice(&self.preferred_read_replica.to_be_bytes());
-        buffer.put_u8(self.records.0);
-        buffer.extend_from_slice(&self.records.1);
-        buffer.put_u8(0);
-        buffer
+        assert_eq!(response.responses[0].partitions[1].partition_index, 3);
     }
-}
 
-#[derive(Debug)]
-struct AbortedTransaction {
-    producer_id: i64,
-    first_offset: i64,
-}
+    /// Body of a v16 fetch from offset 0 of partition 0 of "foo" sent by
+    /// librdkafka 2.12.1.
+    const LIBRDKAFKA_REQUEST_V16: &str = "
+        000001f4 00000001 03200000 01 00000000 ffffffff
+        02 3e01eefaa07e4ada8b6cba8d8b96b0b5
+            02 00000000 00000000 0000000000000000 ffffffff ffffffffffffffff 00100000 00
+            00
+        01 01 00";
 
-impl Into<Vec<u8>> for &AbortedTransaction {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.producer_id.to_be_bytes());
-        buffer.extend_from_slice(&self.first_offset.to_be_bytes());
-        buffer.put_u8(0);
-        buffer
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: FetchRequest = captured(LIBRDKAFKA_REQUEST_V16, 16);
+        assert_eq!(request.max_wait_ms, 500);
+        assert_eq!(request.max_bytes, 52428800);
+        assert_eq!(request.isolation_level, 1);
+        assert_eq!(request.session_epoch, -1);
+        assert_eq!(
+            request.topics[0].topic_id,
+            0x3e01eefaa07e4ada8b6cba8d8b96b0b5
+        );
+        let partition = &request.topics[0].partitions[0];
+        assert_eq!(partition.current_leader_epoch, 0);
+        assert_eq!(partition.fetch_offset, 0);
+        assert_eq!(partition.partition_max_bytes, 1048576);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = FetchRequest {
+            replica_id: -1,
+            replica_state: ReplicaState {
+                replica_id: 1,
+                replica_epoch: 5,
+                ..Default::default()
+            },
+            max_wait_ms: 500,
+            min_bytes: 1,
+            max_bytes: 0x100000,
+            isolation_level: 1,
+            session_id: 7,
+            session_epoch: 2,
+            topics: vec![FetchTopic {
+                topic: "foo".to_string(),
+                topic_id: 2,
+                partitions: vec![FetchPartition {
+                    partition: 0,
+                    current_leader_epoch: 3,
+                    fetch_offset: 4,
+                    last_fetched_epoch: 6,
+                    log_start_offset: 0,
+                    partition_max_bytes: 0x10000,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            forgotten_topics_data: vec![ForgottenTopic {
+                topic: "bar".to_string(),
+                topic_id: 3,
+                partitions: vec![1],
+                ..Default::default()
+            }],
+            rack_id: "r".to_string(),
+            ..Default::default()
+        };
+        let foo = "00000000000000000000000000000002";
+        let bar = "00000000000000000000000000000003";
+        let topics = |partition: &str| format!("00000001 0003 666f6f 00000001 {partition}");
+        let v0 = topics("00000000 0000000000000004 00010000");
+        let v5 = topics("00000000 0000000000000004 0000000000000000 00010000");
+        let v9 = topics("00000000 00000003 0000000000000004 0000000000000000 00010000");
+        let forgotten = "00000001 0003 626172 00000001 00000001";
+        let session = "00100000 01 00000007 00000002";
+        let v12 = "00000000 00000003 0000000000000004 00000006 0000000000000000 00010000 00";
+        golden(
+            &request,
+            0..=16,
+            &[
+                (0, &format!("ffffffff 000001f4 00000001 {v0}")),
+                (3, &format!("ffffffff 000001f4 00000001 00100000 {v0}")),
+                (4, &format!("ffffffff 000001f4 00000001 00100000 01 {v0}")),
+                (5, &format!("ffffffff 000001f4 00000001 00100000 01 {v5}")),
+                (
+                    7,
+                    &format!("ffffffff 000001f4 00000001 {session} {v5} {forgotten}"),
+                ),
+                (
+                    9,
+                    &format!("ffffffff 000001f4 00000001 {session} {v9} {forgotten}"),
+                ),
+                (
+                    11,
+                    &format!("ffffffff 000001f4 00000001 {session} {v9} {forgotten} 0001 72"),
+                ),
+                (
+                    12,
+                    &format!(
+                        "ffffffff 000001f4 00000001 {session}
+                            02 04 666f6f 02 {v12} 00
+                            02 04 626172 02 00000001 00
+                            02 72 00"
+                    ),
+                ),
+                (
+                    13,
+                    &format!(
+                        "ffffffff 000001f4 00000001 {session}
+                            02 {foo} 02 {v12} 00
+                            02 {bar} 02 00000001 00
+                            02 72 00"
+                    ),
+                ),
+                // The replica moves into tag 1, 13 bytes long.
+                (
+                    15,
+                    &format!(
+                        "000001f4 00000001 {session}
+                            02 {foo} 02 {v12} 00
+                            02 {bar} 02 00000001 00
+                            02 72
+                            01 01 0d 00000001 0000000000000005 00"
+                    ),
+                ),
+            ],
+        );
+
+        let response = FetchResponse {
+            throttle_time_ms: 100,
+            session_id: 7,
+            responses: vec![FetchableTopicResponse {
+                topic: "foo".to_string(),
+                topic_id: 2,
+                partitions: vec![PartitionData {
+                    partition_index: 0,
+                    high_watermark: 5,
+                    last_stable_offset: 5,
+                    log_start_offset: 0,
+                    current_leader: LeaderIdAndEpoch {
+                        leader_id: 1,
+                        leader_epoch: 0,
+                        ..Default::default()
+                    },
+                    aborted_transactions: Some(vec![AbortedTransaction {
+                        producer_id: 9,
+                        first_offset: 2,
+                        ..Default::default()
+                    }]),
+                    preferred_read_replica: -1,
+                    records: Some(Bytes::from_static(&[1, 2])),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            node_endpoints: vec![NodeEndpoint {
+                node_id: 1,
+                host: "h".to_string(),
+                port: 9092,
+                rack: None,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let responses = |partition: &str| format!("00000001 0003 666f6f 00000001 {partition}");
+        let aborted = "00000001 0000000000000009 0000000000000002";
+        let v0 = responses("00000000 0000 0000000000000005 00000002 0102");
+        let v4 = responses(&format!(
+            "00000000 0000 0000000000000005 0000000000000005 {aborted} 00000002 0102"
+        ));
+        let v5 = responses(&format!(
+            "00000000 0000 0000000000000005 0000000000000005 0000000000000000 {aborted}
+                00000002 0102"
+        ));
+        let v11 = responses(&format!(
+            "00000000 0000 0000000000000005 0000000000000005 0000000000000000 {aborted}
+                ffffffff 00000002 0102"
+        ));
+        // The current leader is tag 0, 9 bytes long.
+        let v12 = "00000000 0000 0000000000000005 0000000000000005 0000000000000000
+            02 0000000000000009 0000000000000002 00
+            ffffffff 03 0102
+            01 01 09 00000001 00000000 00";
+        golden(
+            &response,
+            0..=16,
+            &[
+                (0, &v0),
+                (1, &format!("00000064 {v0}")),
+                (4, &format!("00000064 {v4}")),
+                (5, &format!("00000064 {v5}")),
+                (7, &format!("00000064 0000 00000007 {v5}")),
+                (11, &format!("00000064 0000 00000007 {v11}")),
+                (
+                    12,
+                    &format!("00000064 0000 00000007 02 04 666f6f 02 {v12} 00 00"),
+                ),
+                (
+                    13,
+                    &format!("00000064 0000 00000007 02 {foo} 02 {v12} 00 00"),
+                ),
+                // The node endpoints are tag 0, 13 bytes long.
+                (
+                    16,
+                    &format!(
+                        "00000064 0000 00000007 02 {foo} 02 {v12} 00
+                            01 00 0d 02 00000001 02 68 00002384 00 00"
+                    ),
+                ),
+            ],
+        );
     }
 }
@@ -0,0 +1,458 @@
+use std::{borrow::Cow, collections::HashMap, sync::Mutex};
+
+use crate::{
+    config::Config,
+    error_code,
+    fetch::{FetchPartition, FetchRequest, FetchResponse, FetchTopic},
+};
+
+/// The epoch that opens a new session, dropping the one it names if any.
+pub const INITIAL_EPOCH: i32 = 0;
+/// The epoch that closes a session, or fetches without one.
+pub const FINAL_EPOCH: i32 = -1;
+
+/// Incremental fetch sessions (KIP-227). A session remembers the
+/// partitions a client fetches, so that later requests only need to list
+/// the ones that changed and responses only carry the partitions with
+/// something new to report.
+#[derive(Debug)]
+pub struct FetchSessionCache {
+    slots: usize,
+    state: Mutex<Sessions>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    sessions: HashMap<i32, FetchSession>,
+    last_id: i32,
+    /// Ticks on every use of a session, to find the least recently used.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct FetchSession {
+    /// The epoch the next request in the session has to carry.
+    epoch: i32,
+    /// The partitions in the session, grouped by topic.
+    partitions: Vec<CachedPartition>,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct CachedPartition {
+    topic: String,
+    topic_id: u128,
+    request: FetchPartition,
+    /// The high watermark, last stable offset and log start offset last
+    /// sent to the client, or `None` if the partition has not been sent.
+    sent: Option<(i64, i64, i64)>,
+}
+
+/// A request resolved against the session cache.
+#[derive(Debug)]
+pub struct FetchContext<'a> {
+    /// Every partition to read, which for an incremental fetch includes
+    /// the ones the request left out.
+    pub request: Cow<'a, FetchRequest>,
+    /// The session to answer with, or 0 for a sessionless fetch.
+    session_id: i32,
+    incremental: bool,
+}
+
+impl Default for FetchSessionCache {
+    fn default() -> Self {
+        Self::new(Config::default().fetch_session_slots())
+    }
+}
+
+impl FetchSessionCache {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Opens, continues or closes the session `request` names, failing
+    /// with the error code to answer it with when that session is unknown
+    /// or the epoch is out of order.
+    pub fn begin<'a>(&self, request: &'a FetchRequest) -> Result<FetchContext<'a>, i16> {
+        let mut state = self.state.lock().unwrap();
+        let full = |session_id| FetchContext {
+            request: Cow::Borrowed(request),
+            session_id,
+            incremental: false,
+        };
+        match (request.session_id, request.session_epoch) {
+            (session_id, FINAL_EPOCH) => {
+                state.sessions.remove(&session_id);
+                Ok(full(0))
+            }
+            (session_id, INITIAL_EPOCH) => {
+                state.sessions.remove(&session_id);
+                let partitions = request
+                    .topics
+                    .iter()
+                    .flat_map(|topic| {
+                        topic
+                            .partitions
+                            .iter()
+                            .map(|partition| CachedPartition::new(topic, partition))
+                    })
+                    .collect();
+                Ok(full(state.create(partitions, self.slots)))
+            }
+            (session_id, epoch) => {
+                state.clock += 1;
+                let clock = state.clock;
+                let session = state
+                    .sessions
+                    .get_mut(&session_id)
+                    .ok_or(error_code::FETCH_SESSION_ID_NOT_FOUND)?;
+                if epoch != session.epoch {
+                    return Err(error_code::INVALID_FETCH_SESSION_EPOCH);
+                }
+                session.epoch = match epoch {
+                    i32::MAX => 1,
+                    epoch => epoch + 1,
+                };
+                session.last_used = clock;
+                session.update(request);
+                Ok(FetchContext {
+                    request: Cow::Owned(FetchRequest {
+                        topics: session.topics(),
+                        forgotten_topics_data: Vec::new(),
+                        ..request.clone()
+                    }),
+                    session_id,
+                    incremental: true,
+                })
+            }
+        }
+    }
+
+    /// Stamps `response` with its session and remembers what the client
+    /// has now seen. Incremental responses drop the partitions that have
+    /// neither records, an error, nor new offsets to report.
+    pub fn complete(&self, context: &FetchContext, response: &mut FetchResponse) {
+        response.session_id = context.session_id;
+        let mut state = self.state.lock().unwrap();
+        // The session may have been evicted while the fetch was waiting;
+        // the client then still gets everything it asked for.
+        let Some(session) = state.sessions.get_mut(&context.session_id) else {
+            return;
+        };
+        for topic in &mut response.responses {
+            topic.partitions.retain(|data| {
+                let Some(cached) = session.find(&topic.topic, topic.topic_id, data.partition_index)
+                else {
+                    return true;
+                };
+                let sent = (
+                    data.high_watermark,
+                    data.last_stable_offset,
+                    data.log_start_offset,
+                );
+                let changed = cached.sent != Some(sent)
+                    || data.error_code != error_code::NONE
+                    || data
+                        .records
+                        .as_ref()
+                        .is_some_and(|records| !records.is_empty());
+                cached.sent = Some(sent);
+                changed || !context.incremental
+            });
+        }
+        if context.incremental {
+            response
+                .responses
+                .retain(|topic| !topic.partitions.is_empty());
+        }
+    }
+}
+
+impl Sessions {
+    /// Caches a session over `partitions`, evicting the least recently used
+    /// one if the cache is full. Returns its id, or 0 if there is no room.
+    fn create(&mut self, partitions: Vec<CachedPartition>, slots: usize) -> i32 {
+        if slots == 0 {
+            return 0;
+        }
+        if self.sessions.len() >= slots {
+            let evicted = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.last_used);
+            if let Some((&id, _)) = evicted {
+                self.sessions.remove(&id);
+            }
+        }
+        let mut id = self.last_id;
+        loop {
+            id = id.checked_add(1).unwrap_or(1);
+            if !self.sessions.contains_key(&id) {
+                break;
+            }
+        }
+        self.last_id = id;
+        self.clock += 1;
+        let session = FetchSession {
+            epoch: 1,
+            partitions,
+            last_used: self.clock,
+        };
+        self.sessions.insert(id, session);
+        id
+    }
+}
+
+impl FetchSession {
+    fn find(
+        &mut self,
+        topic: &str,
+        topic_id: u128,
+        partition: i32,
+    ) -> Option<&mut CachedPartition> {
+        self.partitions
+            .iter_mut()
+            .find(|cached| cached.is(topic, topic_id, partition))
+    }
+
+    /// Adds or updates the partitions `request` lists and drops the ones
+    /// it forgets.
+    fn update(&mut self, request: &FetchRequest) {
+        for topic in &request.topics {
+            for partition in &topic.partitions {
+                match self.find(&topic.topic, topic.topic_id, partition.partition) {
+                    Some(cached) => cached.request = partition.clone(),
+                    None => {
+                        // Keep the partitions of a topic together.
+                        let at = self
+                            .partitions
+                            .iter()
+                            .rposition(|cached| {
+                                cached.topic == topic.topic && cached.topic_id == topic.topic_id
+                            })
+                            .map_or(self.partitions.len(), |last| last + 1);
+                        self.partitions
+                            .insert(at, CachedPartition::new(topic, partition));
+                    }
+                }
+            }
+        }
+        for forgotten in &request.forgotten_topics_data {
+            self.partitions.retain(|cached| {
+                !forgotten
+                    .partitions
+                    .iter()
+                    .any(|&partition| cached.is(&forgotten.topic, forgotten.topic_id, partition))
+            });
+        }
+    }
+
+    /// The session's partitions as the topics of a fetch request.
+    fn topics(&self) -> Vec<FetchTopic> {
+        let mut topics: Vec<FetchTopic> = Vec::new();
+        for cached in &self.partitions {
+            match topics.last_mut() {
+                Some(topic) if topic.topic == cached.topic && topic.topic_id == cached.topic_id => {
+                    topic.partitions.push(cached.request.clone())
+                }
+                _ => topics.push(FetchTopic {
+                    topic: cached.topic.clone(),
+                    topic_id: cached.topic_id,
+                    partitions: vec![cached.request.clone()],
+                    ..Default::default()
+                }),
+            }
+        }
+        topics
+    }
+}
+
+impl CachedPartition {
+    fn new(topic: &FetchTopic, partition: &FetchPartition) -> Self {
+        Self {
+            topic: topic.topic.clone(),
+            topic_id: topic.topic_id,
+            request: partition.clone(),
+            sent: None,
+        }
+    }
+
+    fn is(&self, topic: &str, topic_id: u128, partition: i32) -> bool {
+        self.topic == topic && self.topic_id == topic_id && self.request.partition == partition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{fetch_request::ForgottenTopic, FetchableTopicResponse, PartitionData};
+
+    fn request(session_id: i32, session_epoch: i32, partitions: &[i32]) -> FetchRequest {
+        FetchRequest {
+            session_id,
+            session_epoch,
+            topics: vec![FetchTopic {
+                topic_id: 2,
+                partitions: partitions
+                    .iter()
+                    .map(|&partition| FetchPartition {
+                        partition,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Answers `context` as if each partition's high watermark were
+    /// `high_watermark(partition)`, then completes it.
+    fn respond(
+        cache: &FetchSessionCache,
+        context: &FetchContext,
+        high_watermark: impl Fn(i32) -> i64,
+    ) -> FetchResponse {
+        let mut response = FetchResponse {
+            responses: context
+                .request
+                .topics
+                .iter()
+                .map(|topic| FetchableTopicResponse {
+                    topic_id: topic.topic_id,
+                    partitions: topic
+                        .partitions
+                        .iter()
+                        .map(|partition| PartitionData {
+                            partition_index: partition.partition,
+                            high_watermark: high_watermark(partition.partition),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        cache.complete(context, &mut response);
+        response
+    }
+
+    fn partitions(response: &FetchResponse) -> Vec<i32> {
+        let partitions = response
+            .responses
+            .iter()
+            .flat_map(|topic| &topic.partitions);
+        partitions
+            .map(|partition| partition.partition_index)
+            .collect()
+    }
+
+    #[test]
+    fn returns_only_changed_partitions() {
+        let cache = FetchSessionCache::new(10);
+        let initial = request(0, INITIAL_EPOCH, &[0, 1]);
+        let context = cache.begin(&initial).unwrap();
+        let response = respond(&cache, &context, |_| 5);
+        assert_eq!(response.session_id, 1);
+        assert_eq!(partitions(&response), [0, 1]);
+
+        // Nothing listed: the session's partitions are read, and only the
+        // one whose high watermark moved is returned.
+        let next = request(1, 1, &[]);
+        let context = cache.begin(&next).unwrap();
+        assert_eq!(context.request.topics[0].partitions.len(), 2);
+        let response = respond(&cache, &context, |partition| 5 + partition as i64);
+        assert_eq!(response.session_id, 1);
+        assert_eq!(partitions(&response), [1]);
+
+        let next = request(1, 2, &[]);
+        let context = cache.begin(&next).unwrap();
+        let response = respond(&cache, &context, |partition| 5 + partition as i64);
+        assert!(response.responses.is_empty());
+    }
+
+    #[test]
+    fn adds_and_forgets_partitions() {
+        let cache = FetchSessionCache::new(10);
+        let next = request(0, INITIAL_EPOCH, &[0, 1]);
+        let context = cache.begin(&next).unwrap();
+        respond(&cache, &context, |_| 5);
+
+        let mut incremental = request(1, 1, &[2]);
+        incremental.forgotten_topics_data = vec![ForgottenTopic {
+            topic_id: 2,
+            partitions: vec![0],
+            ..Default::default()
+        }];
+        let context = cache.begin(&incremental).unwrap();
+        assert!(context.request.forgotten_topics_data.is_empty());
+        let read: Vec<i32> = context.request.topics[0]
+            .partitions
+            .iter()
+            .map(|partition| partition.partition)
+            .collect();
+        assert_eq!(read, [1, 2]);
+        // A partition new to the session is always sent once.
+        let response = respond(&cache, &context, |_| 5);
+        assert_eq!(partitions(&response), [2]);
+    }
+
+    #[test]
+    fn enforces_epoch_order() {
+        let cache = FetchSessionCache::new(10);
+        let next = request(0, INITIAL_EPOCH, &[0]);
+        let context = cache.begin(&next).unwrap();
+        respond(&cache, &context, |_| 5);
+
+        let error = |request| cache.begin(&request).unwrap_err();
+        assert_eq!(
+            error(request(1, 2, &[])),
+            error_code::INVALID_FETCH_SESSION_EPOCH
+        );
+        assert_eq!(
+            error(request(7, 1, &[])),
+            error_code::FETCH_SESSION_ID_NOT_FOUND
+        );
+        assert!(cache.begin(&request(1, 1, &[])).is_ok());
+        assert_eq!(
+            error(request(1, 1, &[])),
+            error_code::INVALID_FETCH_SESSION_EPOCH
+        );
+
+        // Closing the session fetches without one.
+        let next = request(1, FINAL_EPOCH, &[0]);
+        let context = cache.begin(&next).unwrap();
+        assert_eq!(respond(&cache, &context, |_| 5).session_id, 0);
+        assert_eq!(
+            error(request(1, 2, &[])),
+            error_code::FETCH_SESSION_ID_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used_session() {
+        let cache = FetchSessionCache::new(2);
+        let initial = request(0, INITIAL_EPOCH, &[0]);
+        for _ in 0..2 {
+            let context = cache.begin(&initial).unwrap();
+            respond(&cache, &context, |_| 5);
+        }
+        assert!(cache.begin(&request(1, 1, &[])).is_ok());
+        let context = cache.begin(&initial).unwrap();
+        assert_eq!(respond(&cache, &context, |_| 5).session_id, 3);
+        assert!(cache.begin(&request(1, 2, &[])).is_ok());
+        assert_eq!(
+            cache.begin(&request(2, 1, &[])).unwrap_err(),
+            error_code::FETCH_SESSION_ID_NOT_FOUND
+        );
+
+        // Without slots every fetch is sessionless.
+        let cache = FetchSessionCache::new(0);
+        let context = cache.begin(&initial).unwrap();
+        assert_eq!(respond(&cache, &context, |_| 5).session_id, 0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::messages::{
+    api_versions_request, create_partitions_request, create_topics_request,
+    delete_topics_request, describe_topic_partitions_request, fetch_request, list_offsets_request,
+    metadata_request, produce_request,
+};
+
+/// An API this broker implements and the range of versions it speaks.
+/// Both the ApiVersions response and request dispatch are derived from
+/// [`HANDLERS`], so what is advertised is exactly what is accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handler {
+    pub api_key: i16,
+    pub min_version: i16,
+    pub max_version: i16,
+}
+
+impl Handler {
+    pub fn supports(&self, version: i16) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
+    }
+}
+
+/// Every implemented API, ordered by key.
+pub const HANDLERS: &[Handler] = &[
+    Handler {
+        api_key: produce_request::API_KEY,
+        min_version: produce_request::MIN_VERSION,
+        max_version: produce_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: fetch_request::API_KEY,
+        // Fetch v0-v3 responses can only hold magic 0 and 1 message sets,
+        // and the log only has magic 2 batches to serve.
+        min_version: 4,
+        max_version: fetch_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: list_offsets_request::API_KEY,
+        min_version: list_offsets_request::MIN_VERSION,
+        max_version: list_offsets_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: metadata_request::API_KEY,
+        min_version: metadata_request::MIN_VERSION,
+        max_version: metadata_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: api_versions_request::API_KEY,
+        min_version: api_versions_request::MIN_VERSION,
+        max_version: api_versions_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: create_topics_request::API_KEY,
+        min_version: create_topics_request::MIN_VERSION,
+        max_version: create_topics_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: delete_topics_request::API_KEY,
+        min_version: delete_topics_request::MIN_VERSION,
+        max_version: delete_topics_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: create_partitions_request::API_KEY,
+        min_version: create_partitions_request::MIN_VERSION,
+        max_version: create_partitions_request::MAX_VERSION,
+    },
+    Handler {
+        api_key: describe_topic_partitions_request::API_KEY,
+        min_version: describe_topic_partitions_request::MIN_VERSION,
+        max_version: describe_topic_partitions_request::MAX_VERSION,
+    },
+];
+
+pub fn find(api_key: i16) -> Option<&'static Handler> {
+    HANDLERS.iter().find(|handler| handler.api_key == api_key)
+}
+
+/// Whether `version` of `api_key` is implemented.
+pub fn supports(api_key: i16, version: i16) -> bool {
+    find(api_key).is_some_and(|handler| handler.supports(version))
+}
+
+/// Whether `item` is in `items` more than once. Requests that repeat an
+/// entry are answered with INVALID_REQUEST for it, as Kafka cannot tell
+/// which of the entries was meant.
+pub fn repeated<T: PartialEq>(items: impl IntoIterator<Item = T>, item: T) -> bool {
+    items.into_iter().filter(|other| *other == item).count() > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handlers_are_sorted_and_unique() {
+        assert!(HANDLERS.windows(2).all(|pair| pair[0].api_key < pair[1].api_key));
+    }
+
+    #[test]
+    fn checks_version_ranges() {
+        assert!(!supports(0, 2));
+        assert!(supports(0, 3));
+        assert!(supports(0, 11));
+        assert!(!supports(1, 3));
+        assert!(supports(1, 4));
+        assert!(supports(1, 16));
+        assert!(!supports(1, 17));
+        assert!(supports(18, 4));
+        assert!(!supports(18, 5));
+        assert!(!supports(18, -1));
+        assert!(supports(3, 0));
+        assert!(supports(3, 12));
+        assert!(!supports(3, 13));
+        assert!(!supports(4, 0));
+        assert!(supports(19, 7));
+        assert!(!supports(19, 8));
+        assert!(supports(20, 6));
+        assert!(!supports(20, 7));
+        assert!(supports(2, 9));
+        assert!(!supports(2, 10));
+        assert!(supports(37, 3));
+        assert!(!supports(37, 4));
+    }
+
+    #[test]
+    fn finds_repeated_entries() {
+        let names = ["foo", "bar", "foo"];
+        assert!(repeated(names, "foo"));
+        assert!(!repeated(names, "bar"));
+        assert!(!repeated(names, "baz"));
+    }
+}
@@ -0,0 +1,53 @@
+/// First flexible (KIP-482) version of each API this broker knows about.
+/// Flexible versions use compact strings/arrays and tagged fields, and are
+/// paired with request header v2 and response header v1.
+const FIRST_FLEXIBLE_VERSION: &[(i16, i16)] = &[
+    (0, 9),   // Produce
+    (1, 12),  // Fetch
+    (2, 6),   // ListOffsets
+    (3, 9),   // Metadata
+    (8, 8),   // OffsetCommit
+    (9, 6),   // OffsetFetch
+    (10, 3),  // FindCoordinator
+    (11, 6),  // JoinGroup
+    (12, 4),  // Heartbeat
+    (13, 4),  // LeaveGroup
+    (14, 4),  // SyncGroup
+    (15, 5),  // DescribeGroups
+    (16, 3),  // ListGroups
+    (18, 3),  // ApiVersions
+    (19, 5),  // CreateTopics
+    (20, 4),  // DeleteTopics
+    (22, 2),  // InitProducerId
+    (37, 2),  // CreatePartitions
+    (75, 0),  // DescribeTopicPartitions
+];
+
+pub fn is_flexible(api_key: i16, api_version: i16) -> bool {
+    FIRST_FLEXIBLE_VERSION
+        .iter()
+        .find(|(key, _)| *key == api_key)
+        .is_some_and(|(_, first)| api_version >= *first)
+}
+
+/// Request header v0 has no client id, v1 adds a nullable client id and
+/// v2 adds a tag buffer.
+pub fn request_header_version(api_key: i16, api_version: i16) -> i16 {
+    match (api_key, api_version) {
+        // ControlledShutdown v0 predates the client id.
+        (7, 0) => 0,
+        _ if is_flexible(api_key, api_version) => 2,
+        _ => 1,
+    }
+}
+
+/// Response header v0 is just the correlation id, v1 adds a tag buffer.
+pub fn response_header_version(api_key: i16, api_version: i16) -> i16 {
+    match api_key {
+        // ApiVersions always answers with header v0 so that clients can
+        // parse the response before they know which versions are supported.
+        18 => 0,
+        _ if is_flexible(api_key, api_version) => 1,
+        _ => 0,
+    }
+}
@@ -0,0 +1,470 @@
+pub use crate::messages::list_offsets_request::{
+    self, ListOffsetsPartition, ListOffsetsRequest, ListOffsetsTopic,
+};
+pub use crate::messages::list_offsets_response::{
+    self, ListOffsetsPartitionResponse, ListOffsetsResponse, ListOffsetsTopicResponse,
+};
+use crate::{
+    broker::Broker,
+    error_code, handler,
+    metadata::{self, MetadataImage},
+};
+
+/// Timestamps asking for a particular offset rather than a search by time.
+const LATEST_TIMESTAMP: i64 = -1;
+const EARLIEST_TIMESTAMP: i64 = -2;
+const MAX_TIMESTAMP: i64 = -3;
+const EARLIEST_LOCAL_TIMESTAMP: i64 = -4;
+const LATEST_TIERED_TIMESTAMP: i64 = -5;
+
+/// The `isolation_level` of consumers that only see committed records.
+const READ_COMMITTED: i8 = 1;
+
+impl ListOffsetsResponse {
+    /// Finds the offset each requested partition has for its timestamp.
+    /// In v0 the answer is a list of offsets, which here never holds
+    /// more than one.
+    pub fn new(request: &ListOffsetsRequest, version: i16, broker: &Broker) -> Self {
+        // Everything is looked up before any log is read, so that the image
+        // is not locked for the reads.
+        let leader_epochs: Vec<Vec<Result<i32, i16>>> = {
+            let image = broker.image.read().unwrap();
+            request
+                .topics
+                .iter()
+                .map(|topic| {
+                    topic
+                        .partitions
+                        .iter()
+                        .map(|partition| resolve(&image, &topic.name, partition.partition_index))
+                        .collect()
+                })
+                .collect()
+        };
+        let topics = request
+            .topics
+            .iter()
+            .zip(leader_epochs)
+            .map(|(topic, leader_epochs)| {
+                let partitions = topic
+                    .partitions
+                    .iter()
+                    .zip(leader_epochs)
+                    .map(|(partition, leader_epoch)| {
+                        let index = partition.partition_index;
+                        let requested = request.topics.iter().flat_map(|other| {
+                            other
+                                .partitions
+                                .iter()
+                                .map(|entry| (&other.name, entry.partition_index))
+                        });
+                        let repeated = handler::repeated(requested, (&topic.name, index));
+                        match (repeated, leader_epoch) {
+                            (false, Ok(leader_epoch)) => ListOffsetsPartitionResponse::list(
+                                broker,
+                                &topic.name,
+                                leader_epoch,
+                                partition,
+                                request.isolation_level,
+                                version,
+                            ),
+                            (false, Err(error_code)) => {
+                                ListOffsetsPartitionResponse::error(index, error_code)
+                            }
+                            (true, _) => ListOffsetsPartitionResponse::error(
+                                index,
+                                error_code::INVALID_REQUEST,
+                            ),
+                        }
+                    })
+                    .collect();
+                ListOffsetsTopicResponse {
+                    name: topic.name.clone(),
+                    partitions,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Self {
+            topics,
+            ..Default::default()
+        }
+    }
+}
+
+/// The leader epoch of partition `partition` of `topic`.
+fn resolve(image: &MetadataImage, topic: &str, partition: i32) -> Result<i32, i16> {
+    image
+        .topic(topic)
+        .and_then(|topic| topic.partitions.get(&partition))
+        .map(|state| state.leader_epoch)
+        .ok_or(error_code::UNKNOWN_TOPIC_OR_PARTITION)
+}
+
+impl ListOffsetsPartitionResponse {
+    fn error(partition_index: i32, error_code: i16) -> Self {
+        Self {
+            partition_index,
+            error_code,
+            timestamp: -1,
+            offset: -1,
+            leader_epoch: -1,
+            ..Default::default()
+        }
+    }
+
+    /// Looks up `request.timestamp` in the log of partition
+    /// `request.partition_index` of `topic`, whose leader is at
+    /// `leader_epoch`. Offsets past what the isolation level lets a
+    /// consumer read are not found, and a partition with nothing to report
+    /// answers offset -1.
+    fn list(
+        broker: &Broker,
+        topic: &str,
+        leader_epoch: i32,
+        request: &ListOffsetsPartition,
+        isolation_level: i8,
+        version: i16,
+    ) -> Self {
+        let index = request.partition_index;
+        let epoch = metadata::check_leader_epoch(request.current_leader_epoch, leader_epoch);
+        if let Err(error_code) = epoch {
+            return Self::error(index, error_code);
+        }
+
+        let found = broker.logs.get(topic, index).and_then(|log| {
+            let log = log.lock().unwrap();
+            let last_fetchable = match isolation_level {
+                READ_COMMITTED => log.last_stable_offset(),
+                _ => log.log_end_offset(),
+            };
+            let found = match request.timestamp {
+                LATEST_TIMESTAMP => return Ok(Some((-1, last_fetchable))),
+                // Nothing is tiered, so the whole log is local.
+                EARLIEST_TIMESTAMP | EARLIEST_LOCAL_TIMESTAMP => {
+                    return Ok(Some((-1, log.log_start_offset())))
+                }
+                LATEST_TIERED_TIMESTAMP => None,
+                MAX_TIMESTAMP => log.max_timestamp()?,
+                timestamp => log.offset_for_timestamp(timestamp)?,
+            };
+            Ok(found.filter(|&(_, offset)| offset < last_fetchable))
+        });
+        let found = match found {
+            Ok(found) => found,
+            Err(err) => {
+                println!("listing offsets of {}-{}: {}", topic, index, err);
+                return Self::error(index, error_code::KAFKA_STORAGE_ERROR);
+            }
+        };
+        let Some((timestamp, offset)) = found else {
+            return Self::error(index, error_code::NONE);
+        };
+        let old_style_offsets = match version == 0 && request.max_num_offsets > 0 {
+            true => vec![offset],
+            false => Vec::new(),
+        };
+        Self {
+            partition_index: index,
+            error_code: error_code::NONE,
+            old_style_offsets,
+            timestamp,
+            offset,
+            leader_epoch,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        record::{Record, RecordBatch},
+        testing::{broker, captured, decode, encode, golden, hex, serialize_batches},
+    };
+
+    /// A broker with topic "foo" of one partition holding offsets 0-5,
+    /// stamped 100, 110, 200, 210, 300 and 310.
+    fn with_records(name: &str) -> Broker {
+        let broker = broker(name, "", &[("foo", 1)]);
+        let batches: Vec<RecordBatch> = (1..=3)
+            .map(|i| RecordBatch {
+                last_offset_delta: 1,
+                base_timestamp: 100 * i,
+                max_timestamp: 100 * i + 10,
+                records: (0..2)
+                    .map(|offset_delta| Record {
+                        offset_delta,
+                        timestamp_delta: 10 * offset_delta as i64,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        let log = broker.logs.get("foo", 0).unwrap();
+        log.lock().unwrap().append(&mut serialize_batches(&batches)).unwrap();
+        broker
+    }
+
+    fn request(partitions: &[(i32, i64)]) -> ListOffsetsRequest {
+        ListOffsetsRequest {
+            replica_id: -1,
+            topics: vec![ListOffsetsTopic {
+                name: "foo".to_string(),
+                partitions: partitions
+                    .iter()
+                    .map(|&(partition_index, timestamp)| ListOffsetsPartition {
+                        partition_index,
+                        timestamp,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// The error code, timestamp and offset found for each partition.
+    fn list(broker: &Broker, request: &ListOffsetsRequest, version: i16) -> Vec<(i16, i64, i64)> {
+        ListOffsetsResponse::new(request, version, broker).topics[0]
+            .partitions
+            .iter()
+            .map(|partition| (partition.error_code, partition.timestamp, partition.offset))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_request_v9() {
+        let bytes = "
+            ffffffff 01
+            02 04 666f6f
+                02 00000000 00000000 fffffffffffffffe 00
+                00
+            00";
+        let request: ListOffsetsRequest = decode(&hex(bytes), 9);
+        assert_eq!(request.isolation_level, READ_COMMITTED);
+        let partition = &request.topics[0].partitions[0];
+        assert_eq!(partition.current_leader_epoch, 0);
+        assert_eq!(partition.timestamp, EARLIEST_TIMESTAMP);
+        assert_eq!(encode(&request, 9), hex(bytes));
+
+        let v0 = "ffffffff 00000001 0003 666f6f 00000001 00000000 ffffffffffffffff 00000001";
+        let request: ListOffsetsRequest = decode(&hex(v0), 0);
+        assert_eq!(request.topics[0].partitions[0].max_num_offsets, 1);
+        assert_eq!(encode(&request, 0), hex(v0));
+    }
+
+    #[test]
+    fn lists_special_offsets() {
+        let broker = with_records("list-offsets-special");
+        let timestamps = [
+            LATEST_TIMESTAMP,
+            EARLIEST_TIMESTAMP,
+            MAX_TIMESTAMP,
+            EARLIEST_LOCAL_TIMESTAMP,
+            LATEST_TIERED_TIMESTAMP,
+        ];
+        let partitions: Vec<(i32, i64)> =
+            timestamps.iter().map(|&timestamp| (0, timestamp)).collect();
+        // Each has to go in a request of its own, as partitions may not
+        // repeat.
+        let listed: Vec<(i16, i64, i64)> = partitions
+            .iter()
+            .flat_map(|&partition| list(&broker, &request(&[partition]), 9))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (error_code::NONE, -1, 6),
+                (error_code::NONE, -1, 0),
+                (error_code::NONE, 310, 5),
+                (error_code::NONE, -1, 0),
+                (error_code::NONE, -1, -1),
+            ]
+        );
+        // read_committed sees up to the last stable offset, which with no
+        // transactions is the high watermark.
+        let mut committed = request(&[(0, LATEST_TIMESTAMP)]);
+        committed.isolation_level = READ_COMMITTED;
+        assert_eq!(list(&broker, &committed, 9), [(error_code::NONE, -1, 6)]);
+    }
+
+    #[test]
+    fn looks_up_timestamps() {
+        let broker = with_records("list-offsets-timestamps");
+        let listed: Vec<(i16, i64, i64)> = [0, 105, 200, 211, 310, 311]
+            .into_iter()
+            .flat_map(|timestamp| list(&broker, &request(&[(0, timestamp)]), 9))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (error_code::NONE, 100, 0),
+                (error_code::NONE, 110, 1),
+                (error_code::NONE, 200, 2),
+                (error_code::NONE, 300, 4),
+                (error_code::NONE, 310, 5),
+                (error_code::NONE, -1, -1),
+            ]
+        );
+        let response = ListOffsetsResponse::new(&request(&[(0, 200)]), 0, &broker);
+        assert_eq!(response.topics[0].partitions[0].old_style_offsets, [2]);
+    }
+
+    #[test]
+    fn rejects_unknown_and_fenced_partitions() {
+        let broker = with_records("list-offsets-errors");
+        let mut request = request(&[(1, LATEST_TIMESTAMP), (0, LATEST_TIMESTAMP)]);
+        request.topics[0].partitions[1].current_leader_epoch = 1;
+        assert_eq!(
+            list(&broker, &request, 9),
+            [
+                (error_code::UNKNOWN_TOPIC_OR_PARTITION, -1, -1),
+                (error_code::UNKNOWN_LEADER_EPOCH, -1, -1),
+            ]
+        );
+        request.topics[0].name = "bar".to_string();
+        assert_eq!(
+            list(&broker, &request, 9)[0].0,
+            error_code::UNKNOWN_TOPIC_OR_PARTITION
+        );
+        let repeated = self::request(&[(0, LATEST_TIMESTAMP), (0, EARLIEST_TIMESTAMP)]);
+        assert_eq!(
+            list(&broker, &repeated, 9),
+            [(error_code::INVALID_REQUEST, -1, -1); 2]
+        );
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let response = ListOffsetsResponse {
+            topics: vec![ListOffsetsTopicResponse {
+                name: "foo".to_string(),
+                partitions: vec![ListOffsetsPartitionResponse {
+                    old_style_offsets: vec![6],
+                    timestamp: -1,
+                    offset: 6,
+                    leader_epoch: 0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let v0 = "00000001 0003 666f6f 00000001 00000000 0000 00000001 0000000000000006";
+        assert_eq!(encode(&response, 0), hex(v0));
+        let v9 = "
+            00000000
+            02 04 666f6f
+                02 00000000 0000 ffffffffffffffff 0000000000000006 00000000 00
+                00
+            00";
+        assert_eq!(encode(&response, 9), hex(v9));
+    }
+
+    /// Body of the v7 request librdkafka 2.12.1 sends for the earliest
+    /// offset of partition 0 of "foo" when a consumer starts.
+    const LIBRDKAFKA_REQUEST_V7: &str = "
+        ffffffff 01
+        02 04 666f6f
+            02 00000000 00000000 fffffffffffffffe 00
+            00
+        00";
+
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: ListOffsetsRequest = captured(LIBRDKAFKA_REQUEST_V7, 7);
+        assert_eq!(request.replica_id, -1);
+        assert_eq!(request.isolation_level, READ_COMMITTED);
+        assert_eq!(request.topics[0].name, "foo");
+        let partition = &request.topics[0].partitions[0];
+        assert_eq!(partition.current_leader_epoch, 0);
+        assert_eq!(partition.timestamp, EARLIEST_TIMESTAMP);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 1,
+            topics: vec![ListOffsetsTopic {
+                name: "foo".to_string(),
+                partitions: vec![ListOffsetsPartition {
+                    partition_index: 0,
+                    current_leader_epoch: 0,
+                    timestamp: EARLIEST_TIMESTAMP,
+                    max_num_offsets: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        golden(
+            &request,
+            0..=9,
+            &[
+                (
+                    0,
+                    "ffffffff 00000001 0003 666f6f 00000001 00000000 fffffffffffffffe 00000001",
+                ),
+                (
+                    1,
+                    "ffffffff 00000001 0003 666f6f 00000001 00000000 fffffffffffffffe",
+                ),
+                (
+                    2,
+                    "ffffffff 01 00000001 0003 666f6f 00000001 00000000 fffffffffffffffe",
+                ),
+                (
+                    4,
+                    "ffffffff 01 00000001 0003 666f6f 00000001 00000000 00000000 fffffffffffffffe",
+                ),
+                (
+                    6,
+                    "ffffffff 01 02 04 666f6f 02 00000000 00000000 fffffffffffffffe 00 00 00",
+                ),
+            ],
+        );
+
+        let response = ListOffsetsResponse {
+            throttle_time_ms: 100,
+            topics: vec![ListOffsetsTopicResponse {
+                name: "foo".to_string(),
+                partitions: vec![ListOffsetsPartitionResponse {
+                    partition_index: 0,
+                    old_style_offsets: vec![3],
+                    timestamp: -1,
+                    offset: 3,
+                    leader_epoch: 0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let v1 = "00000001 0003 666f6f 00000001 00000000 0000 ffffffffffffffff 0000000000000003";
+        golden(
+            &response,
+            0..=9,
+            &[
+                (
+                    0,
+                    "00000001 0003 666f6f 00000001 00000000 0000 00000001 0000000000000003",
+                ),
+                (1, v1),
+                (2, &format!("00000064 {v1}")),
+                (4, &format!("00000064 {v1} 00000000")),
+                (
+                    6,
+                    "00000064 02 04 666f6f
+                        02 00000000 0000 ffffffffffffffff 0000000000000003 00000000 00
+                        00 00",
+                ),
+            ],
+        );
+    }
+}
@@ -0,0 +1,1406 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    config::Config,
+    record::{
+        check_batch, RecordBatch, BATCH_HEADER_LEN, LAST_OFFSET_DELTA_OFFSET, LOG_OVERHEAD,
+        MAX_TIMESTAMP_OFFSET,
+    },
+    deserialize::Deserialize,
+};
+
+/// Size of an offset index entry: offset relative to the segment base (4)
+/// and position in the segment file (4).
+const OFFSET_ENTRY_LEN: usize = 8;
+
+/// Size of a time index entry: timestamp (8) and relative offset (4).
+const TIME_ENTRY_LEN: usize = 12;
+
+/// The file in the log directory recording, for every partition, the
+/// offset up to which its log is known to be flushed to disk.
+const RECOVERY_POINT_CHECKPOINT: &str = "recovery-point-offset-checkpoint";
+
+/// The partition directory of the cluster metadata log, which is read by
+/// [`MetadataImage`](crate::metadata::MetadataImage) and not managed here.
+const METADATA_TOPIC: &str = "__cluster_metadata";
+
+/// The suffix of partition directories waiting to be deleted.
+const DELETE_SUFFIX: &str = "-delete";
+
+/// How partition logs are split into segments and indexed.
+#[derive(Debug, Clone, Copy)]
+pub struct LogConfig {
+    pub segment_bytes: u64,
+    pub roll_ms: i64,
+    pub index_interval_bytes: u64,
+}
+
+impl LogConfig {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            segment_bytes: config.segment_bytes(),
+            roll_ms: config.roll_ms(),
+            index_interval_bytes: config.index_interval_bytes(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::new(&Config::default())
+    }
+}
+
+/// Open partition logs by topic name and partition index.
+type Logs = HashMap<(String, i32), Arc<Mutex<PartitionLog>>>;
+
+/// The partition logs under the log directory. Each is opened on first use
+/// and kept open, so that reads and appends go through the same indexes.
+#[derive(Debug, Default)]
+pub struct LogManager {
+    log_dir: PathBuf,
+    config: LogConfig,
+    logs: Mutex<Logs>,
+    /// How long a deleted partition's directory is kept before removal.
+    delete_delay: Duration,
+    /// Renamed directories of deleted partitions and when they are due to
+    /// be removed.
+    deletions: Mutex<Vec<(PathBuf, Instant)>>,
+}
+
+impl LogManager {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            log_dir: config.log_dir(),
+            config: LogConfig::new(config),
+            logs: Mutex::default(),
+            delete_delay: Duration::from_millis(config.file_delete_delay_ms()),
+            deletions: Mutex::default(),
+        }
+    }
+
+    /// The log of `partition` of `topic`. Appends must hold its lock for
+    /// the duration, or the offsets handed out would collide.
+    pub fn get(&self, topic: &str, partition: i32) -> io::Result<Arc<Mutex<PartitionLog>>> {
+        let mut logs = self.logs.lock().unwrap();
+        let key = (topic.to_string(), partition);
+        if let Some(log) = logs.get(&key) {
+            return Ok(Arc::clone(log));
+        }
+        // A log not found by recovery at startup has no recovery point, so
+        // all of it is checked.
+        let dir = PartitionLog::dir(&self.log_dir, topic, partition);
+        let log = Arc::new(Mutex::new(PartitionLog::open(&dir, self.config, 0)?));
+        logs.insert(key, Arc::clone(&log));
+        Ok(log)
+    }
+
+    /// Creates the directory of a new partition and opens its empty log.
+    pub fn create(&self, topic: &str, partition: i32) -> io::Result<()> {
+        fs::create_dir_all(PartitionLog::dir(&self.log_dir, topic, partition))?;
+        self.get(topic, partition).map(drop)
+    }
+
+    /// Closes the log of a partition of a deleted topic and renames its
+    /// directory to `<topic>-<partition>.<topic id>-delete`, where
+    /// [`Self::delete_expired`] removes it once the delete delay is up.
+    /// Readers still holding the log keep working on the renamed files.
+    pub fn delete(&self, topic: &str, partition: i32, topic_id: u128) -> io::Result<()> {
+        self.logs
+            .lock()
+            .unwrap()
+            .remove(&(topic.to_string(), partition));
+        let dir = PartitionLog::dir(&self.log_dir, topic, partition);
+        let renamed = self
+            .log_dir
+            .join(format!("{topic}-{partition}.{topic_id:032x}{DELETE_SUFFIX}"));
+        match fs::rename(&dir, &renamed) {
+            Ok(()) => {}
+            // Nothing was ever written to the partition.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        self.schedule_deletion(renamed);
+        Ok(())
+    }
+
+    fn schedule_deletion(&self, dir: PathBuf) {
+        let due = Instant::now() + self.delete_delay;
+        self.deletions.lock().unwrap().push((dir, due));
+    }
+
+    /// Removes the directories of deleted partitions whose delay is up.
+    pub fn delete_expired(&self) -> io::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<PathBuf> = {
+            let mut deletions = self.deletions.lock().unwrap();
+            let (expired, pending) = deletions.drain(..).partition(|(_, due)| *due <= now);
+            *deletions = pending;
+            expired.into_iter().map(|(dir, _)| dir).collect()
+        };
+        for dir in expired {
+            println!("deleting {}", dir.display());
+            match fs::remove_dir_all(&dir) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens every partition log in the log directory, recovering what was
+    /// written to each after its checkpointed recovery point, then
+    /// checkpoints the result. Directories of partitions deleted before a
+    /// restart are scheduled for removal again. Run once at startup, before
+    /// any requests.
+    pub fn recover(&self) -> io::Result<()> {
+        let path = self.log_dir.join(RECOVERY_POINT_CHECKPOINT);
+        let recovery_points = read_checkpoint(&path).unwrap_or_else(|err| {
+            println!(
+                "recovering all logs, {} is unreadable: {:#}",
+                path.display(),
+                err
+            );
+            HashMap::new()
+        });
+        let entries = match fs::read_dir(&self.log_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut logs = self.logs.lock().unwrap();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_str().is_some_and(|name| name.ends_with(DELETE_SUFFIX)) {
+                self.schedule_deletion(entry.path());
+                continue;
+            }
+            let Some((topic, partition)) = name.to_str().and_then(PartitionLog::parse_dir) else {
+                continue;
+            };
+            if topic == METADATA_TOPIC || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let key = (topic.to_string(), partition);
+            let recovery_point = recovery_points.get(&key).copied().unwrap_or(0);
+            let log = PartitionLog::open(&entry.path(), self.config, recovery_point)?;
+            logs.insert(key, Arc::new(Mutex::new(log)));
+        }
+        drop(logs);
+        self.checkpoint()
+    }
+
+    /// Flushes every open log and writes the offsets they are now flushed
+    /// up to into the recovery point checkpoint, so that a restart only has
+    /// to recover what is appended after this.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let logs: Vec<_> = self
+            .logs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, log)| (key.clone(), Arc::clone(log)))
+            .collect();
+        let mut recovery_points = Vec::with_capacity(logs.len());
+        for ((topic, partition), log) in logs {
+            let mut log = log.lock().unwrap();
+            log.flush()?;
+            recovery_points.push((topic, partition, log.recovery_point()));
+        }
+        recovery_points.sort();
+        fs::create_dir_all(&self.log_dir)?;
+        write_checkpoint(
+            &self.log_dir.join(RECOVERY_POINT_CHECKPOINT),
+            &recovery_points,
+        )
+    }
+}
+
+/// Reads an offset checkpoint file in Kafka's format: a version line, which
+/// is 0, a line with the number of entries, then one `topic partition
+/// offset` line per entry. A missing file has no entries.
+fn read_checkpoint(path: &Path) -> io::Result<HashMap<(String, i32), i64>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut lines = text.lines();
+    if lines.next() != Some("0") {
+        return Err(invalid("unsupported checkpoint version"));
+    }
+    let count: usize = lines
+        .next()
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| invalid("malformed entry count"))?;
+    let entries = lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [topic, partition, offset] => Some((
+                    (topic.to_string(), partition.parse().ok()?),
+                    offset.parse().ok()?,
+                )),
+                _ => None,
+            }
+        })
+        .collect::<Option<HashMap<_, _>>>()
+        .ok_or_else(|| invalid("malformed entry"))?;
+    match entries.len() == count {
+        true => Ok(entries),
+        false => Err(invalid("entry count mismatch")),
+    }
+}
+
+/// Writes an offset checkpoint file, going through a temporary file that is
+/// synced and renamed over the old one so that a crash never leaves it
+/// half-written.
+fn write_checkpoint(path: &Path, entries: &[(String, i32, i64)]) -> io::Result<()> {
+    let mut text = format!("0\n{}\n", entries.len());
+    for (topic, partition, offset) in entries {
+        text.push_str(&format!("{topic} {partition} {offset}\n"));
+    }
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// A partition's log: a run of segment files, each named after the first
+/// offset it holds and paired with a sparse `.index` (offset to file
+/// position) and `.timeindex` (timestamp to offset) in Kafka's format.
+/// Appends go to the last, active, segment.
+#[derive(Debug)]
+pub struct PartitionLog {
+    dir: PathBuf,
+    config: LogConfig,
+    segments: Vec<Segment>,
+    /// Everything before this offset is flushed to disk.
+    recovery_point: i64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    base_offset: i64,
+    log: File,
+    offset_index: File,
+    time_index: File,
+    /// Bytes of whole batches in the log file.
+    size: u64,
+    /// The offset after the last batch in the segment.
+    next_offset: i64,
+    /// Offset index entries: relative offset and file position.
+    offsets: Vec<(u32, u32)>,
+    /// Time index entries: timestamp and relative offset.
+    timestamps: Vec<(i64, u32)>,
+    /// The largest timestamp in the segment and the offset it is at.
+    max_timestamp: (i64, i64),
+    /// The max timestamp of the first batch, which segment age is measured
+    /// from when producers set timestamps.
+    first_timestamp: Option<i64>,
+    created: Instant,
+    bytes_since_index: u64,
+}
+
+/// What a batch header tells about where the batch is and what it holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BatchInfo {
+    last_offset: i64,
+    max_timestamp: i64,
+    size: u64,
+}
+
+impl BatchInfo {
+    /// Reads the info from the first [`BATCH_HEADER_LEN`] bytes of a batch.
+    fn parse(header: &[u8]) -> Self {
+        let base_offset = i64::from_be_bytes(header[..8].try_into().unwrap());
+        let batch_length = i32::from_be_bytes(header[8..LOG_OVERHEAD].try_into().unwrap());
+        let delta = &header[LAST_OFFSET_DELTA_OFFSET..LAST_OFFSET_DELTA_OFFSET + 4];
+        let last_offset_delta = i32::from_be_bytes(delta.try_into().unwrap());
+        let timestamp = &header[MAX_TIMESTAMP_OFFSET..MAX_TIMESTAMP_OFFSET + 8];
+        Self {
+            last_offset: base_offset + last_offset_delta as i64,
+            max_timestamp: i64::from_be_bytes(timestamp.try_into().unwrap()),
+            size: LOG_OVERHEAD as u64 + batch_length.max(0) as u64,
+        }
+    }
+}
+
+impl PartitionLog {
+    /// The directory holding the log of `partition` of `topic`.
+    pub fn dir(log_dir: &Path, topic: &str, partition: i32) -> PathBuf {
+        log_dir.join(format!("{topic}-{partition}"))
+    }
+
+    /// The topic and partition a log directory named by [`Self::dir`]
+    /// belongs to.
+    pub fn parse_dir(name: &str) -> Option<(&str, i32)> {
+        let (topic, partition) = name.rsplit_once('-')?;
+        let partition = partition.parse().ok().filter(|partition| *partition >= 0)?;
+        (!topic.is_empty()).then_some((topic, partition))
+    }
+
+    /// Opens the segments in `dir`. A partition nothing has been written to
+    /// yet has no directory, which is the same as an empty log.
+    ///
+    /// Segments that end at or before `recovery_point` were flushed whole
+    /// and only have their indexes loaded. The rest are recovered: every
+    /// batch is checked, the indexes rebuilt, and the log cut off at the
+    /// first batch that is torn or corrupt, dropping any later segments.
+    pub fn open(dir: &Path, config: LogConfig, recovery_point: i64) -> io::Result<Self> {
+        let mut base_offsets = Vec::new();
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let name = entry?.file_name();
+                    let base_offset = name
+                        .to_str()
+                        .and_then(|name| name.strip_suffix(".log"))
+                        .and_then(|base_offset| base_offset.parse::<i64>().ok());
+                    base_offsets.extend(base_offset);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        base_offsets.sort_unstable();
+        let mut segments = Vec::with_capacity(base_offsets.len());
+        for (i, &base_offset) in base_offsets.iter().enumerate() {
+            let recover = match base_offsets.get(i + 1) {
+                Some(&next) => next > recovery_point,
+                None => true,
+            };
+            let (segment, truncated) = Segment::open(dir, base_offset, &config, recover)?;
+            segments.push(segment);
+            if truncated {
+                for &base_offset in &base_offsets[i + 1..] {
+                    println!(
+                        "deleting segment {base_offset} of {} after a truncation",
+                        dir.display()
+                    );
+                    Segment::delete(dir, base_offset)?;
+                }
+                break;
+            }
+        }
+        let mut log = Self {
+            dir: dir.to_path_buf(),
+            config,
+            segments,
+            recovery_point: 0,
+        };
+        log.recovery_point = recovery_point.min(log.log_end_offset());
+        Ok(log)
+    }
+
+    /// The offset everything before which is known to be on disk.
+    pub fn recovery_point(&self) -> i64 {
+        self.recovery_point
+    }
+
+    /// The first offset still in the log.
+    pub fn log_start_offset(&self) -> i64 {
+        self.segments
+            .first()
+            .map_or(0, |segment| segment.base_offset)
+    }
+
+    /// The offset the next record will be written at. With a single
+    /// replica everything written is committed, so this is also the high
+    /// watermark.
+    pub fn log_end_offset(&self) -> i64 {
+        self.segments
+            .last()
+            .map_or(0, |segment| segment.next_offset)
+    }
+
+    /// The offset before which every transaction is settled, which bounds
+    /// what read_committed consumers see. This broker implements none of
+    /// the transaction APIs, so none can be open and it is the high
+    /// watermark.
+    pub fn last_stable_offset(&self) -> i64 {
+        self.log_end_offset()
+    }
+
+    /// The timestamp and offset of the first record stamped `timestamp` or
+    /// later, or `None` if every record is older.
+    pub fn offset_for_timestamp(&self, timestamp: i64) -> io::Result<Option<(i64, i64)>> {
+        for segment in &self.segments {
+            if let Some(found) = segment.offset_for_timestamp(timestamp)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The largest timestamp in the log and the offset of the first record
+    /// carrying it, or `None` if no record has a timestamp.
+    pub fn max_timestamp(&self) -> io::Result<Option<(i64, i64)>> {
+        let latest = self
+            .segments
+            .iter()
+            .filter(|segment| segment.max_timestamp.0 >= 0)
+            .reduce(|latest, segment| match segment.max_timestamp.0 > latest.max_timestamp.0 {
+                true => segment,
+                false => latest,
+            });
+        match latest {
+            Some(segment) => segment.offset_for_timestamp(segment.max_timestamp.0),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whole batches from the one holding `offset`, as many as fit
+    /// in `max_bytes`, or nothing if `offset` is at or past the end. With
+    /// `min_one` the first batch is returned even if it alone is too big,
+    /// so that a consumer can always make progress (KIP-74). A read never
+    /// spans segments.
+    pub fn read(&self, offset: i64, max_bytes: usize, min_one: bool) -> io::Result<Bytes> {
+        let first = self
+            .segments
+            .partition_point(|segment| segment.base_offset <= offset)
+            .saturating_sub(1);
+        for segment in self.segments.iter().skip(first) {
+            if let Some(data) = segment.read(offset, max_bytes, min_one)? {
+                return Ok(data);
+            }
+        }
+        Ok(Bytes::new())
+    }
+
+    /// Writes the batches in `data`, each of which has to be whole and
+    /// have passed [`check_batch`], to the end of the log. They are
+    /// numbered on from the log end offset by rewriting their base
+    /// offsets in place, which the CRC does not cover, and the log rolls
+    /// to a new segment first if the active one is full or too old.
+    /// Returns the offset given to the first batch.
+    pub fn append(&mut self, data: &mut [u8]) -> io::Result<i64> {
+        let base_offset = self.log_end_offset();
+        let mut infos = Vec::new();
+        let mut next_offset = base_offset;
+        let mut position = 0;
+        while position < data.len() {
+            let batch = &mut data[position..];
+            batch[..8].copy_from_slice(&next_offset.to_be_bytes());
+            let info = BatchInfo::parse(batch);
+            next_offset = info.last_offset + 1;
+            position += info.size as usize;
+            infos.push(info);
+        }
+        let largest_timestamp = infos
+            .iter()
+            .map(|info| info.max_timestamp)
+            .max()
+            .unwrap_or(-1);
+        let roll = match self.segments.last() {
+            Some(active) => active.should_roll(
+                &self.config,
+                data.len() as u64,
+                next_offset - 1,
+                largest_timestamp,
+            ),
+            None => true,
+        };
+        if roll {
+            // Nothing more is written to the old segment, so flushing it
+            // now keeps it out of recovery.
+            self.flush()?;
+            fs::create_dir_all(&self.dir)?;
+            self.segments.push(Segment::create(&self.dir, base_offset)?);
+        }
+        let active = self.segments.last_mut().unwrap();
+        active.append(data, &infos, self.config.index_interval_bytes)?;
+        Ok(base_offset)
+    }
+
+    /// Syncs the active segment to disk, moving the recovery point up to
+    /// the log end. Earlier segments were flushed when they were rolled.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(active) = self.segments.last() {
+            active.flush()?;
+        }
+        self.recovery_point = self.log_end_offset();
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn path(dir: &Path, base_offset: i64, extension: &str) -> PathBuf {
+        dir.join(format!("{base_offset:020}.{extension}"))
+    }
+
+    fn create(dir: &Path, base_offset: i64) -> io::Result<Self> {
+        let open = |extension| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(Self::path(dir, base_offset, extension))
+        };
+        Ok(Self {
+            base_offset,
+            log: open("log")?,
+            offset_index: open("index")?,
+            time_index: open("timeindex")?,
+            size: 0,
+            next_offset: base_offset,
+            offsets: Vec::new(),
+            timestamps: Vec::new(),
+            max_timestamp: (-1, base_offset),
+            first_timestamp: None,
+            created: Instant::now(),
+            bytes_since_index: 0,
+        })
+    }
+
+    /// Opens an existing segment. Unless it is to be recovered, its
+    /// indexes are loaded and only the batches after the last indexed one
+    /// scanned to find where it ends, dropping one cut short by an
+    /// interrupted write. An index that is missing or fails its sanity
+    /// check gets the segment recovered anyway. Returns the segment and
+    /// whether recovery cut anything off it.
+    fn open(
+        dir: &Path,
+        base_offset: i64,
+        config: &LogConfig,
+        recover: bool,
+    ) -> io::Result<(Self, bool)> {
+        let read = |extension| match fs::read(Self::path(dir, base_offset, extension)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+        let offset_index = read("index")?;
+        let time_index = read("timeindex")?;
+
+        let open = |extension| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(Self::path(dir, base_offset, extension))
+        };
+        let log = open("log")?;
+        let size = log.metadata()?.len();
+        let mut segment = Self {
+            base_offset,
+            log,
+            offset_index: open("index")?,
+            time_index: open("timeindex")?,
+            size,
+            next_offset: base_offset,
+            offsets: Vec::new(),
+            timestamps: Vec::new(),
+            max_timestamp: (-1, base_offset),
+            first_timestamp: None,
+            created: Instant::now(),
+            bytes_since_index: 0,
+        };
+        let loaded = match (offset_index, time_index) {
+            (Some(offsets), Some(timestamps)) if !recover => segment.load(&offsets, &timestamps)?,
+            _ => false,
+        };
+        if loaded {
+            return Ok((segment, false));
+        }
+        println!("recovering segment {base_offset} of {}", dir.display());
+        let truncated = segment.recover(config)?;
+        Ok((segment, truncated))
+    }
+
+    /// Loads the index entries in `offsets` and `timestamps`, then scans
+    /// the batches after the last indexed one. Returns `false`, leaving the
+    /// segment for recovery, if either index fails its sanity check.
+    fn load(&mut self, offsets: &[u8], timestamps: &[u8]) -> io::Result<bool> {
+        let Some(offsets) = parse_offset_index(offsets) else {
+            return Ok(false);
+        };
+        let Some(timestamps) = parse_time_index(timestamps) else {
+            return Ok(false);
+        };
+        // The last offset entry has to point at the start of the batch it
+        // names, and the time index at no later offset.
+        if let Some(&(relative, position)) = offsets.last() {
+            let batch = self.batch_at(position as u64)?;
+            let points_at_batch = match batch {
+                Some(batch) => batch.last_offset == self.base_offset + relative as i64,
+                None => false,
+            };
+            if !points_at_batch {
+                return Ok(false);
+            }
+        }
+        let last_relative = offsets.last().map_or(0, |&(relative, _)| relative);
+        if timestamps
+            .last()
+            .is_some_and(|&(_, relative)| relative > last_relative)
+        {
+            return Ok(false);
+        }
+        self.offsets = offsets;
+        self.timestamps = timestamps;
+        if let Some(&(timestamp, relative)) = self.timestamps.last() {
+            self.max_timestamp = (timestamp, self.base_offset + relative as i64);
+        }
+        self.first_timestamp = self.batch_at(0)?.map(|batch| batch.max_timestamp);
+
+        let mut position = self
+            .offsets
+            .last()
+            .map_or(0, |&(_, position)| position as u64);
+        let last_indexed = position;
+        while let Some(batch) = self.batch_at(position)? {
+            self.next_offset = batch.last_offset + 1;
+            if batch.max_timestamp > self.max_timestamp.0 {
+                self.max_timestamp = (batch.max_timestamp, batch.last_offset);
+            }
+            position += batch.size;
+        }
+        self.bytes_since_index = position - last_indexed;
+        if position < self.size {
+            self.log.set_len(position)?;
+        }
+        self.size = position;
+        Ok(true)
+    }
+
+    /// Rebuilds the segment's indexes from a scan of its batches, checking
+    /// each one's length, CRC and offsets, and truncates the segment at the
+    /// first that fails. Returns whether anything was cut off.
+    fn recover(&mut self, config: &LogConfig) -> io::Result<bool> {
+        self.offset_index.set_len(0)?;
+        self.time_index.set_len(0)?;
+        self.offsets.clear();
+        self.timestamps.clear();
+        let mut position = 0;
+        while let Some(batch) = self.batch_at(position)? {
+            if batch.last_offset < self.next_offset {
+                println!(
+                    "offsets go backwards at {position} in segment {}",
+                    self.base_offset
+                );
+                break;
+            }
+            if let Err(err) = check_batch(&self.read_at(position, batch.size)?) {
+                println!(
+                    "invalid batch at {position} in segment {}: {}",
+                    self.base_offset, err
+                );
+                break;
+            }
+            self.track(&batch, position, config.index_interval_bytes)?;
+            position += batch.size;
+        }
+        let truncated = position < self.size;
+        if truncated {
+            println!(
+                "truncating segment {} from {} to {position} bytes",
+                self.base_offset, self.size
+            );
+            self.log.set_len(position)?;
+        }
+        self.size = position;
+        Ok(truncated)
+    }
+
+    fn delete(dir: &Path, base_offset: i64) -> io::Result<()> {
+        for extension in ["log", "index", "timeindex"] {
+            match fs::remove_file(Self::path(dir, base_offset, extension)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the header of the batch at `position`, or `None` if there is
+    /// no whole batch there.
+    fn batch_at(&self, position: u64) -> io::Result<Option<BatchInfo>> {
+        if position + BATCH_HEADER_LEN as u64 > self.size {
+            return Ok(None);
+        }
+        let mut header = [0; BATCH_HEADER_LEN];
+        self.log.read_exact_at(&mut header, position)?;
+        let batch = BatchInfo::parse(&header);
+        if batch.size < BATCH_HEADER_LEN as u64 || position + batch.size > self.size {
+            return Ok(None);
+        }
+        Ok(Some(batch))
+    }
+
+    /// The position of the last indexed batch at or before `offset`, which
+    /// is where a scan for it starts.
+    fn lookup(&self, offset: i64) -> u64 {
+        let relative = offset - self.base_offset;
+        let index = self
+            .offsets
+            .partition_point(|&(entry, _)| entry as i64 <= relative);
+        match index {
+            0 => 0,
+            index => self.offsets[index - 1].1 as u64,
+        }
+    }
+
+    /// Looks up `timestamp` like [`PartitionLog::offset_for_timestamp`],
+    /// within this segment.
+    fn offset_for_timestamp(&self, timestamp: i64) -> io::Result<Option<(i64, i64)>> {
+        if self.max_timestamp.0 < timestamp {
+            return Ok(None);
+        }
+        // Records up to a time index entry are no newer than it, so the
+        // scan can start at the batch of the last entry older than
+        // `timestamp`.
+        let index = self
+            .timestamps
+            .partition_point(|&(entry, _)| entry < timestamp);
+        let start = match index {
+            0 => self.base_offset,
+            index => self.base_offset + self.timestamps[index - 1].1 as i64,
+        };
+        let mut position = self.lookup(start);
+        while let Some(batch) = self.batch_at(position)? {
+            if batch.max_timestamp < timestamp {
+                position += batch.size;
+                continue;
+            }
+            let data = self.read_at(position, batch.size)?;
+            let base_offset = i64::from_be_bytes(data[..8].try_into().unwrap());
+            let found = match RecordBatch::from_bytes(&mut data.as_ref()) {
+                Ok(batch) => batch
+                    .record_timestamps()
+                    .find(|&(record_timestamp, _)| record_timestamp >= timestamp),
+                // Compressed records cannot be told apart, so the batch is
+                // found as a whole.
+                Err(_) => None,
+            };
+            return Ok(Some(found.unwrap_or((batch.max_timestamp, base_offset))));
+        }
+        Ok(None)
+    }
+
+    /// Reads like [`PartitionLog::read`], or returns `None` if `offset` is
+    /// past the end of this segment.
+    fn read(&self, offset: i64, max_bytes: usize, min_one: bool) -> io::Result<Option<Bytes>> {
+        let mut position = self.lookup(offset);
+        let first = loop {
+            let Some(batch) = self.batch_at(position)? else {
+                return Ok(None);
+            };
+            if batch.last_offset >= offset {
+                break batch;
+            }
+            position += batch.size;
+        };
+
+        let len = (self.size - position).min(max_bytes as u64);
+        if len < first.size {
+            return Ok(Some(match min_one {
+                true => self.read_at(position, first.size)?,
+                false => Bytes::new(),
+            }));
+        }
+        let data = self.read_at(position, len)?;
+        // Keep only whole batches.
+        let mut end = 0;
+        while data.len() - end >= LOG_OVERHEAD {
+            let length = i32::from_be_bytes(data[end + 8..end + LOG_OVERHEAD].try_into().unwrap());
+            let batch_end = end + LOG_OVERHEAD + length.max(0) as usize;
+            if batch_end > data.len() {
+                break;
+            }
+            end = batch_end;
+        }
+        Ok(Some(data.slice(..end)))
+    }
+
+    fn read_at(&self, position: u64, len: u64) -> io::Result<Bytes> {
+        let mut data = vec![0; len as usize];
+        self.log.read_exact_at(&mut data, position)?;
+        Ok(data.into())
+    }
+
+    /// Whether `len` more bytes, up to `last_offset` and with timestamps up
+    /// to `largest_timestamp`, belong in a new segment. The indexes hold
+    /// positions and offsets relative to the base as `u32`s, so a segment
+    /// also rolls before either would overflow. An empty segment is never
+    /// rolled.
+    fn should_roll(
+        &self,
+        config: &LogConfig,
+        len: u64,
+        last_offset: i64,
+        largest_timestamp: i64,
+    ) -> bool {
+        let age = match self.first_timestamp {
+            Some(first) if first >= 0 => largest_timestamp - first,
+            _ => self.created.elapsed().as_millis() as i64,
+        };
+        let full = self.size + len > config.segment_bytes.min(u32::MAX as u64);
+        let out_of_offsets = last_offset - self.base_offset > u32::MAX as i64;
+        self.size > 0 && (full || out_of_offsets || age > config.roll_ms)
+    }
+
+    /// Writes the serialized `batches` in `data` at the end of the segment,
+    /// adding index entries every `index_interval_bytes`.
+    fn append(
+        &mut self,
+        data: &[u8],
+        batches: &[BatchInfo],
+        index_interval_bytes: u64,
+    ) -> io::Result<()> {
+        self.log.write_all_at(data, self.size)?;
+        let mut position = self.size;
+        for batch in batches {
+            self.track(batch, position, index_interval_bytes)?;
+            position += batch.size;
+        }
+        self.size = position;
+        Ok(())
+    }
+
+    /// Accounts for `batch`, which starts at `position`, indexing it if
+    /// more than `index_interval_bytes` went by since the last entry.
+    fn track(
+        &mut self,
+        batch: &BatchInfo,
+        position: u64,
+        index_interval_bytes: u64,
+    ) -> io::Result<()> {
+        if self.first_timestamp.is_none() {
+            self.first_timestamp = Some(batch.max_timestamp);
+        }
+        if batch.max_timestamp > self.max_timestamp.0 {
+            self.max_timestamp = (batch.max_timestamp, batch.last_offset);
+        }
+        if self.bytes_since_index > index_interval_bytes {
+            self.index(batch.last_offset, position)?;
+            self.bytes_since_index = 0;
+        }
+        self.bytes_since_index += batch.size;
+        self.next_offset = batch.last_offset + 1;
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.log.sync_data()?;
+        self.offset_index.sync_data()?;
+        self.time_index.sync_data()
+    }
+
+    /// Adds offset index entries for the batch ending at `last_offset`,
+    /// which starts at `position`, and for the largest timestamp so far if
+    /// it has grown.
+    fn index(&mut self, last_offset: i64, position: u64) -> io::Result<()> {
+        let relative = (last_offset - self.base_offset) as u32;
+        let mut entry = [0; OFFSET_ENTRY_LEN];
+        entry[..4].copy_from_slice(&relative.to_be_bytes());
+        entry[4..].copy_from_slice(&(position as u32).to_be_bytes());
+        let at = (self.offsets.len() * OFFSET_ENTRY_LEN) as u64;
+        self.offset_index.write_all_at(&entry, at)?;
+        self.offsets.push((relative, position as u32));
+
+        let (timestamp, offset) = self.max_timestamp;
+        let newer = match self.timestamps.last() {
+            Some(&(last, _)) => timestamp > last,
+            None => true,
+        };
+        if newer {
+            let relative = (offset - self.base_offset) as u32;
+            let mut entry = [0; TIME_ENTRY_LEN];
+            entry[..8].copy_from_slice(&timestamp.to_be_bytes());
+            entry[8..].copy_from_slice(&relative.to_be_bytes());
+            let at = (self.timestamps.len() * TIME_ENTRY_LEN) as u64;
+            self.time_index.write_all_at(&entry, at)?;
+            self.timestamps.push((timestamp, relative));
+        }
+        Ok(())
+    }
+}
+
+/// Parses offset index entries, which have to strictly increase in both
+/// offset and position.
+fn parse_offset_index(data: &[u8]) -> Option<Vec<(u32, u32)>> {
+    if data.len() % OFFSET_ENTRY_LEN != 0 {
+        return None;
+    }
+    let mut offsets: Vec<(u32, u32)> = Vec::with_capacity(data.len() / OFFSET_ENTRY_LEN);
+    for entry in data.chunks_exact(OFFSET_ENTRY_LEN) {
+        let relative = u32::from_be_bytes(entry[..4].try_into().unwrap());
+        let position = u32::from_be_bytes(entry[4..].try_into().unwrap());
+        if offsets
+            .last()
+            .is_some_and(|&(last, last_position)| relative <= last || position <= last_position)
+        {
+            return None;
+        }
+        offsets.push((relative, position));
+    }
+    Some(offsets)
+}
+
+/// Parses time index entries, which have to strictly increase in timestamp
+/// and not go back in offset.
+fn parse_time_index(data: &[u8]) -> Option<Vec<(i64, u32)>> {
+    if data.len() % TIME_ENTRY_LEN != 0 {
+        return None;
+    }
+    let mut timestamps: Vec<(i64, u32)> = Vec::with_capacity(data.len() / TIME_ENTRY_LEN);
+    for entry in data.chunks_exact(TIME_ENTRY_LEN) {
+        let timestamp = i64::from_be_bytes(entry[..8].try_into().unwrap());
+        let relative = u32::from_be_bytes(entry[8..].try_into().unwrap());
+        if timestamps
+            .last()
+            .is_some_and(|&(last, last_relative)| timestamp <= last || relative < last_relative)
+        {
+            return None;
+        }
+        timestamps.push((timestamp, relative));
+    }
+    Some(timestamps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        record::{read_batches, Record},
+        serialize::Serialize,
+        testing::{serialize_batches, temp_dir},
+    };
+
+    /// Batches of `counts` records each, with timestamps `10 * count`.
+    fn batches(counts: &[i32]) -> Vec<u8> {
+        let batches: Vec<RecordBatch> = counts
+            .iter()
+            .map(|&count| RecordBatch {
+                last_offset_delta: count - 1,
+                max_timestamp: 10 * count as i64,
+                records: (0..count)
+                    .map(|offset_delta| Record {
+                        offset_delta,
+                        value: Some(Bytes::from_static(b"value")),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        serialize_batches(&batches)
+    }
+
+    fn base_offsets(data: &[u8]) -> Vec<i64> {
+        read_batches(data)
+            .unwrap()
+            .iter()
+            .map(|batch| batch.base_offset)
+            .collect()
+    }
+
+    /// A log in a fresh directory holding batches at offsets 0-1, 2 and 3-5.
+    fn log(name: &str, config: LogConfig) -> PartitionLog {
+        let mut log = PartitionLog::open(&temp_dir(name), config, 0).unwrap();
+        assert_eq!(log.append(&mut batches(&[2, 1, 3])).unwrap(), 0);
+        log
+    }
+
+    fn segment_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reads_from_the_batch_holding_the_offset() {
+        let log = log("log-read", LogConfig::default());
+        assert_eq!(log.log_start_offset(), 0);
+        assert_eq!(log.log_end_offset(), 6);
+        let read = |offset| base_offsets(&log.read(offset, usize::MAX, false).unwrap());
+        assert_eq!(read(0), [0, 2, 3]);
+        assert_eq!(read(1), [0, 2, 3]);
+        assert_eq!(read(2), [2, 3]);
+        assert_eq!(read(5), [3]);
+        assert!(log.read(6, usize::MAX, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn stops_at_max_bytes() {
+        let log = log("log-max-bytes", LogConfig::default());
+        let read = |max_bytes, min_one| log.read(0, max_bytes, min_one).unwrap();
+        let first = read(usize::MAX, false).len() - log.read(2, usize::MAX, false).unwrap().len();
+        assert_eq!(base_offsets(&read(first, false)), [0]);
+        assert_eq!(base_offsets(&read(first + 1, false)), [0]);
+        assert!(read(first - 1, false).is_empty());
+        assert_eq!(base_offsets(&read(0, true)), [0]);
+    }
+
+    #[test]
+    fn appends_after_the_log_end() {
+        let dir = temp_dir("log-append");
+        let mut log = PartitionLog::open(&dir, LogConfig::default(), 0).unwrap();
+        assert_eq!(log.append(&mut batches(&[2, 1])).unwrap(), 0);
+        assert_eq!(log.append(&mut batches(&[2, 1, 3])).unwrap(), 3);
+        assert_eq!(
+            base_offsets(&log.read(0, usize::MAX, false).unwrap()),
+            [0, 2, 3, 5, 6]
+        );
+        assert_eq!(log.log_end_offset(), 9);
+        assert_eq!(
+            segment_files(&dir),
+            [
+                "00000000000000000000.index",
+                "00000000000000000000.log",
+                "00000000000000000000.timeindex"
+            ]
+        );
+    }
+
+    #[test]
+    fn rolls_segments_by_size_and_age() {
+        let size = batches(&[1]).len() as u64;
+        let config = LogConfig {
+            segment_bytes: 2 * size,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-roll");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        for _ in 0..5 {
+            log.append(&mut batches(&[1])).unwrap();
+        }
+        let logs: Vec<String> = segment_files(&dir)
+            .into_iter()
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        assert_eq!(
+            logs,
+            [
+                "00000000000000000000.log",
+                "00000000000000000002.log",
+                "00000000000000000004.log"
+            ]
+        );
+        // Reads stay within a segment.
+        assert_eq!(base_offsets(&log.read(1, usize::MAX, false).unwrap()), [1]);
+        assert_eq!(
+            base_offsets(&log.read(2, usize::MAX, false).unwrap()),
+            [2, 3]
+        );
+
+        // Batch timestamps 10 and 30 are 20ms apart.
+        let config = LogConfig {
+            roll_ms: 15,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-roll-age");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        log.append(&mut batches(&[1])).unwrap();
+        log.append(&mut batches(&[1])).unwrap();
+        log.append(&mut batches(&[3])).unwrap();
+        assert_eq!(log.segments.len(), 2);
+        assert_eq!(log.segments[1].base_offset, 2);
+
+        // Offsets relative to the base have to fit the index.
+        let huge = RecordBatch {
+            last_offset_delta: i32::MAX,
+            ..Default::default()
+        };
+        let dir = temp_dir("log-roll-offsets");
+        let mut log = PartitionLog::open(&dir, LogConfig::default(), 0).unwrap();
+        log.append(&mut serialize_batches(&[huge.clone(), huge]))
+            .unwrap();
+        assert_eq!(log.log_end_offset(), 1 << 32);
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.append(&mut batches(&[1])).unwrap(), 1 << 32);
+        assert_eq!(log.segments.len(), 2);
+    }
+
+    #[test]
+    fn writes_and_reloads_sparse_indexes() {
+        let size = batches(&[1]).len() as u64;
+        let config = LogConfig {
+            index_interval_bytes: size,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-index");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        for count in 1..=6 {
+            log.append(&mut batches(&[count])).unwrap();
+        }
+        // A batch is indexed once more than `size` bytes were written since
+        // the last entry, which from the third batch on is every time.
+        let segment = &log.segments[0];
+        let relative: Vec<u32> = segment.offsets.iter().map(|&(offset, _)| offset).collect();
+        assert_eq!(relative, [5, 9, 14, 20]);
+        assert_eq!(segment.timestamps, [(30, 5), (40, 9), (50, 14), (60, 20)]);
+        assert_eq!(
+            fs::read(dir.join("00000000000000000000.index"))
+                .unwrap()
+                .len(),
+            4 * 8
+        );
+        assert_eq!(
+            fs::read(dir.join("00000000000000000000.timeindex"))
+                .unwrap()
+                .len(),
+            4 * 12
+        );
+
+        let reopened = PartitionLog::open(&dir, config, 0).unwrap();
+        assert_eq!(reopened.segments[0].offsets, log.segments[0].offsets);
+        assert_eq!(reopened.segments[0].timestamps, log.segments[0].timestamps);
+        assert_eq!(reopened.log_end_offset(), 21);
+        assert_eq!(reopened.segments[0].max_timestamp, (60, 20));
+        for offset in [0, 5, 6, 13, 14, 20] {
+            let data = reopened.read(offset, usize::MAX, false).unwrap();
+            let batches = read_batches(&data).unwrap();
+            assert!(batches[0].base_offset <= offset && offset <= batches[0].last_offset());
+        }
+    }
+
+    #[test]
+    fn looks_up_offsets_by_timestamp() {
+        // Batch i holds offsets 2i and 2i + 1, stamped 100i and 100i + 10.
+        let batch = |i: i64| RecordBatch {
+            last_offset_delta: 1,
+            base_timestamp: 100 * i,
+            max_timestamp: 100 * i + 10,
+            records: (0..2)
+                .map(|offset_delta| Record {
+                    offset_delta,
+                    timestamp_delta: 10 * offset_delta as i64,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let size = batch(0).encoded_len() as u64;
+        let config = LogConfig {
+            segment_bytes: 3 * size,
+            index_interval_bytes: size,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-timestamps");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        assert_eq!(log.max_timestamp().unwrap(), None);
+        for i in 0..6 {
+            log.append(&mut serialize_batches(&[batch(i)])).unwrap();
+        }
+        assert_eq!(log.segments.len(), 2);
+        let reopened = PartitionLog::open(&dir, config, log.log_end_offset()).unwrap();
+        for log in [log, reopened] {
+            let lookups = [0, 10, 105, 300, 301, 510, 511].map(|timestamp| {
+                log.offset_for_timestamp(timestamp).unwrap()
+            });
+            assert_eq!(
+                lookups,
+                [
+                    Some((0, 0)),
+                    Some((10, 1)),
+                    Some((110, 3)),
+                    Some((300, 6)),
+                    Some((310, 7)),
+                    Some((510, 11)),
+                    None,
+                ]
+            );
+            assert_eq!(log.max_timestamp().unwrap(), Some((510, 11)));
+        }
+    }
+
+    #[test]
+    fn drops_a_torn_final_batch() {
+        let dir = temp_dir("log-torn");
+        drop(log("log-torn", LogConfig::default()));
+        let path = dir.join("00000000000000000000.log");
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+        let mut log = PartitionLog::open(&dir, LogConfig::default(), 0).unwrap();
+        assert_eq!(log.log_end_offset(), 3);
+        assert_eq!(log.append(&mut batches(&[1])).unwrap(), 3);
+        let reopened = PartitionLog::open(&dir, LogConfig::default(), 0).unwrap();
+        assert_eq!(
+            base_offsets(&reopened.read(0, usize::MAX, false).unwrap()),
+            [0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn recovers_only_past_the_recovery_point() {
+        let size = batches(&[1]).len() as u64;
+        let config = LogConfig {
+            segment_bytes: 2 * size,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-recover");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        for _ in 0..5 {
+            log.append(&mut batches(&[1])).unwrap();
+        }
+        log.flush().unwrap();
+        assert_eq!(log.recovery_point(), 5);
+        drop(log);
+        // Flip a bit in the value of the record at offset 1.
+        let path = dir.join("00000000000000000000.log");
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, &data).unwrap();
+
+        // The first two segments are before the recovery point, so only
+        // the last one is checked.
+        let log = PartitionLog::open(&dir, config, 5).unwrap();
+        assert_eq!(log.segments.len(), 3);
+        assert_eq!(log.log_end_offset(), 5);
+
+        let log = PartitionLog::open(&dir, config, 0).unwrap();
+        assert_eq!(log.log_end_offset(), 1);
+        assert_eq!(log.recovery_point(), 0);
+        assert_eq!(
+            segment_files(&dir),
+            [
+                "00000000000000000000.index",
+                "00000000000000000000.log",
+                "00000000000000000000.timeindex"
+            ]
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+    }
+
+    #[test]
+    fn rebuilds_missing_and_corrupt_indexes() {
+        let size = batches(&[1]).len() as u64;
+        let config = LogConfig {
+            segment_bytes: 4 * size,
+            index_interval_bytes: size - 1,
+            ..LogConfig::default()
+        };
+        let dir = temp_dir("log-rebuild");
+        let mut log = PartitionLog::open(&dir, config, 0).unwrap();
+        for _ in 0..8 {
+            log.append(&mut batches(&[1])).unwrap();
+        }
+        log.flush().unwrap();
+        let segment = &log.segments[0];
+        assert_eq!(log.segments.len(), 2);
+        let size = size as u32;
+        assert_eq!(segment.offsets, [(1, size), (2, 2 * size), (3, 3 * size)]);
+
+        // Intact indexes of a flushed segment are loaded as they are.
+        let reopened = PartitionLog::open(&dir, config, log.recovery_point()).unwrap();
+        assert_eq!(reopened.segments[0].offsets, segment.offsets);
+
+        let index = dir.join("00000000000000000000.index");
+        let time_index = dir.join("00000000000000000000.timeindex");
+        let written = (fs::read(&index).unwrap(), fs::read(&time_index).unwrap());
+        fs::remove_file(&index).unwrap();
+        let reopened = PartitionLog::open(&dir, config, log.recovery_point()).unwrap();
+        assert_eq!(reopened.segments[0].offsets, segment.offsets);
+        assert_eq!(reopened.segments[0].timestamps, segment.timestamps);
+        assert_eq!(
+            (fs::read(&index).unwrap(), fs::read(&time_index).unwrap()),
+            written
+        );
+
+        // An index pointing into the middle of a batch.
+        let mut corrupt = written.0.clone();
+        corrupt[OFFSET_ENTRY_LEN - 1] += 1;
+        fs::write(&index, corrupt).unwrap();
+        fs::write(&time_index, [0; 5]).unwrap();
+        let reopened = PartitionLog::open(&dir, config, log.recovery_point()).unwrap();
+        assert_eq!(reopened.segments[0].offsets, segment.offsets);
+        assert_eq!(
+            (fs::read(&index).unwrap(), fs::read(&time_index).unwrap()),
+            written
+        );
+        assert_eq!(reopened.log_end_offset(), 8);
+    }
+
+    #[test]
+    fn checkpoints_recovery_points() {
+        let dir = temp_dir("log-checkpoint");
+        let config = Config::parse(&format!("log.dirs={}", dir.display()));
+        let logs = LogManager::new(&config);
+        let foo = logs.get("foo", 0).unwrap();
+        foo.lock().unwrap().append(&mut batches(&[2, 1])).unwrap();
+        logs.get("bar", 1).unwrap();
+        logs.checkpoint().unwrap();
+        let path = dir.join(RECOVERY_POINT_CHECKPOINT);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "0\n2\nbar 1 0\nfoo 0 3\n"
+        );
+        foo.lock().unwrap().append(&mut batches(&[1])).unwrap();
+        drop(foo);
+
+        // Not partition logs.
+        fs::create_dir(dir.join("__cluster_metadata-0")).unwrap();
+        fs::create_dir(dir.join("foo-0.0123-delete")).unwrap();
+        let recovered = LogManager::new(&config);
+        recovered.recover().unwrap();
+        let mut names: Vec<_> = recovered.logs.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, [("foo".to_string(), 0)]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0\n1\nfoo 0 4\n");
+
+        fs::write(&path, "0\n2\nfoo 0 4\n").unwrap();
+        assert_eq!(
+            read_checkpoint(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let recovered = LogManager::new(&config);
+        recovered.recover().unwrap();
+        let foo = recovered.get("foo", 0).unwrap();
+        assert_eq!(foo.lock().unwrap().log_end_offset(), 4);
+    }
+
+    #[test]
+    fn deletes_logs_after_the_delay() {
+        let dir = temp_dir("log-delete");
+        let config = Config::parse(&format!(
+            "log.dirs={}\nlog.segment.delete.delay.ms=0",
+            dir.display()
+        ));
+        let logs = LogManager::new(&config);
+        logs.create("foo", 0).unwrap();
+        logs.delete("foo", 0, 0xab).unwrap();
+        // Deleting a partition that never had a directory is a no-op.
+        logs.delete("foo", 1, 0xab).unwrap();
+        assert!(logs.logs.lock().unwrap().is_empty());
+        let renamed = format!("foo-0.{:032x}-delete", 0xab);
+        assert_eq!(segment_files(&dir), [renamed.as_str()]);
+        logs.delete_expired().unwrap();
+        assert!(segment_files(&dir).is_empty());
+
+        // Deletions cut short by a restart are picked up again.
+        fs::create_dir(dir.join(&renamed)).unwrap();
+        let config = Config::parse(&format!("log.dirs={}", dir.display()));
+        let recovered = LogManager::new(&config);
+        recovered.recover().unwrap();
+        recovered.delete_expired().unwrap();
+        assert!(dir.join(&renamed).exists());
+        assert_eq!(recovered.deletions.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn missing_directory_is_an_empty_log() {
+        let log =
+            PartitionLog::open(Path::new("/nonexistent/topic-0"), LogConfig::default(), 0).unwrap();
+        assert_eq!(log.log_end_offset(), 0);
+        assert!(log.read(0, usize::MAX, true).unwrap().is_empty());
+    }
+}
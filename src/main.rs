@@ -1,107 +1,319 @@
 #![allow(unused_imports)]
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Error, Result};
-use api_version::{ApiKey, ApiVersion};
+use anyhow::{bail, Error, Result};
+use api_version::ApiVersionsResponse;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use create_partitions::CreatePartitionsResponse;
+use create_topics::CreateTopicsResponse;
+use delete_topics::DeleteTopicsResponse;
 use describe::DescribeTopicPartitionsResponse;
-use deserialize::Deserialize;
+use deserialize::{DecodeError, Deserialize};
 use fetch::FetchResponse;
-use pretty_hex::PrettyHex;
-use pretty_hex::pretty_hex;
+use list_offsets::ListOffsetsResponse;
+use produce::ProduceResponse;
+use topic_metadata::MetadataResponse;
+use broker::Broker;
+use config::Config;
 use request::{Request, RequestBody, RequestHeader};
 use response::{Response, ResponseBody, ResponseHeader};
+use serialize::Serialize;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    task::JoinHandle,
 };
 
 mod request;
 mod response;
 mod api_version;
 mod fetch;
+mod fetch_session;
 mod deserialize;
+mod serialize;
 mod describe;
+mod tagged_fields;
+mod header_version;
+mod messages;
+mod handler;
+mod metadata;
+mod record;
+mod config;
+mod log;
+mod broker;
+mod error_code;
+mod purgatory;
+mod produce;
+mod topic_metadata;
+mod create_topics;
+mod delete_topics;
+mod create_partitions;
+mod list_offsets;
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:9092").await.unwrap();
+    // The broker is started as `kafka-starter-rust <server.properties>`.
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path)).unwrap(),
+        None => Config::default(),
+    };
+    let listener = TcpListener::bind(config.listener()).await.unwrap();
+    let broker = Arc::new(Broker::start(config));
+    tokio::spawn(checkpoint_logs(Arc::clone(&broker)));
+    tokio::spawn(delete_logs(Arc::clone(&broker)));
 
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted.unwrap(),
+            _ = &mut shutdown => break,
+        };
+        let broker = Arc::clone(&broker);
         tokio::spawn(async move {
-            process(stream).await;
+            process(stream, broker).await;
         });
     }
+    // Checkpointing on the way out leaves nothing for the next start to
+    // recover.
+    println!("shutting down");
+    if let Err(err) = broker.logs.checkpoint() {
+        println!("failed to checkpoint logs: {:#}", err);
+    }
+}
+
+/// Resolves once the broker is told to stop, by Ctrl-C or SIGTERM.
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Flushes the logs and checkpoints their recovery points every
+/// `log.flush.offset.checkpoint.interval.ms`, which bounds how much has to
+/// be recovered after a crash.
+async fn checkpoint_logs(broker: Arc<Broker>) {
+    let period = Duration::from_millis(broker.config.checkpoint_interval_ms().max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = broker.logs.checkpoint() {
+            println!("failed to checkpoint logs: {:#}", err);
+        }
+    }
 }
 
-async fn process(mut stream: TcpStream) {
+/// Removes the logs of deleted topics once `log.segment.delete.delay.ms`
+/// has passed, giving readers of the renamed directories time to finish.
+async fn delete_logs(broker: Arc<Broker>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(err) = broker.logs.delete_expired() {
+            println!("failed to delete logs: {:#}", err);
+        }
+    }
+}
+
+async fn process(stream: TcpStream, broker: Arc<Broker>) {
     println!("accepted new connection");
+    let (mut reader, mut writer) = stream.into_split();
+    let max_bytes = broker.config.socket_request_max_bytes();
+    // Requests are handled in the order they arrive, except that a Fetch
+    // waiting for data is parked so that it does not hold up the ones
+    // behind it, e.g. the Produce it is waiting for. Responses still go out
+    // in request order, as clients expect.
+    let (responses, mut pending) = mpsc::channel::<Pending>(MAX_PENDING_RESPONSES);
+    let writing = tokio::spawn(async move {
+        while let Some(pending) = pending.recv().await {
+            let reply = match pending {
+                Pending::Ready(reply) => reply,
+                Pending::Parked(fetch) => fetch.await.unwrap_or(Reply::Close),
+            };
+            let response = match reply {
+                Reply::Send(response) => response,
+                Reply::Silent => continue,
+                Reply::Close => break,
+            };
+            let buffer = response_to_bytes(&response);
+            if let Err(err) = writer.write_all(&buffer).await {
+                println!("closing connection: {:#}", err);
+                break;
+            }
+        }
+    });
     loop {
-        let request = read_request(&mut stream).await;
-        println!("request: {:?}", &request);
-        let response = build_response(&request);
-        println!("response: {:?}", &response);
-        let buffer = response_to_bytes(&response);
-        println!("{:?}", buffer.hex_dump());
+        let request = tokio::select! {
+            request = read_request(&mut reader, max_bytes) => request,
+            // The writer has given up on the connection.
+            _ = responses.closed() => break,
+        };
+        let request = match request {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err) => {
+                println!("closing connection: {:#}", err);
+                break;
+            }
+        };
+        let pending = match request.body {
+            RequestBody::Fetch(_) => {
+                let broker = Arc::clone(&broker);
+                Pending::Parked(tokio::spawn(async move { reply(&request, &broker).await }))
+            }
+            _ => Pending::Ready(reply(&request, &broker).await),
+        };
+        // Waits while too many responses are queued, which stops reading
+        // from a client that does not read its responses.
+        if responses.send(pending).await.is_err() {
+            break;
+        }
+    }
+    // Let the responses already underway go out before closing.
+    drop(responses);
+    let _ = writing.await;
+}
+
+/// How many responses a connection may have queued before we stop reading
+/// its requests.
+const MAX_PENDING_RESPONSES: usize = 64;
+
+/// A response queued to be written back.
+enum Pending {
+    Ready(Reply),
+    /// A Fetch left to wait for data.
+    Parked(JoinHandle<Reply>),
+}
+
+/// What handling a request leaves to send back.
+enum Reply {
+    Send(Response),
+    /// The client asked not to be answered.
+    Silent,
+    /// The connection has to be dropped.
+    Close,
+}
 
-        stream.write(&buffer).await.unwrap();
+async fn reply(request: &Request, broker: &Broker) -> Reply {
+    let Some(response) = build_response(request, broker).await else {
+        // There is no response layout we could answer an unknown API with,
+        // so do what Kafka does and drop the connection.
+        println!(
+            "closing connection: unsupported api key {} (version {})",
+            request.header.request_api_key, request.header.request_api_version
+        );
+        return Reply::Close;
+    };
+    match request.expects_response() {
+        true => Reply::Send(response),
+        false => Reply::Silent,
     }
 }
 
 fn response_to_bytes(response: &Response) -> BytesMut {
-    let mut buffer = BytesMut::new();
-    buffer.put_u32(0);
-    let mut msg = buffer.split_off(4);
-    msg.extend_from_slice(&Into::<Vec<u8>>::into(response)[..]);
-    println!("length: {}", msg.len());
-    buffer.copy_from_slice(&(msg.len() as u32).to_be_bytes());
-    buffer.unsplit(msg);
+    let length = response.encoded_len();
+    let mut buffer = BytesMut::with_capacity(4 + length);
+    buffer.put_u32(length as u32);
+    response.serialize(&mut buffer);
+    debug_assert_eq!(buffer.len(), 4 + length);
     buffer
 }
 
-fn build_response(request: &Request) -> Response {
-
+async fn build_response(request: &Request, broker: &Broker) -> Option<Response> {
+    let mut api_version = request.header.request_api_version;
     let body = match request.body {
+        RequestBody::Produce(ref produce) => {
+            ResponseBody::Produce(ProduceResponse::new(produce, broker))
+        }
         RequestBody::Fetch(ref fetch) => {
-            let error_code = match request.header.request_api_version {
-                0..=16 => 0,
-                _ => 35,
-            };
-            ResponseBody::Fetch(FetchResponse::new(error_code, fetch))
-        },
+            ResponseBody::Fetch(
+                FetchResponse::handle(fetch, request.header.request_api_version, broker).await,
+            )
+        }
+        RequestBody::ListOffsets(ref list_offsets) => {
+            ResponseBody::ListOffsets(ListOffsetsResponse::new(
+                list_offsets,
+                request.header.request_api_version,
+                broker,
+            ))
+        }
+        RequestBody::Metadata(ref metadata) => ResponseBody::Metadata(MetadataResponse::new(
+            metadata,
+            request.header.request_api_version,
+            broker,
+        )),
         RequestBody::ApiVersion => {
-            let error_code = match request.header.request_api_version {
-                0..=4 => 0,
-                _ => 35,
+            let error_code = match handler::supports(
+                request.header.request_api_key,
+                request.header.request_api_version,
+            ) {
+                true => error_code::NONE,
+                false => error_code::UNSUPPORTED_VERSION,
             };
-            ResponseBody::ApiVersion(ApiVersion::new(error_code))
-        },
+            // Clients that sent a version we do not support can only be
+            // expected to parse a v0 response.
+            if error_code != 0 {
+                api_version = 0;
+            }
+            ResponseBody::ApiVersion(ApiVersionsResponse::new(
+                error_code,
+                &broker.image.read().unwrap(),
+            ))
+        }
+        RequestBody::CreateTopics(ref create_topics) => {
+            ResponseBody::CreateTopics(CreateTopicsResponse::new(
+                create_topics,
+                request.header.request_api_version,
+                broker,
+            ))
+        }
+        RequestBody::DeleteTopics(ref delete_topics) => {
+            ResponseBody::DeleteTopics(DeleteTopicsResponse::new(delete_topics, broker))
+        }
+        RequestBody::CreatePartitions(ref create_partitions) => ResponseBody::CreatePartitions(
+            CreatePartitionsResponse::new(create_partitions, broker),
+        ),
         RequestBody::Describe(ref describe) => {
-            let error_code = match request.header.request_api_version {
-                0 => 0,
-                _ => 3,
-            };
-            ResponseBody::Describe(DescribeTopicPartitionsResponse::new(3, describe))
+            ResponseBody::Describe(DescribeTopicPartitionsResponse::new(
+                describe,
+                &broker.image.read().unwrap(),
+            ))
         }
-        _ => unimplemented!()
+        RequestBody::Unknown => return None,
     };
-    let response = Response {
-        header: ResponseHeader {
-            correlation_id: request.header.correlation_id,
-        },
+    Some(Response {
+        header: ResponseHeader::new(&request.header),
+        api_version,
         body,
-    };
-    response
+    })
 }
 
-async fn read_request(stream: &mut TcpStream) -> Request {
+/// Reads one length-prefixed request frame of at most `max_bytes`,
+/// returning `None` once the client has closed the connection.
+async fn read_request(stream: &mut OwnedReadHalf, max_bytes: usize) -> Result<Option<Request>> {
     let mut buffer = [0; 4];
-    stream.read_exact(&mut buffer).await.unwrap();
-    let length = u32::from_be_bytes(buffer);
-    let mut buffer = vec![0; length as usize];
-    stream.read_exact(&mut buffer).await.unwrap();
-    println!("{:?}", buffer.hex_dump());
-
-    Request::from_bytes(&mut &buffer[..])
+    match stream.read_exact(&mut buffer).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let length = i32::from_be_bytes(buffer);
+    let length = usize::try_from(length).map_err(|_| DecodeError::InvalidLength(length as i64))?;
+    if length > max_bytes {
+        bail!("request of {length} bytes is larger than socket.request.max.bytes ({max_bytes})");
+    }
+    let mut buffer = vec![0; length];
+    stream.read_exact(&mut buffer).await?;
+    Ok(Some(Request::from_bytes(&mut &buffer[..])?))
 }
@@ -0,0 +1,17 @@
+//! Request and response types generated by `build.rs` from the Kafka JSON
+//! message and metadata record schemas under `resources/`.
+// Every schema gets the same constants and accessors whether or not the
+// broker uses them.
+#![allow(dead_code, unused_variables, unused_mut, clippy::all)]
+
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    deserialize::{BufExt, DecodeError, Deserialize, VersionedDeserialize},
+    serialize::*,
+    tagged_fields::TaggedFields,
+};
+#[cfg(test)]
+use crate::testing::{Random, Rng};
+
+include!(concat!(env!("OUT_DIR"), "/messages.rs"));
@@ -0,0 +1,499 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    config::Config,
+    deserialize::{BufExt, DecodeError, VersionedDeserialize},
+    error_code,
+    log::{LogConfig, PartitionLog},
+    messages::{
+        config_record, feature_level_record, partition_record, remove_topic_record, topic_record,
+        ConfigRecord, FeatureLevelRecord, PartitionRecord, RemoveTopicRecord, TopicRecord,
+    },
+    record::{read_batches, Record, RecordBatch},
+    serialize::{BufMutExt, Serialize, VersionedSerialize},
+};
+
+/// The `metadata.version` levels this broker can run at.
+pub const METADATA_VERSIONS: RangeInclusive<i16> = 1..=20;
+
+/// The `ResourceType` of topic configs in a `ConfigRecord`.
+pub const TOPIC_RESOURCE: i8 = 2;
+
+/// The frame version every metadata record value starts with.
+const FRAME_VERSION: u32 = 1;
+
+/// The longest topic name Kafka allows, which leaves room for the partition
+/// suffix in directory names.
+const MAX_TOPIC_NAME_LEN: usize = 249;
+
+/// Topic ids with a reserved meaning: zero is "no id" and one is the
+/// metadata topic's.
+const RESERVED_TOPIC_IDS: [u128; 2] = [0, 1];
+
+/// The cluster state replayed from the metadata log: which topics exist,
+/// their partitions, and the finalized feature levels.
+#[derive(Debug)]
+pub struct MetadataImage {
+    topics: BTreeMap<String, TopicImage>,
+    topic_names: HashMap<u128, String>,
+    features: BTreeMap<String, i16>,
+    /// The offset of the last feature level change, or -1 if there was
+    /// none.
+    features_epoch: i64,
+    /// The offset of the record being replayed, when it came from the log.
+    offset: i64,
+}
+
+impl Default for MetadataImage {
+    fn default() -> Self {
+        Self {
+            topics: BTreeMap::new(),
+            topic_names: HashMap::new(),
+            features: BTreeMap::new(),
+            features_epoch: -1,
+            offset: -1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicImage {
+    pub name: String,
+    pub topic_id: u128,
+    pub partitions: BTreeMap<i32, PartitionRecord>,
+    /// The configs set on the topic, which override the broker's.
+    pub configs: BTreeMap<String, String>,
+}
+
+impl MetadataImage {
+    /// Replays every `.log` segment in `dir`, oldest first.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut segments = fs::read_dir(dir)
+            .with_context(|| format!("reading {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        segments.retain(|path| path.extension().is_some_and(|ext| ext == "log"));
+        segments.sort();
+
+        let mut image = Self::default();
+        for segment in segments {
+            let data = fs::read(&segment).with_context(|| format!("reading {}", segment.display()))?;
+            image
+                .replay_segment(&data)
+                .with_context(|| format!("replaying {}", segment.display()))?;
+        }
+        Ok(image)
+    }
+
+    /// Applies every data record in a log segment. Control batches (leader
+    /// changes and the like) carry no metadata and are skipped.
+    pub fn replay_segment(&mut self, data: &[u8]) -> Result<(), DecodeError> {
+        for batch in read_batches(data)? {
+            if batch.is_control() {
+                continue;
+            }
+            for record in batch.records {
+                if let Some(value) = record.value {
+                    self.offset = batch.base_offset + record.offset_delta as i64;
+                    self.replay(&value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies one metadata record value: a frame version, the record type
+    /// and its version, all unsigned varints, followed by the record.
+    pub fn replay(&mut self, mut value: &[u8]) -> Result<(), DecodeError> {
+        let _frame_version = value.get_checked_unsigned_varint()?;
+        let record_type = value.get_checked_unsigned_varint()? as i16;
+        let version = value.get_checked_unsigned_varint()? as i16;
+        match record_type {
+            topic_record::API_KEY => self.add_topic(TopicRecord::from_bytes(&mut value, version)?),
+            partition_record::API_KEY => {
+                self.add_partition(PartitionRecord::from_bytes(&mut value, version)?)
+            }
+            remove_topic_record::API_KEY => {
+                self.remove_topic(RemoveTopicRecord::from_bytes(&mut value, version)?.topic_id)
+            }
+            config_record::API_KEY => {
+                self.set_config(ConfigRecord::from_bytes(&mut value, version)?)
+            }
+            feature_level_record::API_KEY => {
+                let record = FeatureLevelRecord::from_bytes(&mut value, version)?;
+                self.features.insert(record.name, record.feature_level);
+                self.features_epoch = self.offset;
+            }
+            // Brokers, producer ids and the like do not affect anything we
+            // serve yet.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn add_topic(&mut self, record: TopicRecord) {
+        self.topic_names.insert(record.topic_id, record.name.clone());
+        self.topics.insert(
+            record.name.clone(),
+            TopicImage {
+                name: record.name,
+                topic_id: record.topic_id,
+                partitions: BTreeMap::new(),
+                configs: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Sets, or with a null value removes, a config of an already known
+    /// topic. Configs of other resources are dropped.
+    pub fn set_config(&mut self, record: ConfigRecord) {
+        if record.resource_type != TOPIC_RESOURCE {
+            return;
+        }
+        let Some(topic) = self.topics.get_mut(&record.resource_name) else {
+            return;
+        };
+        match record.value {
+            Some(value) => topic.configs.insert(record.name, value),
+            None => topic.configs.remove(&record.name),
+        };
+    }
+
+    /// Records a partition of an already known topic; partitions of unknown
+    /// topics are dropped.
+    pub fn add_partition(&mut self, record: PartitionRecord) {
+        let Some(name) = self.topic_names.get(&record.topic_id) else {
+            return;
+        };
+        if let Some(topic) = self.topics.get_mut(name) {
+            topic.partitions.insert(record.partition_id, record);
+        }
+    }
+
+    /// Forgets a topic and its partitions.
+    pub fn remove_topic(&mut self, topic_id: u128) {
+        if let Some(name) = self.topic_names.remove(&topic_id) {
+            self.topics.remove(&name);
+        }
+    }
+
+    pub fn topic(&self, name: &str) -> Option<&TopicImage> {
+        self.topics.get(name)
+    }
+
+    pub fn topic_by_id(&self, topic_id: u128) -> Option<&TopicImage> {
+        self.topic_names.get(&topic_id).and_then(|name| self.topic(name))
+    }
+
+    /// All topics, ordered by name.
+    pub fn topics(&self) -> impl Iterator<Item = &TopicImage> {
+        self.topics.values()
+    }
+
+    #[cfg(test)]
+    pub fn feature_level(&self, name: &str) -> Option<i16> {
+        self.features.get(name).copied()
+    }
+
+    /// The finalized feature levels, ordered by name. A feature at level
+    /// zero is disabled and left out.
+    pub fn features(&self) -> impl Iterator<Item = (&str, i16)> {
+        self.features
+            .iter()
+            .filter(|&(_, &level)| level > 0)
+            .map(|(name, &level)| (name.as_str(), level))
+    }
+
+    /// The offset of the record that last changed a feature level, or -1.
+    pub fn features_epoch(&self) -> i64 {
+        self.features_epoch
+    }
+
+    /// A random (version 4) UUID that no topic has yet.
+    pub fn new_topic_id(&self) -> io::Result<u128> {
+        loop {
+            let mut bytes = [0; 16];
+            File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+            let random = u128::from_be_bytes(bytes);
+            let topic_id = random & !(0xf0 << 72 | 0xc0 << 56) | 0x40 << 72 | 0x80 << 56;
+            if !RESERVED_TOPIC_IDS.contains(&topic_id) && !self.topic_names.contains_key(&topic_id) {
+                return Ok(topic_id);
+            }
+        }
+    }
+}
+
+/// Whether `name` is a legal topic name: up to 249 ASCII letters, digits,
+/// `.`, `_` and `-`, and neither `.` nor `..`.
+pub fn valid_topic_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_TOPIC_NAME_LEN
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'_' | b'-'))
+}
+
+/// Checks the leader epoch a client knows a partition by against its
+/// current `leader_epoch`: an older one has been fenced, and a newer one
+/// is from metadata this broker has not caught up with. -1 means the
+/// client does not know the epoch and skips the check.
+pub fn check_leader_epoch(requested: i32, leader_epoch: i32) -> Result<(), i16> {
+    if requested == -1 {
+        return Ok(());
+    }
+    match requested.cmp(&leader_epoch) {
+        Ordering::Less => Err(error_code::FENCED_LEADER_EPOCH),
+        Ordering::Greater => Err(error_code::UNKNOWN_LEADER_EPOCH),
+        Ordering::Equal => Ok(()),
+    }
+}
+
+/// Frames `record` as a metadata record value, the inverse of
+/// [`MetadataImage::replay`].
+pub fn record_value<R: VersionedSerialize>(record_type: i16, version: i16, record: &R) -> Bytes {
+    let mut value = BytesMut::new();
+    value.put_unsigned_varint(FRAME_VERSION);
+    value.put_unsigned_varint(record_type as u32);
+    value.put_unsigned_varint(version as u32);
+    record.serialize(&mut value, version);
+    value.freeze()
+}
+
+/// The `__cluster_metadata` log, which this broker, as the only controller
+/// of its cluster, appends to. It is opened on the first write, as nothing
+/// but [`MetadataImage::load`] reads it.
+#[derive(Debug, Default)]
+pub struct MetadataLog {
+    dir: PathBuf,
+    config: LogConfig,
+    log: Mutex<Option<PartitionLog>>,
+}
+
+impl MetadataLog {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            dir: config.metadata_log_dir(),
+            config: LogConfig::new(config),
+            log: Mutex::default(),
+        }
+    }
+
+    /// Writes `values` as one batch and flushes it, so that the records
+    /// are durable before anything is answered based on them.
+    pub fn append(&self, values: &[Bytes]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            *log = Some(PartitionLog::open(&self.dir, self.config, 0)?);
+        }
+        let log = log.as_mut().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let batch = RecordBatch {
+            last_offset_delta: values.len() as i32 - 1,
+            base_timestamp: now,
+            max_timestamp: now,
+            records: values
+                .iter()
+                .enumerate()
+                .map(|(offset_delta, value)| Record {
+                    offset_delta: offset_delta as i32,
+                    value: Some(value.clone()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut data = Vec::with_capacity(batch.encoded_len());
+        batch.serialize(&mut data);
+        log.append(&mut data)?;
+        log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        record::{Record, RecordBatch},
+        serialize::Serialize,
+        testing::{golden, hex},
+    };
+
+    const TOPIC_ID: &str = "00000000000040008000000000000091";
+    const TOPIC_RECORD: &str = "01 02 00  04 666f6f  00000000000040008000000000000091  00";
+    const PARTITION_RECORD: &str = "
+        01 03 00
+        00000001 00000000000040008000000000000091
+        02 00000001  02 00000001  01  01
+        00000001 00000000 00000000
+        00";
+
+    #[test]
+    fn replays_topic_and_partition_records() {
+        let mut image = MetadataImage::default();
+        image.replay(&hex(TOPIC_RECORD)).unwrap();
+        image.replay(&hex(PARTITION_RECORD)).unwrap();
+        image.replay(&hex("01 0c 00  11 6d657461646174612e76657273696f6e 0014 00")).unwrap();
+
+        let topic_id = u128::from_str_radix(TOPIC_ID, 16).unwrap();
+        let topic = image.topic("foo").unwrap();
+        assert_eq!(topic.topic_id, topic_id);
+        assert_eq!(image.topic_by_id(topic_id), Some(topic));
+        let partition = &topic.partitions[&1];
+        assert_eq!(partition.leader, 1);
+        assert_eq!(partition.replicas, vec![1]);
+        assert_eq!(partition.isr, vec![1]);
+        assert_eq!(image.feature_level("metadata.version"), Some(20));
+    }
+
+    #[test]
+    fn replays_topic_removal() {
+        let mut image = MetadataImage::default();
+        image.replay(&hex(TOPIC_RECORD)).unwrap();
+        image.replay(&hex(PARTITION_RECORD)).unwrap();
+        image.replay(&hex(&format!("01 09 00 {TOPIC_ID} 00"))).unwrap();
+        assert_eq!(image.topic("foo"), None);
+        assert_eq!(image.topic_by_id(u128::from_str_radix(TOPIC_ID, 16).unwrap()), None);
+        // Its partitions go with it.
+        image.replay(&hex(TOPIC_RECORD)).unwrap();
+        assert!(image.topic("foo").unwrap().partitions.is_empty());
+    }
+
+    #[test]
+    fn ignores_partitions_of_unknown_topics() {
+        let mut image = MetadataImage::default();
+        image.replay(&hex(PARTITION_RECORD)).unwrap();
+        assert_eq!(image.topics().count(), 0);
+    }
+
+    #[test]
+    fn frames_records_for_replay() {
+        let record = TopicRecord {
+            name: "foo".to_string(),
+            topic_id: u128::from_str_radix(TOPIC_ID, 16).unwrap(),
+            ..Default::default()
+        };
+        let value = record_value(topic_record::API_KEY, 0, &record);
+        assert_eq!(&value[..], hex(TOPIC_RECORD));
+
+        let image = MetadataImage::default();
+        let topic_id = image.new_topic_id().unwrap();
+        assert_eq!(topic_id >> 76 & 0xf, 4);
+        assert_eq!(topic_id >> 62 & 0x3, 2);
+    }
+
+    #[test]
+    fn checks_leader_epochs() {
+        assert_eq!(check_leader_epoch(-1, 3), Ok(()));
+        assert_eq!(check_leader_epoch(3, 3), Ok(()));
+        assert_eq!(
+            check_leader_epoch(2, 3),
+            Err(error_code::FENCED_LEADER_EPOCH)
+        );
+        assert_eq!(
+            check_leader_epoch(4, 3),
+            Err(error_code::UNKNOWN_LEADER_EPOCH)
+        );
+    }
+
+    #[test]
+    fn validates_topic_names() {
+        assert!(valid_topic_name("foo.bar_baz-1"));
+        for name in ["", ".", "..", "foo/bar", "foo bar", &"a".repeat(250)] {
+            assert!(!valid_topic_name(name), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn replays_segment() {
+        let value = |record: &str| Record {
+            value: Some(hex(record).into()),
+            ..Default::default()
+        };
+        let batch = RecordBatch {
+            last_offset_delta: 1,
+            records: vec![value(TOPIC_RECORD), value(PARTITION_RECORD)],
+            ..Default::default()
+        };
+        let leader_change = RecordBatch {
+            base_offset: 2,
+            attributes: 0x20,
+            records: vec![value("0000 00000001")],
+            ..Default::default()
+        };
+        let mut segment = Vec::new();
+        batch.serialize(&mut segment);
+        leader_change.serialize(&mut segment);
+
+        let mut image = MetadataImage::default();
+        image.replay_segment(&segment).unwrap();
+        assert_eq!(image.topic("foo").unwrap().partitions.len(), 1);
+        assert_eq!(image.features_epoch(), -1);
+
+        let features = RecordBatch {
+            base_offset: 3,
+            last_offset_delta: 1,
+            records: vec![
+                value("01 0c 00  11 6d657461646174612e76657273696f6e 0014 00"),
+                Record {
+                    offset_delta: 1,
+                    ..value("01 0c 00  04 666f6f 0000 00")
+                },
+            ],
+            ..Default::default()
+        };
+        let mut segment = Vec::new();
+        features.serialize(&mut segment);
+        image.replay_segment(&segment).unwrap();
+        assert_eq!(
+            image.features().collect::<Vec<_>>(),
+            [("metadata.version", 20)]
+        );
+        assert_eq!(image.features_epoch(), 4);
+    }
+
+    #[test]
+    fn matches_partition_record_golden_vectors() {
+        let record = PartitionRecord {
+            partition_id: 1,
+            topic_id: 2,
+            replicas: vec![1],
+            isr: vec![1],
+            leader: 1,
+            leader_epoch: 0,
+            partition_epoch: 0,
+            directories: vec![5],
+            eligible_leader_replicas: Some(vec![1]),
+            ..Default::default()
+        };
+        let v0 = "00000001 00000000000000000000000000000002
+            02 00000001 02 00000001 01 01
+            00000001 00000000 00000000";
+        let directories = "02 00000000000000000000000000000005";
+        golden(
+            &record,
+            0..=2,
+            &[
+                (0, &format!("{v0} 00")),
+                (1, &format!("{v0} {directories} 00")),
+                // The eligible leader replicas are tag 1, 5 bytes long.
+                (2, &format!("{v0} {directories} 01 01 05 02 00000001")),
+            ],
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+pub use crate::messages::produce_request::{
+    self, PartitionProduceData, ProduceRequest, TopicProduceData,
+};
+pub use crate::messages::produce_response::{
+    self, PartitionProduceResponse, ProduceResponse, TopicProduceResponse,
+};
+use crate::{
+    broker::Broker,
+    error_code,
+    record::{self, check_batch, LOG_OVERHEAD},
+};
+
+/// The `message.timestamp.type` of topics whose batches are stamped with
+/// the time they are appended.
+const LOG_APPEND_TIME: &str = "LogAppendTime";
+
+impl ProduceResponse {
+    /// Appends the records of every partition in `request` to its log.
+    ///
+    /// This broker is the only replica, so once a batch is in the leader's
+    /// log it is also in the full ISR: `acks` of 1 and -1 are both answered
+    /// as soon as the append is done, the latter only after the data has
+    /// been flushed to disk. With no followers to wait for, `timeout_ms`
+    /// never comes into play.
+    pub fn new(request: &ProduceRequest, broker: &Broker) -> Self {
+        let responses = request
+            .topic_data
+            .iter()
+            .map(|topic| TopicProduceResponse {
+                name: topic.name.clone(),
+                partition_responses: topic
+                    .partition_data
+                    .iter()
+                    .map(|partition| {
+                        PartitionProduceResponse::append(
+                            broker,
+                            request.acks,
+                            &topic.name,
+                            partition,
+                        )
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            responses,
+            ..Default::default()
+        }
+    }
+}
+
+impl PartitionProduceResponse {
+    fn error(index: i32, error_code: i16) -> Self {
+        Self {
+            index,
+            error_code,
+            base_offset: -1,
+            ..Default::default()
+        }
+    }
+
+    fn append(broker: &Broker, acks: i16, topic: &str, request: &PartitionProduceData) -> Self {
+        let index = request.index;
+        if !(-1..=1).contains(&acks) {
+            return Self::error(index, error_code::INVALID_REQUIRED_ACKS);
+        }
+        let log_append_time = match broker.image.read().unwrap().topic(topic) {
+            Some(image) if image.partitions.contains_key(&index) => {
+                let timestamp_type = image
+                    .configs
+                    .get("message.timestamp.type")
+                    .map_or(broker.config.message_timestamp_type(), String::as_str);
+                timestamp_type == LOG_APPEND_TIME
+            }
+            _ => return Self::error(index, error_code::UNKNOWN_TOPIC_OR_PARTITION),
+        };
+        let mut data = match check(request.records.as_ref()) {
+            Ok(data) => data,
+            Err(error_code) => return Self::error(index, error_code),
+        };
+
+        let appended = broker.logs.get(topic, index).and_then(|log| {
+            let mut log = log.lock().unwrap();
+            // Stamped under the lock, so that append times never go back
+            // as offsets go up.
+            let log_append_time_ms = stamp(&mut data, log_append_time);
+            let base_offset = log.append(&mut data)?;
+            if acks == -1 {
+                log.flush()?;
+            }
+            Ok((base_offset, log_append_time_ms, log.log_start_offset()))
+        });
+        let (base_offset, log_append_time_ms, log_start_offset) = match appended {
+            Ok(offsets) => offsets,
+            Err(err) => {
+                println!("failed to append to {topic}-{index}: {:#}", err);
+                return Self::error(index, error_code::KAFKA_STORAGE_ERROR);
+            }
+        };
+        broker.fetch_purgatory.wake(topic, index);
+        Self {
+            index,
+            base_offset,
+            log_append_time_ms,
+            log_start_offset,
+            ..Default::default()
+        }
+    }
+}
+
+/// Stamps the checked batches in `data` with the current time as their
+/// LogAppendTime: all of them if `log_append_time`, otherwise those whose
+/// producer asked for it. Returns the time, or -1 if no batch was stamped.
+fn stamp(data: &mut [u8], log_append_time: bool) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64);
+    let mut stamped = -1;
+    let mut position = 0;
+    while position < data.len() {
+        let batch = &mut data[position..];
+        let batch_length = i32::from_be_bytes(batch[8..LOG_OVERHEAD].try_into().unwrap());
+        let batch = &mut batch[..LOG_OVERHEAD + batch_length as usize];
+        if log_append_time || record::has_log_append_time(batch) {
+            record::set_log_append_time(batch, now);
+            stamped = now;
+        }
+        position += batch.len();
+    }
+    stamped
+}
+
+/// Checks the batches a producer sent, every one of which has to be whole
+/// and pass its CRC check, and returns a copy of them to be numbered and
+/// appended. The records are not decoded, so compressed batches go into
+/// the log as they are.
+fn check(records: Option<&Bytes>) -> Result<Vec<u8>, i16> {
+    let data = records.map_or(&[][..], |records| &records[..]);
+    if data.is_empty() {
+        return Err(error_code::INVALID_RECORD);
+    }
+    let mut position = 0;
+    while position < data.len() {
+        let rest = &data[position..];
+        if rest.len() < LOG_OVERHEAD {
+            return Err(error_code::CORRUPT_MESSAGE);
+        }
+        let batch_length = i32::from_be_bytes(rest[8..LOG_OVERHEAD].try_into().unwrap());
+        let size = usize::try_from(batch_length)
+            .ok()
+            .map(|len| LOG_OVERHEAD + len)
+            .filter(|&size| size <= rest.len())
+            .ok_or(error_code::CORRUPT_MESSAGE)?;
+        check_batch(&rest[..size]).map_err(|_| error_code::CORRUPT_MESSAGE)?;
+        position += size;
+    }
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messages::{
+            produce_response::{BatchIndexAndErrorMessage, LeaderIdAndEpoch, NodeEndpoint},
+            ConfigRecord,
+        },
+        metadata::TOPIC_RESOURCE,
+        record::{read_batches, Record, RecordBatch},
+        testing::{broker, captured, encode, golden, hex, serialize_batches},
+    };
+
+    /// Batches of `counts` records each, numbered from 0 as producers do.
+    fn records(counts: &[i32]) -> Bytes {
+        let batches: Vec<RecordBatch> = counts
+            .iter()
+            .map(|&count| RecordBatch {
+                last_offset_delta: count - 1,
+                records: (0..count)
+                    .map(|offset_delta| Record {
+                        offset_delta,
+                        value: Some(Bytes::from_static(b"hello")),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        serialize_batches(&batches).into()
+    }
+
+    fn produce(topic: &str, index: i32, records: Option<Bytes>) -> ProduceRequest {
+        ProduceRequest {
+            acks: -1,
+            timeout_ms: 1000,
+            topic_data: vec![TopicProduceData {
+                name: topic.to_string(),
+                partition_data: vec![PartitionProduceData {
+                    index,
+                    records,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn partition(response: &ProduceResponse) -> &PartitionProduceResponse {
+        &response.responses[0].partition_responses[0]
+    }
+
+    #[test]
+    fn appends_batches_at_the_log_end() {
+        let broker = broker("produce-append", "", &[("foo", 1)]);
+        let response = ProduceResponse::new(&produce("foo", 0, Some(records(&[2, 1]))), &broker);
+        let appended = partition(&response);
+        assert_eq!(appended.error_code, error_code::NONE);
+        assert_eq!(appended.base_offset, 0);
+        assert_eq!(appended.log_append_time_ms, -1);
+        assert_eq!(appended.log_start_offset, 0);
+
+        let response = ProduceResponse::new(&produce("foo", 0, Some(records(&[1]))), &broker);
+        assert_eq!(partition(&response).base_offset, 3);
+
+        let log = broker.logs.get("foo", 0).unwrap();
+        let records = log.lock().unwrap().read(0, usize::MAX, false).unwrap();
+        let batches = read_batches(&records).unwrap();
+        let base_offsets: Vec<i64> = batches.iter().map(|batch| batch.base_offset).collect();
+        assert_eq!(base_offsets, [0, 2, 3]);
+    }
+
+    #[test]
+    fn appends_compressed_batches_as_they_are() {
+        let broker = broker("produce-compressed", "", &[("foo", 1)]);
+        ProduceResponse::new(&produce("foo", 0, Some(records(&[2]))), &broker);
+        // The records are never decoded, so a gzip flag over bytes that
+        // are not gzip is as good as the real thing so long as the CRC
+        // matches.
+        let batch = RecordBatch {
+            attributes: 1,
+            records: vec![Record::default()],
+            ..Default::default()
+        };
+        let sent = serialize_batches(&[batch]);
+        let response = ProduceResponse::new(&produce("foo", 0, Some(sent.clone().into())), &broker);
+        assert_eq!(partition(&response).error_code, error_code::NONE);
+        assert_eq!(partition(&response).base_offset, 2);
+
+        let log = broker.logs.get("foo", 0).unwrap();
+        let stored = log.lock().unwrap().read(2, usize::MAX, false).unwrap();
+        assert_eq!(stored[..8], 2i64.to_be_bytes());
+        assert_eq!(stored[8..], sent[8..]);
+    }
+
+    #[test]
+    fn stamps_log_append_time() {
+        let broker = broker("produce-log-append-time", "", &[("foo", 1)]);
+        let stored = |offset| {
+            let log = broker.logs.get("foo", 0).unwrap();
+            let records = log.lock().unwrap().read(offset, usize::MAX, false).unwrap();
+            read_batches(&records).unwrap().remove(0)
+        };
+
+        // A producer can ask for it batch by batch.
+        let batch = RecordBatch {
+            attributes: 0x08,
+            max_timestamp: 5,
+            records: vec![Record::default()],
+            ..Default::default()
+        };
+        let sent = serialize_batches(&[batch]).into();
+        let response = ProduceResponse::new(&produce("foo", 0, Some(sent)), &broker);
+        let stamped = partition(&response).log_append_time_ms;
+        assert!(stamped > 5);
+        assert!(stored(0).is_log_append_time());
+        assert_eq!(stored(0).max_timestamp, stamped);
+
+        // Or the topic for all of them.
+        broker.image.write().unwrap().set_config(ConfigRecord {
+            resource_type: TOPIC_RESOURCE,
+            resource_name: "foo".to_string(),
+            name: "message.timestamp.type".to_string(),
+            value: Some("LogAppendTime".to_string()),
+            ..Default::default()
+        });
+        let response = ProduceResponse::new(&produce("foo", 0, Some(records(&[1]))), &broker);
+        let stamped = partition(&response).log_append_time_ms;
+        assert!(stamped > 5);
+        assert!(stored(1).is_log_append_time());
+        assert_eq!(stored(1).max_timestamp, stamped);
+    }
+
+    #[test]
+    fn reports_errors_per_partition() {
+        let broker = broker("produce-errors", "", &[("foo", 1)]);
+        let error = |request: ProduceRequest| {
+            partition(&ProduceResponse::new(&request, &broker)).error_code
+        };
+        assert_eq!(
+            error(produce("bar", 0, Some(records(&[1])))),
+            error_code::UNKNOWN_TOPIC_OR_PARTITION
+        );
+        assert_eq!(
+            error(produce("foo", 1, Some(records(&[1])))),
+            error_code::UNKNOWN_TOPIC_OR_PARTITION
+        );
+        assert_eq!(error(produce("foo", 0, None)), error_code::INVALID_RECORD);
+
+        let mut corrupt = records(&[1]).to_vec();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            error(produce("foo", 0, Some(corrupt.into()))),
+            error_code::CORRUPT_MESSAGE
+        );
+        let mut truncated = records(&[1, 1]);
+        truncated.truncate(truncated.len() - 1);
+        assert_eq!(
+            error(produce("foo", 0, Some(truncated))),
+            error_code::CORRUPT_MESSAGE
+        );
+
+        // A batch whose last offset delta does not count its records
+        // would renumber the log.
+        for last_offset_delta in [-3, 2] {
+            let batch = RecordBatch {
+                last_offset_delta,
+                records: vec![Record::default()],
+                ..Default::default()
+            };
+            assert_eq!(
+                error(produce("foo", 0, Some(serialize_batches(&[batch]).into()))),
+                error_code::CORRUPT_MESSAGE
+            );
+        }
+
+        let mut request = produce("foo", 0, Some(records(&[1])));
+        request.acks = 2;
+        assert_eq!(error(request), error_code::INVALID_REQUIRED_ACKS);
+
+        let response = ProduceResponse::new(&produce("foo", 0, None), &broker);
+        assert_eq!(partition(&response).base_offset, -1);
+        // Nothing was written.
+        let log = broker.logs.get("foo", 0).unwrap();
+        assert_eq!(log.lock().unwrap().log_end_offset(), 0);
+    }
+
+    #[test]
+    fn encodes_response() {
+        let response = ProduceResponse {
+            responses: vec![TopicProduceResponse {
+                name: "foo".to_string(),
+                partition_responses: vec![PartitionProduceResponse {
+                    base_offset: 3,
+                    log_start_offset: 0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let v3 = "
+            00000001 0003 666f6f
+                00000001 00000000 0000 0000000000000003 ffffffffffffffff
+            00000000";
+        assert_eq!(encode(&response, 3), hex(v3));
+        let v9 = "
+            02 04 666f6f
+                02 00000000 0000 0000000000000003 ffffffffffffffff 0000000000000000
+                    01 00 00
+                00
+            00000000 00";
+        assert_eq!(encode(&response, 9), hex(v9));
+    }
+
+    /// Body of a v10 request librdkafka 2.12.1 sent for one record with
+    /// key "k" and value "hello".
+    const LIBRDKAFKA_REQUEST_V10: &str = "
+        00 ffff 00007530
+        02 04 666f6f
+            02 00000000
+                4b 0000000000000000 0000003e 00000000 02 36ff4dc3 0000 00000000
+                    0000018bcfe56800 0000018bcfe56800 ffffffffffffffff ffff ffffffff
+                    00000001 18 00 00 00 02 6b 0a 68656c6c6f 00
+                00
+            00
+        00";
+
+    #[test]
+    fn decodes_librdkafka_request() {
+        let request: ProduceRequest = captured(LIBRDKAFKA_REQUEST_V10, 10);
+        assert_eq!(request.transactional_id, None);
+        assert_eq!(request.acks, -1);
+        assert_eq!(request.timeout_ms, 30000);
+        assert_eq!(request.topic_data[0].name, "foo");
+        let partition = &request.topic_data[0].partition_data[0];
+        assert_eq!(partition.index, 0);
+        let records = partition.records.as_ref().unwrap();
+        check_batch(records).unwrap();
+        let batch = &read_batches(records).unwrap()[0];
+        assert_eq!(batch.max_timestamp, 1700000000000);
+        assert_eq!(batch.records[0].key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(batch.records[0].value.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let mut request = produce("foo", 0, Some(Bytes::from_static(&[1, 2])));
+        request.transactional_id = Some("t".to_string());
+        golden(
+            &request,
+            3..=11,
+            &[
+                (
+                    3,
+                    "0001 74 ffff 000003e8 00000001 0003 666f6f 00000001 00000000 00000002 0102",
+                ),
+                (
+                    9,
+                    "02 74 ffff 000003e8 02 04 666f6f 02 00000000 03 0102 00 00 00",
+                ),
+            ],
+        );
+
+        let response = ProduceResponse {
+            responses: vec![TopicProduceResponse {
+                name: "foo".to_string(),
+                partition_responses: vec![PartitionProduceResponse {
+                    base_offset: 3,
+                    log_start_offset: 0,
+                    record_errors: vec![BatchIndexAndErrorMessage {
+                        batch_index: 1,
+                        batch_index_error_message: Some("x".to_string()),
+                        ..Default::default()
+                    }],
+                    error_message: Some("y".to_string()),
+                    current_leader: LeaderIdAndEpoch {
+                        leader_id: 1,
+                        leader_epoch: 0,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            throttle_time_ms: 100,
+            node_endpoints: vec![NodeEndpoint {
+                node_id: 1,
+                host: "h".to_string(),
+                port: 9092,
+                rack: None,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let partition = "00000000 0000 0000000000000003 ffffffffffffffff";
+        let flexible_partition = format!("{partition} 0000000000000000 02 00000001 02 78 00 02 79");
+        golden(
+            &response,
+            3..=11,
+            &[
+                (
+                    3,
+                    &format!("00000001 0003 666f6f 00000001 {partition} 00000064"),
+                ),
+                (
+                    5,
+                    &format!("00000001 0003 666f6f 00000001 {partition} 0000000000000000 00000064"),
+                ),
+                (
+                    8,
+                    &format!(
+                        "00000001 0003 666f6f 00000001 {partition} 0000000000000000
+                            00000001 00000001 0001 78 0001 79
+                        00000064"
+                    ),
+                ),
+                (
+                    9,
+                    &format!("02 04 666f6f 02 {flexible_partition} 00 00 00000064 00"),
+                ),
+                // The current leader and the node endpoints are both tag 0.
+                (
+                    10,
+                    &format!(
+                        "02 04 666f6f 02 {flexible_partition} 01 00 09 00000001 00000000 00 00
+                        00000064
+                        01 00 0d 02 00000001 02 68 00002384 00 00"
+                    ),
+                ),
+            ],
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::sync::Notify;
+
+/// The requests watching each partition, by topic name and index.
+type Watchers = HashMap<(String, i32), Vec<Weak<Notify>>>;
+
+/// Where delayed requests wait for the partitions they read to change.
+///
+/// A request registers with [`Purgatory::watch`] *before* checking whether
+/// it can complete; an append that lands in between leaves a permit on the
+/// returned [`Notify`], so the wakeup cannot be missed.
+#[derive(Debug, Default)]
+pub struct Purgatory {
+    watchers: Mutex<Watchers>,
+}
+
+impl Purgatory {
+    /// Returns a notifier that fires on the next append to any of
+    /// `partitions`. Dropping it withdraws the interest.
+    pub fn watch<'a>(&self, partitions: impl IntoIterator<Item = (&'a str, i32)>) -> Arc<Notify> {
+        let notify = Arc::new(Notify::new());
+        let mut watchers = self.watchers.lock().unwrap();
+        for (topic, partition) in partitions {
+            let waiting = watchers.entry((topic.to_string(), partition)).or_default();
+            // Requests that gave up before anything was appended leave
+            // their entries behind; drop them as new ones come in.
+            waiting.retain(|watcher| watcher.strong_count() > 0);
+            waiting.push(Arc::downgrade(&notify));
+        }
+        notify
+    }
+
+    /// Wakes every request watching `partition` of `topic`.
+    pub fn wake(&self, topic: &str, partition: i32) {
+        let waiting = self
+            .watchers
+            .lock()
+            .unwrap()
+            .remove(&(topic.to_string(), partition))
+            .unwrap_or_default();
+        for notify in waiting.iter().filter_map(Weak::upgrade) {
+            notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn wakes_watchers_of_the_partition() {
+        let purgatory = Purgatory::default();
+        let foo = purgatory.watch([("foo", 0), ("foo", 1)]);
+        let bar = purgatory.watch([("bar", 0)]);
+
+        // Woken before waiting: the permit is kept.
+        purgatory.wake("foo", 1);
+        timeout(Duration::from_secs(1), foo.notified())
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(10), bar.notified())
+            .await
+            .is_err());
+
+        purgatory.wake("bar", 0);
+        timeout(Duration::from_secs(1), bar.notified())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn forgets_dropped_watchers() {
+        let purgatory = Purgatory::default();
+        drop(purgatory.watch([("foo", 0)]));
+        let _foo = purgatory.watch([("foo", 0)]);
+        assert_eq!(
+            purgatory.watchers.lock().unwrap()[&("foo".to_string(), 0)].len(),
+            1
+        );
+    }
+}
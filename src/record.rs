@@ -0,0 +1,566 @@
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    deserialize::{BufExt, DecodeError, Deserialize},
+    serialize::{varint_len, varlong_len, BufMutExt, Serialize},
+};
+
+/// Size of the batch fields before the records: base offset (8), batch
+/// length (4), partition leader epoch (4), magic (1), CRC (4), attributes
+/// (2), last offset delta (4), base and max timestamps (8 + 8), producer id
+/// (8), producer epoch (2), base sequence (4) and record count (4).
+pub const BATCH_HEADER_LEN: usize = 61;
+
+/// Bytes before the batch length is counted from: base offset and length.
+pub const LOG_OVERHEAD: usize = 12;
+
+/// Offset of the attributes, where the CRC-32C coverage starts.
+const CRC_START: usize = 21;
+
+/// Offset of the last offset delta, for scanning batches without decoding
+/// them.
+pub const LAST_OFFSET_DELTA_OFFSET: usize = 23;
+
+/// Offset of the max timestamp, likewise.
+pub const MAX_TIMESTAMP_OFFSET: usize = 35;
+
+/// Offset of the record count, the last field of the header.
+const RECORD_COUNT_OFFSET: usize = 57;
+
+const COMPRESSION_MASK: i16 = 0x07;
+const TIMESTAMP_TYPE_FLAG: i16 = 0x08;
+const CONTROL_FLAG: i16 = 0x20;
+
+/// A RecordBatch in the v2 (magic 2) format, as found both in log segments
+/// and in the `records` fields of Fetch and Produce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordBatch {
+    pub base_offset: i64,
+    pub partition_leader_epoch: i32,
+    pub attributes: i16,
+    pub last_offset_delta: i32,
+    pub base_timestamp: i64,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub base_sequence: i32,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    pub attributes: i8,
+    pub timestamp_delta: i64,
+    pub offset_delta: i32,
+    pub key: Option<Bytes>,
+    pub value: Option<Bytes>,
+    pub headers: Vec<Header>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    pub key: String,
+    pub value: Option<Bytes>,
+}
+
+impl Default for RecordBatch {
+    fn default() -> Self {
+        Self {
+            base_offset: 0,
+            partition_leader_epoch: -1,
+            attributes: 0,
+            last_offset_delta: 0,
+            base_timestamp: 0,
+            max_timestamp: 0,
+            producer_id: -1,
+            producer_epoch: -1,
+            base_sequence: -1,
+            records: Vec::new(),
+        }
+    }
+}
+
+impl RecordBatch {
+    /// Whether the broker stamped the batch with the time it was appended,
+    /// which then stands for the timestamps of all its records.
+    pub fn is_log_append_time(&self) -> bool {
+        self.attributes & TIMESTAMP_TYPE_FLAG != 0
+    }
+
+    /// Control batches hold transaction markers and leader changes rather
+    /// than application data.
+    pub fn is_control(&self) -> bool {
+        self.attributes & CONTROL_FLAG != 0
+    }
+
+    #[cfg(test)]
+    pub fn last_offset(&self) -> i64 {
+        self.base_offset + self.last_offset_delta as i64
+    }
+
+    /// The timestamp and offset of each record.
+    pub fn record_timestamps(&self) -> impl Iterator<Item = (i64, i64)> + '_ {
+        self.records.iter().map(|record| {
+            let timestamp = match self.is_log_append_time() {
+                true => self.max_timestamp,
+                false => self.base_timestamp + record.timestamp_delta,
+            };
+            (timestamp, self.base_offset + record.offset_delta as i64)
+        })
+    }
+
+    /// The value of the batch length field: everything after it.
+    fn batch_length(&self) -> usize {
+        BATCH_HEADER_LEN - LOG_OVERHEAD + self.records.iter().map(Record::encoded_len).sum::<usize>()
+    }
+
+    /// Writes the part of the batch covered by the CRC.
+    fn put_checksummed<B: BufMut>(&self, buffer: &mut B) {
+        buffer.put_i16(self.attributes);
+        buffer.put_i32(self.last_offset_delta);
+        buffer.put_i64(self.base_timestamp);
+        buffer.put_i64(self.max_timestamp);
+        buffer.put_i64(self.producer_id);
+        buffer.put_i16(self.producer_epoch);
+        buffer.put_i32(self.base_sequence);
+        buffer.put_i32(self.records.len() as i32);
+        for record in &self.records {
+            record.serialize(buffer);
+        }
+    }
+}
+
+impl<T: Buf> Deserialize<T> for RecordBatch {
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError> {
+        let base_offset = buffer.get_checked_i64()?;
+        let batch_length = buffer.get_checked_i32()?;
+        let batch_length = usize::try_from(batch_length)
+            .ok()
+            .filter(|len| *len >= BATCH_HEADER_LEN - LOG_OVERHEAD)
+            .ok_or(DecodeError::InvalidLength(batch_length as i64))?;
+        let mut batch = buffer.copy_to_bytes_checked(batch_length)?;
+
+        let partition_leader_epoch = batch.get_checked_i32()?;
+        let magic = batch.get_checked_i8()?;
+        if magic != 2 {
+            return Err(DecodeError::UnsupportedMagic(magic));
+        }
+        let expected = batch.get_checked_u32()?;
+        let computed = crc32c(&batch);
+        if expected != computed {
+            return Err(DecodeError::InvalidCrc { expected, computed });
+        }
+        let attributes = batch.get_checked_i16()?;
+        if attributes & COMPRESSION_MASK != 0 {
+            return Err(DecodeError::UnsupportedCompression(attributes & COMPRESSION_MASK));
+        }
+        let last_offset_delta = batch.get_checked_i32()?;
+        let base_timestamp = batch.get_checked_i64()?;
+        let max_timestamp = batch.get_checked_i64()?;
+        let producer_id = batch.get_checked_i64()?;
+        let producer_epoch = batch.get_checked_i16()?;
+        let base_sequence = batch.get_checked_i32()?;
+        let count = batch.get_checked_i32()?;
+        let count = usize::try_from(count).map_err(|_| DecodeError::InvalidLength(count as i64))?;
+        let records = batch.get_checked_elements(count, Record::from_bytes)?;
+
+        Ok(Self {
+            base_offset,
+            partition_leader_epoch,
+            attributes,
+            last_offset_delta,
+            base_timestamp,
+            max_timestamp,
+            producer_id,
+            producer_epoch,
+            base_sequence,
+            records,
+        })
+    }
+}
+
+impl Serialize for RecordBatch {
+    fn encoded_len(&self) -> usize {
+        LOG_OVERHEAD + self.batch_length()
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B) {
+        let mut checksummed = Vec::with_capacity(self.encoded_len() - CRC_START);
+        self.put_checksummed(&mut checksummed);
+        buffer.put_i64(self.base_offset);
+        buffer.put_i32(self.batch_length() as i32);
+        buffer.put_i32(self.partition_leader_epoch);
+        buffer.put_i8(2);
+        buffer.put_u32(crc32c(&checksummed));
+        buffer.put_slice(&checksummed);
+    }
+}
+
+/// Decodes the batches in `data`. Brokers may cut the last batch of a
+/// Fetch response short, so a trailing partial batch is ignored rather
+/// than treated as an error.
+pub fn read_batches(mut data: &[u8]) -> Result<Vec<RecordBatch>, DecodeError> {
+    let mut batches = Vec::new();
+    while data.len() >= LOG_OVERHEAD {
+        let batch_length = i32::from_be_bytes(data[8..12].try_into().unwrap());
+        if usize::try_from(batch_length).is_ok_and(|len| data.len() < LOG_OVERHEAD + len) {
+            break;
+        }
+        batches.push(RecordBatch::from_bytes(&mut data)?);
+    }
+    Ok(batches)
+}
+
+/// Checks that `batch` is exactly one v2 batch, that its CRC matches and
+/// that its last offset delta moves the log forward, without decoding the
+/// records. Compressed batches pass too, since the CRC covers the
+/// compressed bytes; uncompressed ones also have to have a delta that
+/// accounts for exactly their records, as offsets are numbered from it.
+pub fn check_batch(batch: &[u8]) -> Result<(), DecodeError> {
+    if batch.len() < BATCH_HEADER_LEN {
+        return Err(DecodeError::InvalidLength(batch.len() as i64));
+    }
+    let batch_length = i32::from_be_bytes(batch[8..LOG_OVERHEAD].try_into().unwrap());
+    if LOG_OVERHEAD as i64 + batch_length as i64 != batch.len() as i64 {
+        return Err(DecodeError::InvalidLength(batch_length as i64));
+    }
+    let magic = batch[16] as i8;
+    if magic != 2 {
+        return Err(DecodeError::UnsupportedMagic(magic));
+    }
+    let expected = u32::from_be_bytes(batch[17..CRC_START].try_into().unwrap());
+    let computed = crc32c(&batch[CRC_START..]);
+    if expected != computed {
+        return Err(DecodeError::InvalidCrc { expected, computed });
+    }
+    let attributes = i16::from_be_bytes(batch[CRC_START..CRC_START + 2].try_into().unwrap());
+    let last_offset_delta = i32::from_be_bytes(
+        batch[LAST_OFFSET_DELTA_OFFSET..LAST_OFFSET_DELTA_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let count = i32::from_be_bytes(
+        batch[RECORD_COUNT_OFFSET..BATCH_HEADER_LEN]
+            .try_into()
+            .unwrap(),
+    );
+    let compressed = attributes & COMPRESSION_MASK != 0;
+    if last_offset_delta < 0 || (!compressed && last_offset_delta as i64 != count as i64 - 1) {
+        return Err(DecodeError::InvalidLastOffsetDelta {
+            last_offset_delta,
+            count,
+        });
+    }
+    Ok(())
+}
+
+/// Whether the producer of the checked `batch` asked for LogAppendTime.
+pub fn has_log_append_time(batch: &[u8]) -> bool {
+    let attributes = i16::from_be_bytes(batch[CRC_START..CRC_START + 2].try_into().unwrap());
+    attributes & TIMESTAMP_TYPE_FLAG != 0
+}
+
+/// Stamps the checked `batch` with `timestamp` as its LogAppendTime: the
+/// timestamp type is set and the max timestamp, which then stands for
+/// every record's, overwritten. Both are covered by the CRC, so it is
+/// computed again; the records are left as they are.
+pub fn set_log_append_time(batch: &mut [u8], timestamp: i64) {
+    let attributes = i16::from_be_bytes(batch[CRC_START..CRC_START + 2].try_into().unwrap());
+    batch[CRC_START..CRC_START + 2]
+        .copy_from_slice(&(attributes | TIMESTAMP_TYPE_FLAG).to_be_bytes());
+    batch[MAX_TIMESTAMP_OFFSET..MAX_TIMESTAMP_OFFSET + 8].copy_from_slice(&timestamp.to_be_bytes());
+    let crc = crc32c(&batch[CRC_START..]);
+    batch[17..CRC_START].copy_from_slice(&crc.to_be_bytes());
+}
+
+impl Record {
+    /// The size of everything after the length prefix.
+    fn body_len(&self) -> usize {
+        1 + varlong_len(self.timestamp_delta)
+            + varint_len(self.offset_delta)
+            + varint_bytes_len(self.key.as_deref())
+            + varint_bytes_len(self.value.as_deref())
+            + varint_len(self.headers.len() as i32)
+            + self
+                .headers
+                .iter()
+                .map(|header| {
+                    varint_bytes_len(Some(header.key.as_bytes()))
+                        + varint_bytes_len(header.value.as_deref())
+                })
+                .sum::<usize>()
+    }
+}
+
+impl<T: Buf> Deserialize<T> for Record {
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError> {
+        let length = buffer.get_checked_varint()?;
+        let length = usize::try_from(length).map_err(|_| DecodeError::InvalidLength(length as i64))?;
+        let mut record = buffer.copy_to_bytes_checked(length)?;
+
+        let attributes = record.get_checked_i8()?;
+        let timestamp_delta = record.get_checked_varlong()?;
+        let offset_delta = record.get_checked_varint()?;
+        let key = get_varint_bytes(&mut record)?;
+        let value = get_varint_bytes(&mut record)?;
+        let count = record.get_checked_varint()?;
+        let count = usize::try_from(count).map_err(|_| DecodeError::InvalidLength(count as i64))?;
+        let headers = record.get_checked_elements(count, |record| {
+            let key = get_varint_bytes(record)?.ok_or(DecodeError::InvalidLength(-1))?;
+            Ok(Header {
+                key: String::from_utf8(key.to_vec())?,
+                value: get_varint_bytes(record)?,
+            })
+        })?;
+
+        Ok(Self {
+            attributes,
+            timestamp_delta,
+            offset_delta,
+            key,
+            value,
+            headers,
+        })
+    }
+}
+
+impl Serialize for Record {
+    fn encoded_len(&self) -> usize {
+        let body_len = self.body_len();
+        varint_len(body_len as i32) + body_len
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B) {
+        buffer.put_varint(self.body_len() as i32);
+        buffer.put_i8(self.attributes);
+        buffer.put_varlong(self.timestamp_delta);
+        buffer.put_varint(self.offset_delta);
+        put_varint_bytes(buffer, self.key.as_deref());
+        put_varint_bytes(buffer, self.value.as_deref());
+        buffer.put_varint(self.headers.len() as i32);
+        for header in &self.headers {
+            put_varint_bytes(buffer, Some(header.key.as_bytes()));
+            put_varint_bytes(buffer, header.value.as_deref());
+        }
+    }
+}
+
+/// Keys, values and headers are prefixed with a signed varint length,
+/// where -1 means null.
+fn get_varint_bytes(buffer: &mut Bytes) -> Result<Option<Bytes>, DecodeError> {
+    match buffer.get_checked_varint()? {
+        -1 => Ok(None),
+        len => {
+            let len = usize::try_from(len).map_err(|_| DecodeError::InvalidLength(len as i64))?;
+            Ok(Some(buffer.copy_to_bytes_checked(len)?))
+        }
+    }
+}
+
+fn put_varint_bytes<B: BufMut>(buffer: &mut B, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            buffer.put_varint(value.len() as i32);
+            buffer.put_slice(value);
+        }
+        None => buffer.put_varint(-1),
+    }
+}
+
+fn varint_bytes_len(value: Option<&[u8]>) -> usize {
+    value.map_or(1, |value| varint_len(value.len() as i32) + value.len())
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f6_3b78,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32C (Castagnoli), the checksum of v2 record batches.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{hex, Rng};
+
+    /// Two records at offsets 5 and 6, the second with a key and a header.
+    const BATCH: &str = "
+        0000000000000005 0000004e 00000001 02 70721019
+        0000 00000001 0000018bcfe56800 0000018bcfe56801
+        ffffffffffffffff ffff ffffffff 00000002
+        16 00 00 00 01 0a 68656c6c6f 00
+        20 00 02 02 02 6b 0a 776f726c64 02 02 68 02 76";
+
+    fn encode(batch: &RecordBatch) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        batch.serialize(&mut buffer);
+        assert_eq!(buffer.len(), batch.encoded_len());
+        buffer
+    }
+
+    #[test]
+    fn computes_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn decodes_golden_batch() {
+        let bytes = hex(BATCH);
+        let batch = RecordBatch::from_bytes(&mut &bytes[..]).unwrap();
+        assert_eq!(batch.base_offset, 5);
+        assert_eq!(batch.last_offset(), 6);
+        assert_eq!(batch.base_timestamp, 1_700_000_000_000);
+        assert_eq!(batch.records.len(), 2);
+        assert_eq!(batch.records[0].key, None);
+        assert_eq!(batch.records[0].value.as_deref(), Some(&b"hello"[..]));
+        let record = &batch.records[1];
+        assert_eq!(record.offset_delta, 1);
+        assert_eq!(record.key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(record.headers[0].key, "h");
+        assert_eq!(record.headers[0].value.as_deref(), Some(&b"v"[..]));
+        assert_eq!(encode(&batch), bytes);
+    }
+
+    #[test]
+    fn rejects_corrupt_batches() {
+        let mut bytes = hex(BATCH);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            RecordBatch::from_bytes(&mut &bytes[..]),
+            Err(DecodeError::InvalidCrc { .. })
+        ));
+        assert!(matches!(
+            check_batch(&bytes),
+            Err(DecodeError::InvalidCrc { .. })
+        ));
+
+        let mut bytes = hex(BATCH);
+        bytes[16] = 1;
+        assert!(matches!(
+            RecordBatch::from_bytes(&mut &bytes[..]),
+            Err(DecodeError::UnsupportedMagic(1))
+        ));
+        assert!(check_batch(&hex(BATCH)).is_ok());
+        assert!(matches!(
+            check_batch(&bytes),
+            Err(DecodeError::UnsupportedMagic(1))
+        ));
+        assert!(matches!(
+            check_batch(&hex(BATCH)[..60]),
+            Err(DecodeError::InvalidLength(60))
+        ));
+
+        let compressed = RecordBatch {
+            attributes: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            RecordBatch::from_bytes(&mut &encode(&compressed)[..]),
+            Err(DecodeError::UnsupportedCompression(1))
+        ));
+
+        // The last offset delta has to count the records, or for
+        // compressed batches whose records are not looked at, at least not
+        // go backwards.
+        let delta = |attributes, last_offset_delta, count| {
+            let batch = RecordBatch {
+                attributes,
+                last_offset_delta,
+                records: vec![Record::default(); count],
+                ..Default::default()
+            };
+            check_batch(&encode(&batch))
+        };
+        assert!(delta(0, 1, 2).is_ok());
+        assert!(delta(1, 5, 2).is_ok());
+        for (attributes, last_offset_delta, count) in
+            [(0, -3, 1), (0, 0, 2), (0, -1, 0), (1, -1, 0)]
+        {
+            assert!(
+                matches!(
+                    delta(attributes, last_offset_delta, count),
+                    Err(DecodeError::InvalidLastOffsetDelta { .. })
+                ),
+                "{attributes} {last_offset_delta} {count}"
+            );
+        }
+    }
+
+    #[test]
+    fn stamps_log_append_time() {
+        let mut bytes = hex(BATCH);
+        assert!(!has_log_append_time(&bytes));
+        set_log_append_time(&mut bytes, 1_700_000_000_000);
+        assert!(has_log_append_time(&bytes));
+        assert!(check_batch(&bytes).is_ok());
+        let batch = RecordBatch::from_bytes(&mut &bytes[..]).unwrap();
+        assert!(batch.is_log_append_time());
+        assert_eq!(batch.max_timestamp, 1_700_000_000_000);
+        assert!(batch
+            .record_timestamps()
+            .all(|(timestamp, _)| timestamp == 1_700_000_000_000));
+        let original = RecordBatch::from_bytes(&mut &hex(BATCH)[..]).unwrap();
+        assert_eq!(batch.records, original.records);
+    }
+
+    #[test]
+    fn ignores_trailing_partial_batch() {
+        let mut bytes = hex(BATCH);
+        bytes.extend_from_slice(&hex(BATCH)[..30]);
+        let batches = read_batches(&bytes).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(read_batches(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut rng = Rng::new(2);
+        for _ in 0..200 {
+            let batch = RecordBatch {
+                base_offset: rng.i64(),
+                partition_leader_epoch: rng.i32(),
+                attributes: rng.i16() & !COMPRESSION_MASK,
+                last_offset_delta: rng.i32(),
+                base_timestamp: rng.i64(),
+                max_timestamp: rng.i64(),
+                producer_id: rng.i64(),
+                producer_epoch: rng.i16(),
+                base_sequence: rng.i32(),
+                records: rng.vec(|rng| Record {
+                    attributes: rng.i8(),
+                    timestamp_delta: rng.i64(),
+                    offset_delta: rng.i32(),
+                    key: rng.option(Rng::bytes),
+                    value: rng.option(Rng::bytes),
+                    headers: rng.vec(|rng| Header {
+                        key: rng.string(),
+                        value: rng.option(Rng::bytes),
+                    }),
+                }),
+            };
+            let bytes = encode(&batch);
+            assert_eq!(RecordBatch::from_bytes(&mut &bytes[..]).unwrap(), batch);
+        }
+    }
+}
@@ -2,7 +2,21 @@ use std::io::{Read, Write};
 
 use bytes::{Buf, Bytes};
 
-use crate::{describe::DescribeTopicPartitionsRequest, deserialize::Deserialize, fetch::FetchRequest};
+use crate::{
+    api_version::ApiVersionsRequest,
+    create_partitions::CreatePartitionsRequest,
+    create_topics::CreateTopicsRequest,
+    delete_topics::DeleteTopicsRequest,
+    describe::DescribeTopicPartitionsRequest,
+    deserialize::{BufExt, DecodeError, Deserialize, VersionedDeserialize},
+    fetch::FetchRequest,
+    handler,
+    header_version::request_header_version,
+    list_offsets::ListOffsetsRequest,
+    produce::ProduceRequest,
+    tagged_fields::TaggedFields,
+    topic_metadata::MetadataRequest,
+};
 
 #[derive(Debug)]
 pub struct Request {
@@ -11,59 +25,128 @@ pub struct Request {
 }
 
 impl<T: Buf> Deserialize<T> for Request {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let header = RequestHeader::from_bytes(buffer);
-        match header.request_api_key {
-            1 => {
-                let body = RequestBody::Fetch(FetchRequest::from_bytes(buffer));
-                Self { header, body }
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError> {
+        let header = RequestHeader::from_bytes(buffer)?;
+        let version = header.request_api_version;
+        let supported = handler::supports(header.request_api_key, version);
+        let body = match header.request_api_key {
+            0 if supported => RequestBody::Produce(ProduceRequest::from_bytes(buffer, version)?),
+            1 if supported => RequestBody::Fetch(FetchRequest::from_bytes(buffer, version)?),
+            2 if supported => {
+                RequestBody::ListOffsets(ListOffsetsRequest::from_bytes(buffer, version)?)
             }
+            3 if supported => RequestBody::Metadata(MetadataRequest::from_bytes(buffer, version)?),
+            18 if supported => {
+                // Nothing in the request changes the answer, but it still
+                // has to be well-formed.
+                ApiVersionsRequest::from_bytes(buffer, version)?;
+                RequestBody::ApiVersion
+            }
+            // A client may send a newer ApiVersions request than we
+            // understand; it is answered with UNSUPPORTED_VERSION, so the
+            // body does not need to be parsed.
             18 => {
-                let body = RequestBody::ApiVersion;
-                Self { header, body }
+                buffer.advance(buffer.remaining());
+                RequestBody::ApiVersion
             }
-            75 => {
-                let body = RequestBody::Describe(DescribeTopicPartitionsRequest::from_bytes(buffer));
-                Self { header, body }
+            19 if supported => {
+                RequestBody::CreateTopics(CreateTopicsRequest::from_bytes(buffer, version)?)
             }
-            _ => todo!(),
-        }
+            20 if supported => {
+                RequestBody::DeleteTopics(DeleteTopicsRequest::from_bytes(buffer, version)?)
+            }
+            37 if supported => RequestBody::CreatePartitions(CreatePartitionsRequest::from_bytes(
+                buffer, version,
+            )?),
+            75 if supported => RequestBody::Describe(DescribeTopicPartitionsRequest::from_bytes(
+                buffer, version,
+            )?),
+            // Anything else is kept as opaque bytes; the frame length
+            // already told us where it ends.
+            _ => {
+                buffer.advance(buffer.remaining());
+                RequestBody::Unknown
+            }
+        };
+        Ok(Self { header, body })
     }
 }
 
-
-
 #[derive(Debug)]
 pub struct RequestHeader {
     pub request_api_key: i16,
     pub request_api_version: i16,
     pub correlation_id: i32,
-    client_id: (i16, String),
-    _tagged_fields: Option<Vec<i32>>,
 }
 
 impl<T: Buf> Deserialize<T> for RequestHeader {
-    fn from_bytes(buffer: &mut T) -> Self {
-        let request_api_key = buffer.get_i16();
-        let request_api_version = buffer.get_i16();
-        let correlation_id = buffer.get_i32();
-        let mut client_id = (buffer.get_i16(), String::new());
-        client_id.1 = String::from_utf8_lossy(&buffer.copy_to_bytes(client_id.0 as usize)).to_string();
-        buffer.get_u8();
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError> {
+        let request_api_key = buffer.get_checked_i16()?;
+        let request_api_version = buffer.get_checked_i16()?;
+        let correlation_id = buffer.get_checked_i32()?;
+        // The client id and tagged fields are read past but not used.
+        let header_version = request_header_version(request_api_key, request_api_version);
+        if header_version >= 1 {
+            buffer.get_checked_nullable_string()?;
+        }
+        if header_version >= 2 {
+            TaggedFields::from_bytes(buffer)?;
+        }
 
-        RequestHeader {
+        Ok(RequestHeader {
             request_api_key,
             request_api_version,
             correlation_id,
-            client_id,
-            _tagged_fields: None,
-        }
+        })
     }
 }
 
 #[derive(Debug)]
 pub enum RequestBody {
+    Produce(ProduceRequest),
     ApiVersion,
     Fetch(FetchRequest),
+    ListOffsets(ListOffsetsRequest),
+    Metadata(MetadataRequest),
+    CreateTopics(CreateTopicsRequest),
+    DeleteTopics(DeleteTopicsRequest),
+    CreatePartitions(CreatePartitionsRequest),
     Describe(DescribeTopicPartitionsRequest),
-}
\ No newline at end of file
+    /// An API, or a version of one, that has no handler.
+    Unknown,
+}
+
+impl Request {
+    /// Whether the client waits for an answer. Producers asking for no
+    /// acknowledgement (`acks=0`) do not.
+    pub fn expects_response(&self) -> bool {
+        !matches!(self.body, RequestBody::Produce(ref produce) if produce.acks == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::hex;
+
+    #[test]
+    fn keeps_unknown_api_body_opaque() {
+        // ListGroups v4 (header v2) with no states filter.
+        let bytes = hex("0010 0004 00000007 0002 6b74 00  00 00 00");
+        let mut buffer = &bytes[..];
+        let request = Request::from_bytes(&mut buffer).unwrap();
+        assert_eq!(request.header.request_api_key, 16);
+        assert_eq!(request.header.correlation_id, 7);
+        assert!(matches!(request.body, RequestBody::Unknown));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn produce_without_acks_expects_no_response() {
+        // Produce v3 (header v1) with acks=0 and no topics.
+        let bytes = hex("0000 0003 00000001 0002 6b74  ffff 0000 000003e8 00000000");
+        let request = Request::from_bytes(&mut &bytes[..]).unwrap();
+        assert!(matches!(request.body, RequestBody::Produce(_)));
+        assert!(!request.expects_response());
+    }
+}
@@ -1,58 +1,121 @@
 use bytes::BufMut;
 
-use crate::{api_version::ApiVersion, describe::DescribeTopicPartitionsResponse, fetch::FetchResponse};
+use crate::{
+    api_version::ApiVersionsResponse,
+    create_partitions::CreatePartitionsResponse,
+    create_topics::CreateTopicsResponse,
+    delete_topics::DeleteTopicsResponse,
+    describe::DescribeTopicPartitionsResponse,
+    fetch::FetchResponse,
+    header_version::response_header_version,
+    list_offsets::ListOffsetsResponse,
+    produce::ProduceResponse,
+    request::RequestHeader,
+    serialize::{Serialize, VersionedSerialize},
+    tagged_fields::TaggedFields,
+    topic_metadata::MetadataResponse,
+};
 
 #[derive(Debug)]
 pub struct Response {
     pub(crate) header: ResponseHeader,
+    /// The version the body is encoded in, normally that of the request.
+    pub(crate) api_version: i16,
     pub(crate) body: ResponseBody,
 }
 
-impl Into<Vec<u8>> for &Response {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&Into::<Vec<u8>>::into(&self.header)[..]);
-        buffer.extend_from_slice(&Into::<Vec<u8>>::into(&self.body)[..]);
-        buffer
+impl Serialize for Response {
+    fn encoded_len(&self) -> usize {
+        self.header.encoded_len() + self.body.encoded_len(self.api_version)
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B) {
+        self.header.serialize(buffer);
+        self.body.serialize(buffer, self.api_version);
     }
 }
 
 #[derive(Debug)]
 pub struct ResponseHeader {
     pub(crate) correlation_id: i32,
+    pub(crate) header_version: i16,
+    pub(crate) tagged_fields: TaggedFields,
+}
+
+impl ResponseHeader {
+    /// Builds the header answering `request`, in the header version its
+    /// API key and version call for.
+    pub fn new(request: &RequestHeader) -> Self {
+        Self {
+            correlation_id: request.correlation_id,
+            header_version: response_header_version(
+                request.request_api_key,
+                request.request_api_version,
+            ),
+            tagged_fields: TaggedFields::default(),
+        }
+    }
 }
 
-impl Into<Vec<u8>> for &ResponseHeader {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.correlation_id.to_be_bytes());
-        buffer
+impl Serialize for ResponseHeader {
+    fn encoded_len(&self) -> usize {
+        match self.header_version {
+            0 => 4,
+            _ => 4 + self.tagged_fields.encoded_len(),
+        }
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B) {
+        buffer.put_i32(self.correlation_id);
+        if self.header_version >= 1 {
+            self.tagged_fields.serialize(buffer);
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum ResponseBody {
-    ApiVersion(ApiVersion),
+    Produce(ProduceResponse),
+    ApiVersion(ApiVersionsResponse),
     Fetch(FetchResponse),
+    ListOffsets(ListOffsetsResponse),
+    Metadata(MetadataResponse),
+    CreateTopics(CreateTopicsResponse),
+    DeleteTopics(DeleteTopicsResponse),
+    CreatePartitions(CreatePartitionsResponse),
     Describe(DescribeTopicPartitionsResponse),
 }
 
-impl Into<Vec<u8>> for &ResponseBody {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = Vec::new();
+impl VersionedSerialize for ResponseBody {
+    fn encoded_len(&self, version: i16) -> usize {
         match self {
-            ResponseBody::ApiVersion(api_version) => {
-                buffer.extend_from_slice(&Into::<Vec<u8>>::into(api_version)[..]);
+            ResponseBody::Produce(produce) => produce.encoded_len(version),
+            ResponseBody::ApiVersion(api_version) => api_version.encoded_len(version),
+            ResponseBody::Fetch(fetch_response) => fetch_response.encoded_len(version),
+            ResponseBody::ListOffsets(list_offsets) => list_offsets.encoded_len(version),
+            ResponseBody::Metadata(metadata) => metadata.encoded_len(version),
+            ResponseBody::CreateTopics(create_topics) => create_topics.encoded_len(version),
+            ResponseBody::DeleteTopics(delete_topics) => delete_topics.encoded_len(version),
+            ResponseBody::CreatePartitions(create_partitions) => {
+                create_partitions.encoded_len(version)
             }
-            ResponseBody::Fetch(fetch_response) => {
-                buffer.put_u8(0);
-                buffer.extend_from_slice(&Into::<Vec<u8>>::into(fetch_response)[..]);
-            }
-            ResponseBody::Describe(describe) => {
-                buffer.put_u8(0);
-                buffer.extend_from_slice(&Into::<Vec<u8>>::into(describe)[..]);
+            ResponseBody::Describe(describe) => describe.encoded_len(version),
+        }
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B, version: i16) {
+        match self {
+            ResponseBody::Produce(produce) => produce.serialize(buffer, version),
+            ResponseBody::ApiVersion(api_version) => api_version.serialize(buffer, version),
+            ResponseBody::Fetch(fetch_response) => fetch_response.serialize(buffer, version),
+            ResponseBody::ListOffsets(list_offsets) => list_offsets.serialize(buffer, version),
+            ResponseBody::Metadata(metadata) => metadata.serialize(buffer, version),
+            ResponseBody::CreateTopics(create_topics) => create_topics.serialize(buffer, version),
+            ResponseBody::DeleteTopics(delete_topics) => delete_topics.serialize(buffer, version),
+            ResponseBody::CreatePartitions(create_partitions) => {
+                create_partitions.serialize(buffer, version)
             }
+            ResponseBody::Describe(describe) => describe.serialize(buffer, version),
         }
-        buffer
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,219 @@
+use bytes::BufMut;
+
+/// Writers for the length-prefixed encodings of the Kafka protocol, in
+/// both their classic (INT16/INT32 length) and compact (varint) forms.
+pub trait BufMutExt: BufMut {
+    fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    fn put_string16(&mut self, value: &str) {
+        self.put_i16(value.len() as i16);
+        self.put_slice(value.as_bytes());
+    }
+
+    fn put_nullable_string(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => self.put_string16(value),
+            None => self.put_i16(-1),
+        }
+    }
+
+    fn put_bytes32(&mut self, value: &[u8]) {
+        self.put_i32(value.len() as i32);
+        self.put_slice(value);
+    }
+
+    fn put_nullable_bytes(&mut self, value: Option<&[u8]>) {
+        match value {
+            Some(value) => self.put_bytes32(value),
+            None => self.put_i32(-1),
+        }
+    }
+
+    fn put_array<E, F>(&mut self, items: &[E], f: F)
+    where
+        Self: Sized,
+        F: FnMut(&mut Self, &E),
+    {
+        self.put_nullable_array(Some(items), f);
+    }
+
+    fn put_nullable_array<E, F>(&mut self, items: Option<&[E]>, mut f: F)
+    where
+        Self: Sized,
+        F: FnMut(&mut Self, &E),
+    {
+        match items {
+            Some(items) => {
+                self.put_i32(items.len() as i32);
+                for item in items {
+                    f(self, item);
+                }
+            }
+            None => self.put_i32(-1),
+        }
+    }
+
+    fn put_unsigned_varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.put_u8((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.put_u8(value as u8);
+    }
+
+    fn put_unsigned_varlong(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.put_u8((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.put_u8(value as u8);
+    }
+
+    /// Writes a zigzag-encoded signed varint, as used inside record batches.
+    fn put_varint(&mut self, value: i32) {
+        self.put_unsigned_varint(((value << 1) ^ (value >> 31)) as u32);
+    }
+
+    fn put_varlong(&mut self, value: i64) {
+        self.put_unsigned_varlong(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /// Writes a compact length, stored as `N + 1` so that zero means null.
+    fn put_compact_length(&mut self, len: Option<usize>) {
+        self.put_unsigned_varint(len.map_or(0, |len| len as u32 + 1));
+    }
+
+    fn put_compact_string(&mut self, value: &str) {
+        self.put_compact_bytes(value.as_bytes());
+    }
+
+    fn put_compact_nullable_string(&mut self, value: Option<&str>) {
+        self.put_compact_nullable_bytes(value.map(str::as_bytes));
+    }
+
+    fn put_compact_bytes(&mut self, value: &[u8]) {
+        self.put_compact_length(Some(value.len()));
+        self.put_slice(value);
+    }
+
+    fn put_compact_nullable_bytes(&mut self, value: Option<&[u8]>) {
+        match value {
+            Some(value) => self.put_compact_bytes(value),
+            None => self.put_compact_length(None),
+        }
+    }
+
+    fn put_compact_array<E, F>(&mut self, items: &[E], f: F)
+    where
+        Self: Sized,
+        F: FnMut(&mut Self, &E),
+    {
+        self.put_compact_nullable_array(Some(items), f);
+    }
+
+    fn put_compact_nullable_array<E, F>(&mut self, items: Option<&[E]>, mut f: F)
+    where
+        Self: Sized,
+        F: FnMut(&mut Self, &E),
+    {
+        self.put_compact_length(items.map(<[E]>::len));
+        for item in items.unwrap_or_default() {
+            f(self, item);
+        }
+    }
+}
+
+impl<T: BufMut + ?Sized> BufMutExt for T {}
+
+/// Types that write themselves straight into an output buffer. The size is
+/// known up front so that frames can be length-prefixed without copying.
+pub trait Serialize {
+    /// The exact number of bytes `serialize` writes.
+    fn encoded_len(&self) -> usize;
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B);
+}
+
+/// Encoding for types whose wire layout depends on the API version.
+pub trait VersionedSerialize {
+    /// The exact number of bytes `serialize` writes for `version`.
+    fn encoded_len(&self, version: i16) -> usize;
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B, version: i16);
+}
+
+pub fn string16_len(value: &str) -> usize {
+    2 + value.len()
+}
+
+pub fn nullable_string_len(value: Option<&str>) -> usize {
+    value.map_or(2, string16_len)
+}
+
+pub fn bytes32_len(value: &[u8]) -> usize {
+    4 + value.len()
+}
+
+pub fn nullable_bytes_len(value: Option<&[u8]>) -> usize {
+    value.map_or(4, bytes32_len)
+}
+
+pub fn array_len<E>(items: &[E], f: impl Fn(&E) -> usize) -> usize {
+    4 + items.iter().map(f).sum::<usize>()
+}
+
+pub fn nullable_array_len<E>(items: Option<&[E]>, f: impl Fn(&E) -> usize) -> usize {
+    items.map_or(4, |items| array_len(items, f))
+}
+
+pub fn unsigned_varint_len(value: u32) -> usize {
+    match value {
+        0..=0x7f => 1,
+        0x80..=0x3fff => 2,
+        0x4000..=0x1f_ffff => 3,
+        0x20_0000..=0xfff_ffff => 4,
+        _ => 5,
+    }
+}
+
+pub fn unsigned_varlong_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+pub fn varint_len(value: i32) -> usize {
+    unsigned_varint_len(((value << 1) ^ (value >> 31)) as u32)
+}
+
+pub fn varlong_len(value: i64) -> usize {
+    unsigned_varlong_len(((value << 1) ^ (value >> 63)) as u64)
+}
+
+pub fn compact_length_len(len: Option<usize>) -> usize {
+    unsigned_varint_len(len.map_or(0, |len| len as u32 + 1))
+}
+
+pub fn compact_bytes_len(value: &[u8]) -> usize {
+    compact_length_len(Some(value.len())) + value.len()
+}
+
+pub fn compact_nullable_bytes_len(value: Option<&[u8]>) -> usize {
+    value.map_or(compact_length_len(None), compact_bytes_len)
+}
+
+pub fn compact_string_len(value: &str) -> usize {
+    compact_bytes_len(value.as_bytes())
+}
+
+pub fn compact_nullable_string_len(value: Option<&str>) -> usize {
+    value.map_or(compact_length_len(None), compact_string_len)
+}
+
+pub fn compact_array_len<E>(items: &[E], f: impl Fn(&E) -> usize) -> usize {
+    compact_nullable_array_len(Some(items), f)
+}
+
+pub fn compact_nullable_array_len<E>(items: Option<&[E]>, f: impl Fn(&E) -> usize) -> usize {
+    compact_length_len(items.map(<[E]>::len)) + items.unwrap_or_default().iter().map(f).sum::<usize>()
+}
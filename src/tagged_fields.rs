@@ -0,0 +1,84 @@
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::{
+    deserialize::{BufExt, DecodeError, Deserialize},
+    serialize::{unsigned_varint_len, BufMutExt, Serialize},
+};
+
+/// The TAG_BUFFER that ends every flexible-version structure: a count
+/// followed by `(tag, size, bytes)` entries. Fields a type does not know
+/// about are kept as raw bytes so they survive a decode/encode round trip.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaggedFields(pub Vec<TaggedField>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaggedField {
+    pub tag: u32,
+    pub data: Bytes,
+}
+
+impl TaggedFields {
+    /// Adds a field, keeping the entries sorted by tag as the protocol
+    /// requires. An existing entry with the same tag is replaced.
+    pub fn insert(&mut self, tag: u32, data: impl Into<Bytes>) {
+        let field = TaggedField {
+            tag,
+            data: data.into(),
+        };
+        match self.0.binary_search_by_key(&tag, |field| field.tag) {
+            Ok(index) => self.0[index] = field,
+            Err(index) => self.0.insert(index, field),
+        }
+    }
+
+    /// Removes and returns the raw bytes of a known tag, leaving the
+    /// remaining unknown tags in place.
+    pub fn take(&mut self, tag: u32) -> Option<Bytes> {
+        let index = self.0.iter().position(|field| field.tag == tag)?;
+        Some(self.0.remove(index).data)
+    }
+}
+
+impl<T: Buf> Deserialize<T> for TaggedFields {
+    fn from_bytes(buffer: &mut T) -> Result<Self, DecodeError> {
+        let count = buffer.get_checked_unsigned_varint()? as usize;
+        buffer.ensure(count)?;
+        let mut fields = Vec::with_capacity(count);
+        let mut previous = None;
+        for _ in 0..count {
+            let tag = buffer.get_checked_unsigned_varint()?;
+            if previous.is_some_and(|previous| tag <= previous) {
+                return Err(DecodeError::InvalidTag(tag));
+            }
+            previous = Some(tag);
+            let size = buffer.get_checked_unsigned_varint()? as usize;
+            let data = buffer.copy_to_bytes_checked(size)?;
+            fields.push(TaggedField { tag, data });
+        }
+        Ok(Self(fields))
+    }
+}
+
+impl Serialize for TaggedFields {
+    fn encoded_len(&self) -> usize {
+        unsigned_varint_len(self.0.len() as u32)
+            + self
+                .0
+                .iter()
+                .map(|field| {
+                    unsigned_varint_len(field.tag)
+                        + unsigned_varint_len(field.data.len() as u32)
+                        + field.data.len()
+                })
+                .sum::<usize>()
+    }
+
+    fn serialize<B: BufMut>(&self, buffer: &mut B) {
+        buffer.put_unsigned_varint(self.0.len() as u32);
+        for field in &self.0 {
+            buffer.put_unsigned_varint(field.tag);
+            buffer.put_unsigned_varint(field.data.len() as u32);
+            buffer.put_slice(&field.data);
+        }
+    }
+}
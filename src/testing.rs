@@ -0,0 +1,219 @@
+//! Helpers shared by the protocol test modules.
+
+use std::{fmt::Debug, ops::RangeInclusive};
+
+use bytes::BytesMut;
+
+use crate::{
+    broker::Broker,
+    config::Config,
+    deserialize::VersionedDeserialize,
+    metadata::MetadataImage,
+    record::RecordBatch,
+    serialize::{Serialize, VersionedSerialize},
+};
+
+/// Decodes a hex capture, ignoring whitespace so long dumps can be split
+/// across lines.
+pub fn hex(capture: &str) -> Vec<u8> {
+    let digits: Vec<u8> = capture
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+pub fn encode<T: VersionedSerialize>(value: &T, version: i16) -> Vec<u8> {
+    let mut buffer = BytesMut::new();
+    value.serialize(&mut buffer, version);
+    assert_eq!(
+        buffer.len(),
+        value.encoded_len(version),
+        "encoded_len disagrees with serialize at version {version}"
+    );
+    buffer.to_vec()
+}
+
+/// Decodes `bytes`, insisting that the whole buffer is consumed.
+pub fn decode<T>(bytes: &[u8], version: i16) -> T
+where
+    T: for<'a> VersionedDeserialize<&'a [u8]>,
+{
+    let mut buffer = bytes;
+    let value = T::from_bytes(&mut buffer, version)
+        .unwrap_or_else(|err| panic!("decoding at version {version}: {err}"));
+    assert!(buffer.is_empty(), "{} trailing bytes at version {version}", buffer.len());
+    value
+}
+
+/// Encodes `value`, decodes the result and checks that nothing the version
+/// can carry was lost on the way.
+pub fn round_trip<T>(value: &T, version: i16)
+where
+    T: VersionedSerialize + for<'a> VersionedDeserialize<&'a [u8]> + Debug + PartialEq,
+{
+    let bytes = encode(value, version);
+    let decoded: T = decode(&bytes, version);
+    assert_eq!(encode(&decoded, version), bytes, "version {version}");
+    assert_eq!(decode::<T>(&bytes, version), decoded, "version {version}");
+}
+
+/// Checks `value` against bytes worked out by hand from the schema, given
+/// from each version where the layout changes. Every version in `versions`
+/// has to encode like the last vector given at or before it, and decoding
+/// that vector has to give back a value that encodes the same way.
+pub fn golden<T>(value: &T, versions: RangeInclusive<i16>, vectors: &[(i16, &str)])
+where
+    T: VersionedSerialize + for<'a> VersionedDeserialize<&'a [u8]>,
+{
+    for version in versions {
+        let (_, expected) = vectors
+            .iter()
+            .rev()
+            .find(|&&(from, _)| from <= version)
+            .unwrap_or_else(|| panic!("no golden vector for version {version}"));
+        let expected = hex(expected);
+        assert_eq!(encode(value, version), expected, "version {version}");
+        let decoded: T = decode(&expected, version);
+        assert_eq!(encode(&decoded, version), expected, "version {version}");
+    }
+}
+
+/// Decodes bytes a client was seen to send as a `T` at `version`, checking
+/// that the value encodes back to the same bytes.
+pub fn captured<T>(capture: &str, version: i16) -> T
+where
+    T: VersionedSerialize + for<'a> VersionedDeserialize<&'a [u8]>,
+{
+    let expected = hex(capture);
+    let value: T = decode(&expected, version);
+    assert_eq!(encode(&value, version), expected, "version {version}");
+    value
+}
+
+/// Round-trips random values of `T` through each of `versions`.
+pub fn round_trip_random<T>(rng: &mut Rng, versions: RangeInclusive<i16>)
+where
+    T: Random + VersionedSerialize + for<'a> VersionedDeserialize<&'a [u8]> + Debug + PartialEq,
+{
+    for _ in 0..100 {
+        let value = T::random(rng);
+        for version in versions.clone() {
+            round_trip(&value, version);
+        }
+    }
+}
+
+/// `batches` back to back, as a producer sends them and
+/// [`PartitionLog::append`](crate::log::PartitionLog::append) takes them.
+pub fn serialize_batches(batches: &[RecordBatch]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for batch in batches {
+        batch.serialize(&mut data);
+    }
+    data
+}
+
+/// Types that can be made at random, which `build.rs` implements for
+/// every generated message struct.
+pub trait Random {
+    fn random(rng: &mut Rng) -> Self;
+}
+
+/// A small xorshift generator, so the round-trip tests cover varied values
+/// while staying deterministic.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    pub fn i8(&mut self) -> i8 {
+        self.next_u64() as i8
+    }
+
+    pub fn i16(&mut self) -> i16 {
+        self.next_u64() as i16
+    }
+
+    pub fn i32(&mut self) -> i32 {
+        self.next_u64() as i32
+    }
+
+    pub fn i64(&mut self) -> i64 {
+        self.next_u64() as i64
+    }
+
+    pub fn u128(&mut self) -> u128 {
+        (self.next_u64() as u128) << 64 | self.next_u64() as u128
+    }
+
+    pub fn string(&mut self) -> String {
+        (0..self.below(12))
+            .map(|_| (b'a' + self.below(26) as u8) as char)
+            .collect()
+    }
+
+    pub fn bytes(&mut self) -> bytes::Bytes {
+        (0..self.below(40)).map(|_| self.next_u64() as u8).collect()
+    }
+
+    /// Up to four elements, empty arrays included.
+    pub fn vec<T>(&mut self, mut f: impl FnMut(&mut Self) -> T) -> Vec<T> {
+        (0..self.below(4)).map(|_| f(self)).collect()
+    }
+
+    pub fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        match self.below(3) {
+            0 => None,
+            _ => Some(f(self)),
+        }
+    }
+}
+
+/// A fresh, empty directory under the system temp dir, unique to `name`
+/// and this process.
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("kafka-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A broker logging to a fresh directory for `name`, configured with the
+/// extra `config` lines, on which `topics` have been created with the
+/// given numbers of partitions.
+pub fn broker(name: &str, config: &str, topics: &[(&str, i32)]) -> Broker {
+    let log_dir = temp_dir(name);
+    let config = Config::parse(&format!("log.dirs={}\n{config}", log_dir.display()));
+    let broker = Broker::new(config, MetadataImage::default());
+    for &(topic, partitions) in topics {
+        let assignments = broker.assign_replicas(partitions, 1).unwrap();
+        broker.create_topic(topic, &assignments, &[]).unwrap();
+    }
+    broker
+}
+
+/// The id `broker` gave topic `name`.
+pub fn topic_id(broker: &Broker, name: &str) -> u128 {
+    broker.image.read().unwrap().topic(name).unwrap().topic_id
+}
@@ -0,0 +1,423 @@
+pub use crate::messages::metadata_request::{self, MetadataRequest, MetadataRequestTopic};
+pub use crate::messages::metadata_response::{
+    self, MetadataResponse, MetadataResponseBroker, MetadataResponsePartition,
+    MetadataResponseTopic,
+};
+use crate::{
+    broker::Broker,
+    error_code,
+    metadata::{self, TopicImage},
+};
+
+impl MetadataResponse {
+    /// Describes this broker, which is the whole cluster and its
+    /// controller, and the requested topics: all of them if the request
+    /// leaves the list out (v1+) or empty (v0). Missing topics asked for by
+    /// name are created if both the client and the config allow it.
+    pub fn new(request: &MetadataRequest, version: i16, broker: &Broker) -> Self {
+        let all_topics = match &request.topics {
+            None => true,
+            Some(topics) => version == 0 && topics.is_empty(),
+        };
+        let topics = match all_topics {
+            true => broker
+                .image
+                .read()
+                .unwrap()
+                .topics()
+                .map(MetadataResponseTopic::new)
+                .collect(),
+            false => {
+                let mut requested: Vec<&MetadataRequestTopic> = Vec::new();
+                for topic in request.topics.iter().flatten() {
+                    if !requested.contains(&topic) {
+                        requested.push(topic);
+                    }
+                }
+                let auto_create =
+                    request.allow_auto_topic_creation && broker.config.auto_create_topics();
+                requested
+                    .into_iter()
+                    .map(|topic| MetadataResponseTopic::lookup(broker, topic, auto_create))
+                    .collect()
+            }
+        };
+        let node_id = broker.config.node_id();
+        let (host, port) = broker.config.advertised_listener();
+        Self {
+            brokers: vec![MetadataResponseBroker {
+                node_id,
+                host,
+                port,
+                rack: None,
+                ..Default::default()
+            }],
+            cluster_id: broker.cluster_id.clone(),
+            controller_id: node_id,
+            topics,
+            ..Default::default()
+        }
+    }
+}
+
+impl MetadataResponseTopic {
+    fn new(topic: &TopicImage) -> Self {
+        let partitions = topic
+            .partitions
+            .values()
+            .map(|partition| MetadataResponsePartition {
+                error_code: error_code::NONE,
+                partition_index: partition.partition_id,
+                leader_id: partition.leader,
+                leader_epoch: partition.leader_epoch,
+                replica_nodes: partition.replicas.clone(),
+                isr_nodes: partition.isr.clone(),
+                offline_replicas: Vec::new(),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            error_code: error_code::NONE,
+            name: Some(topic.name.clone()),
+            topic_id: topic.topic_id,
+            is_internal: false,
+            partitions,
+            ..Default::default()
+        }
+    }
+
+    fn error(name: Option<String>, topic_id: u128, error_code: i16) -> Self {
+        Self {
+            error_code,
+            name,
+            topic_id,
+            ..Default::default()
+        }
+    }
+
+    /// Describes one requested topic, by id from v12 and by name before.
+    fn lookup(broker: &Broker, request: &MetadataRequestTopic, auto_create: bool) -> Self {
+        let Some(name) = &request.name else {
+            return match broker.image.read().unwrap().topic_by_id(request.topic_id) {
+                Some(topic) => Self::new(topic),
+                None => Self::error(None, request.topic_id, error_code::UNKNOWN_TOPIC_ID),
+            };
+        };
+        // A name that could never exist is reported as such, whether or
+        // not it would have been created.
+        if !metadata::valid_topic_name(name) {
+            return Self::error(Some(name.clone()), 0, error_code::INVALID_TOPIC_EXCEPTION);
+        }
+        if let Some(topic) = broker.image.read().unwrap().topic(name) {
+            return Self::new(topic);
+        }
+        if !auto_create {
+            return Self::error(Some(name.clone()), 0, error_code::UNKNOWN_TOPIC_OR_PARTITION);
+        }
+        let created = broker
+            .assign_replicas(
+                broker.config.num_partitions(),
+                broker.config.default_replication_factor(),
+            )
+            .and_then(|assignments| broker.create_topic(name, &assignments, &[]));
+        match created {
+            Ok(_) => {}
+            // Another request got there first.
+            Err(error_code::TOPIC_ALREADY_EXISTS) => {}
+            Err(error_code) => return Self::error(Some(name.clone()), 0, error_code),
+        }
+        match broker.image.read().unwrap().topic(name) {
+            Some(topic) => Self::new(topic),
+            None => Self::error(Some(name.clone()), 0, error_code::UNKNOWN_TOPIC_OR_PARTITION),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata::MetadataImage,
+        testing::{broker, captured, decode, encode, golden, hex, topic_id},
+    };
+
+    fn request(names: &[&str]) -> MetadataRequest {
+        MetadataRequest {
+            topics: Some(
+                names
+                    .iter()
+                    .map(|name| MetadataRequestTopic {
+                        name: Some(name.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_requests() {
+        // v0 with no topics, meaning all of them.
+        let request: MetadataRequest = decode(&hex("00000000"), 0);
+        assert_eq!(request.topics, Some(Vec::new()));
+        assert!(request.allow_auto_topic_creation);
+        // v1 with a null array, also meaning all of them.
+        let request: MetadataRequest = decode(&hex("ffffffff"), 1);
+        assert_eq!(request.topics, None);
+
+        let v12 = "02 00000000000000000000000000000000 04 666f6f 00  00 00 00";
+        let request: MetadataRequest = decode(&hex(v12), 12);
+        let topics = request.topics.as_ref().unwrap();
+        assert_eq!(topics[0].name.as_deref(), Some("foo"));
+        assert!(!request.allow_auto_topic_creation);
+        assert_eq!(encode(&request, 12), hex(v12));
+    }
+
+    #[test]
+    fn encodes_response_for_v0_and_v12() {
+        let broker = broker("metadata-encode", "", &[("foo", 1)]);
+        let foo = topic_id(&broker, "foo");
+        let response = MetadataResponse::new(&request(&["foo"]), 0, &broker);
+        let v0 = "
+            00000001 00000001 0009 6c6f63616c686f7374 00002384
+            00000001 0000 0003 666f6f
+                00000001 0000 00000000 00000001 00000001 00000001 00000001 00000001";
+        assert_eq!(encode(&response, 0), hex(v0));
+        let v12 = format!(
+            "
+            00000000
+            02 00000001 0a 6c6f63616c686f7374 00002384 00 00
+            00
+            00000001
+            02 0000 04 666f6f {foo:032x} 00
+                02 0000 00000000 00000001 00000000 02 00000001 02 00000001 01 00
+                80000000 00
+            00"
+        );
+        assert_eq!(encode(&response, 12), hex(&v12));
+    }
+
+    #[test]
+    fn lists_all_topics() {
+        let broker = broker("metadata-all", "", &[("foo", 1)]);
+        let foo = topic_id(&broker, "foo");
+        for (version, topics) in [(0, Some(Vec::new())), (1, None), (12, None)] {
+            let request = MetadataRequest {
+                topics,
+                ..Default::default()
+            };
+            let response = MetadataResponse::new(&request, version, &broker);
+            assert_eq!(response.topics.len(), 1);
+            assert_eq!(response.topics[0].topic_id, foo);
+        }
+        // From v1 an empty list asks for no topics.
+        let response = MetadataResponse::new(&request(&[]), 1, &broker);
+        assert!(response.topics.is_empty());
+        assert_eq!(response.controller_id, 1);
+    }
+
+    #[test]
+    fn auto_creates_missing_topics() {
+        let broker = broker("metadata-auto-create", "num.partitions=3", &[("foo", 1)]);
+        let response = MetadataResponse::new(&request(&["bar", "foo", "bar"]), 12, &broker);
+        assert_eq!(response.topics.len(), 2);
+        let bar = &response.topics[0];
+        assert_eq!(bar.error_code, error_code::NONE);
+        assert_eq!(bar.partitions.len(), 3);
+        assert_eq!(bar.partitions[2].leader_id, 1);
+        assert_ne!(bar.topic_id, 0);
+
+        // The records made it to the metadata log.
+        let image = MetadataImage::load(&broker.config.metadata_log_dir()).unwrap();
+        assert_eq!(image.topic("bar").unwrap().topic_id, bar.topic_id);
+        assert_eq!(image.topic("bar").unwrap().partitions.len(), 3);
+
+        let response = MetadataResponse::new(&request(&["no/slash"]), 12, &broker);
+        assert_eq!(response.topics[0].error_code, error_code::INVALID_TOPIC_EXCEPTION);
+        let mut disallowed = request(&["baz"]);
+        disallowed.allow_auto_topic_creation = false;
+        let response = MetadataResponse::new(&disallowed, 12, &broker);
+        assert_eq!(response.topics[0].error_code, error_code::UNKNOWN_TOPIC_OR_PARTITION);
+
+        let disabled = self::broker(
+            "metadata-no-auto-create",
+            "auto.create.topics.enable=false",
+            &[("foo", 1)],
+        );
+        let response = MetadataResponse::new(&request(&["bar", "no/slash"]), 12, &disabled);
+        assert_eq!(response.topics[0].error_code, error_code::UNKNOWN_TOPIC_OR_PARTITION);
+        assert_eq!(response.topics[1].error_code, error_code::INVALID_TOPIC_EXCEPTION);
+    }
+
+    #[test]
+    fn looks_up_topics_by_id() {
+        let broker = broker("metadata-by-id", "", &[("foo", 1)]);
+        let foo = topic_id(&broker, "foo");
+        let mut request = request(&[]);
+        request.topics = Some(
+            [foo, 7]
+                .into_iter()
+                .map(|topic_id| MetadataRequestTopic {
+                    topic_id,
+                    name: None,
+                    ..Default::default()
+                })
+                .collect(),
+        );
+        let response = MetadataResponse::new(&request, 12, &broker);
+        assert_eq!(response.topics[0].name.as_deref(), Some("foo"));
+        assert_eq!(response.topics[1].error_code, error_code::UNKNOWN_TOPIC_ID);
+        assert_eq!(response.topics[1].name, None);
+    }
+
+    /// Bodies of v12 requests librdkafka 2.12.1 sent: one for no topics,
+    /// which it uses to learn the brokers, and one for "foo" from a
+    /// producer, which may create it.
+    const LIBRDKAFKA_REQUEST_V12: &str = "01 00 00 00";
+    const LIBRDKAFKA_PRODUCER_REQUEST_V12: &str = "
+        02 00000000000000000000000000000000 04 666f6f 00
+        01 00 00";
+
+    #[test]
+    fn decodes_librdkafka_requests() {
+        let request: MetadataRequest = captured(LIBRDKAFKA_REQUEST_V12, 12);
+        assert_eq!(request.topics, Some(Vec::new()));
+        assert!(!request.allow_auto_topic_creation);
+        let request: MetadataRequest = captured(LIBRDKAFKA_PRODUCER_REQUEST_V12, 12);
+        let topics = request.topics.as_ref().unwrap();
+        assert_eq!(topics[0].topic_id, 0);
+        assert_eq!(topics[0].name.as_deref(), Some("foo"));
+        assert!(request.allow_auto_topic_creation);
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let request = MetadataRequest {
+            topics: Some(vec![MetadataRequestTopic {
+                topic_id: 2,
+                name: Some("foo".to_string()),
+                ..Default::default()
+            }]),
+            allow_auto_topic_creation: false,
+            include_cluster_authorized_operations: true,
+            include_topic_authorized_operations: true,
+            ..Default::default()
+        };
+        let foo = "00000000000000000000000000000002";
+        golden(
+            &request,
+            0..=12,
+            &[
+                (0, "00000001 0003 666f6f"),
+                (4, "00000001 0003 666f6f 00"),
+                (8, "00000001 0003 666f6f 00 01 01"),
+                (9, "02 04 666f6f 00  00 01 01 00"),
+                (10, &format!("02 {foo} 04 666f6f 00  00 01 01 00")),
+                (11, &format!("02 {foo} 04 666f6f 00  00 01 00")),
+            ],
+        );
+
+        let response = MetadataResponse {
+            throttle_time_ms: 100,
+            brokers: vec![MetadataResponseBroker {
+                node_id: 1,
+                host: "h".to_string(),
+                port: 9092,
+                rack: None,
+                ..Default::default()
+            }],
+            cluster_id: Some("c".to_string()),
+            controller_id: 1,
+            topics: vec![MetadataResponseTopic {
+                name: Some("foo".to_string()),
+                topic_id: 2,
+                partitions: vec![MetadataResponsePartition {
+                    partition_index: 0,
+                    leader_id: 1,
+                    leader_epoch: 0,
+                    replica_nodes: vec![1],
+                    isr_nodes: vec![1],
+                    ..Default::default()
+                }],
+                topic_authorized_operations: 8,
+                ..Default::default()
+            }],
+            cluster_authorized_operations: 16,
+            ..Default::default()
+        };
+        let v0 = "00000001 00000001 0001 68 00002384
+            00000001 0000 0003 666f6f
+                00000001 0000 00000000 00000001 00000001 00000001 00000001 00000001";
+        let v1 = "00000001 00000001 0001 68 00002384 ffff
+            00000001
+            00000001 0000 0003 666f6f 00
+                00000001 0000 00000000 00000001 00000001 00000001 00000001 00000001";
+        let v2 = "00000001 00000001 0001 68 00002384 ffff
+            0001 63 00000001
+            00000001 0000 0003 666f6f 00
+                00000001 0000 00000000 00000001 00000001 00000001 00000001 00000001";
+        let brokers = "00000001 00000001 0001 68 00002384 ffff 0001 63 00000001";
+        let partition = "0000 00000000 00000001 00000000 00000001 00000001 00000001 00000001";
+        let flexible_partition = "02 0000 00000000 00000001 00000000 02 00000001 02 00000001 01 00";
+        golden(
+            &response,
+            0..=12,
+            &[
+                (0, v0),
+                (1, v1),
+                (2, v2),
+                (3, &format!("00000064 {v2}")),
+                (
+                    5,
+                    &format!(
+                        "00000064 {brokers}
+                            00000001 0000 0003 666f6f 00
+                                00000001 0000 00000000 00000001 00000001 00000001 00000001
+                                00000001 00000000"
+                    ),
+                ),
+                (
+                    7,
+                    &format!(
+                        "00000064 {brokers}
+                            00000001 0000 0003 666f6f 00 00000001 {partition} 00000000"
+                    ),
+                ),
+                (
+                    8,
+                    &format!(
+                        "00000064 {brokers}
+                            00000001 0000 0003 666f6f 00 00000001 {partition} 00000000
+                                00000008
+                            00000010"
+                    ),
+                ),
+                (
+                    9,
+                    &format!(
+                        "00000064 02 00000001 02 68 00002384 00 00 02 63 00000001
+                            02 0000 04 666f6f 00 {flexible_partition} 00000008 00
+                            00000010 00"
+                    ),
+                ),
+                (
+                    10,
+                    &format!(
+                        "00000064 02 00000001 02 68 00002384 00 00 02 63 00000001
+                            02 0000 04 666f6f {foo} 00 {flexible_partition} 00000008 00
+                            00000010 00"
+                    ),
+                ),
+                (
+                    11,
+                    &format!(
+                        "00000064 02 00000001 02 68 00002384 00 00 02 63 00000001
+                            02 0000 04 666f6f {foo} 00 {flexible_partition} 00000008 00
+                            00"
+                    ),
+                ),
+            ],
+        );
+    }
+}